@@ -0,0 +1,439 @@
+use crate::cmd::Cmd;
+use crate::executor::{CmdOutput, Executor, Stream};
+use crate::secret;
+use anyhow::{anyhow, Result};
+use chrono::{Local, SecondsFormat};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+thread_local! {
+    // the jail the thread is working on, for the entries it logs
+    static JAIL: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn set_jail(jail: Option<&str>) {
+    JAIL.with(|j| *j.borrow_mut() = jail.map(String::from));
+}
+
+// An entry in the audit log, each is a line of JSON
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Entry {
+    pub time: String,
+    pub run_id: String,
+    pub user: String,
+    pub config_hash: String,
+    // the host managed over ssh, None for this one
+    pub host: Option<String>,
+    pub jail: Option<String>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+// What was done, file contents are recorded as sha256 hashes
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Command {
+        program: String,
+        args: Vec<String>,
+        code: Option<i32>,
+        duration_ms: u64,
+        // why it didn't run or finish
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    WriteFile {
+        path: PathBuf,
+        // None if the file didn't exist
+        before: Option<String>,
+        after: String,
+    },
+    RemoveFile {
+        path: PathBuf,
+        before: Option<String>,
+    },
+    CopyDir {
+        src: PathBuf,
+        dest: PathBuf,
+    },
+    FetchExtract {
+        url: String,
+        dest: PathBuf,
+    },
+}
+
+impl Event {
+    // Replace secret values, before JSON escaping could hide them from
+    // secret::redact
+    fn redacted(self) -> Event {
+        let path = |p: PathBuf| PathBuf::from(secret::redact(&p.to_string_lossy()));
+        match self {
+            Event::Command {
+                program,
+                args,
+                code,
+                duration_ms,
+                error,
+            } => Event::Command {
+                program: secret::redact(&program),
+                args: args.iter().map(|a| secret::redact(a)).collect(),
+                code,
+                duration_ms,
+                error: error.map(|e| secret::redact(&e)),
+            },
+            Event::WriteFile {
+                path: p,
+                before,
+                after,
+            } => Event::WriteFile {
+                path: path(p),
+                before,
+                after,
+            },
+            Event::RemoveFile { path: p, before } => Event::RemoveFile {
+                path: path(p),
+                before,
+            },
+            Event::CopyDir { src, dest } => Event::CopyDir {
+                src: path(src),
+                dest: path(dest),
+            },
+            Event::FetchExtract { url, dest } => Event::FetchExtract {
+                url: secret::redact(&url),
+                dest: path(dest),
+            },
+        }
+    }
+}
+
+// shown by rj history
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // enough of a hash to tell versions apart
+        let short = |hash: &Option<String>| match hash {
+            Some(hash) => hash.chars().take(12).collect(),
+            None => "none".to_string(),
+        };
+        match self {
+            Event::Command {
+                program,
+                args,
+                code,
+                duration_ms,
+                error,
+            } => {
+                write!(f, "ran {}", program)?;
+                for arg in args.iter() {
+                    write!(f, " {}", arg)?;
+                }
+                match (error, code) {
+                    (Some(error), _) => write!(f, " ({})", error),
+                    (None, Some(code)) => write!(f, " (exit {}, {}ms)", code, duration_ms),
+                    (None, None) => write!(f, " (killed, {}ms)", duration_ms),
+                }
+            },
+            Event::WriteFile {
+                path,
+                before,
+                after,
+            } => write!(
+                f,
+                "wrote {} ({} -> {})",
+                path.display(),
+                short(before),
+                short(&Some(after.to_owned()))
+            ),
+            Event::RemoveFile { path, before } => {
+                write!(f, "removed {} ({})", path.display(), short(before))
+            },
+            Event::CopyDir { src, dest } => {
+                write!(f, "copied {} to {}", src.display(), dest.display())
+            },
+            Event::FetchExtract { url, dest } => {
+                write!(f, "extracted {} to {}", url, dest.display())
+            },
+        }
+    }
+}
+
+pub fn hash(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+// The audit log a run appends to, the details of the run go in every entry
+pub struct AuditLog {
+    file: Mutex<File>,
+    run_id: String,
+    user: String,
+    config_hash: String,
+    host: Option<String>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, config_file: &Path, host: Option<&str>) -> Result<AuditLog> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("audit log {}: {}", path.display(), e))?;
+
+        Ok(AuditLog {
+            file: Mutex::new(file),
+            run_id: thread_rng().sample_iter(&Alphanumeric).take(12).collect(),
+            user: user(),
+            config_hash: hash(&fs::read(config_file)?),
+            host: host.map(String::from),
+        })
+    }
+
+    pub fn log(&self, event: Event) -> Result<()> {
+        let entry = Entry {
+            time: Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+            run_id: self.run_id.to_owned(),
+            user: self.user.to_owned(),
+            config_hash: self.config_hash.to_owned(),
+            host: self.host.to_owned(),
+            jail: JAIL.with(|j| j.borrow().clone()),
+            event: event.redacted(),
+        };
+        let line = serde_json::to_string(&entry)?;
+        // a single write so that runs appending at once don't interleave
+        let mut file = self.file.lock().unwrap();
+        file.write_all(format!("{}\n", line).as_bytes())?;
+        Ok(())
+    }
+}
+
+// the person running rj, through sudo if it was used
+fn user() -> String {
+    ["SUDO_USER", "USER", "LOGNAME"]
+        .iter()
+        .find_map(|var| env::var(var).ok())
+        .unwrap_or_else(|| unsafe { libc::getuid() }.to_string())
+}
+
+// Read the entries in an audit log, oldest first
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow!("audit log {}: {}", path.display(), e)),
+    };
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| anyhow!("audit log {}:{}: {}", path.display(), i + 1, e))
+        })
+        .collect()
+}
+
+// Runs commands and changes files with another executor, logging each one
+pub struct Audited {
+    inner: Arc<dyn Executor>,
+    log: AuditLog,
+}
+
+impl Audited {
+    pub fn new(inner: Arc<dyn Executor>, log: AuditLog) -> Audited {
+        Audited { inner, log }
+    }
+
+    fn log_command(&self, cmd: &Cmd, start: Instant, result: &Result<CmdOutput>) -> Result<()> {
+        let (code, error) = match result {
+            Ok(output) if output.interrupted => (output.code, Some("interrupted".to_string())),
+            Ok(output) if output.timed_out => (output.code, Some("timed out".to_string())),
+            Ok(output) => (output.code, None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        self.log.log(Event::Command {
+            program: cmd.get_program().to_owned(),
+            args: cmd
+                .get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect(),
+            code,
+            duration_ms: start.elapsed().as_millis() as u64,
+            error,
+        })
+    }
+}
+
+impl Executor for Audited {
+    fn output(&self, cmd: &Cmd) -> Result<CmdOutput> {
+        let start = Instant::now();
+        let result = self.inner.output(cmd);
+        self.log_command(cmd, start, &result)?;
+        result
+    }
+
+    fn stream(&self, cmd: &Cmd, line: &(dyn Fn(Stream, &str) + Sync)) -> Result<CmdOutput> {
+        let start = Instant::now();
+        let result = self.inner.stream(cmd, line);
+        self.log_command(cmd, start, &result)?;
+        result
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Option<String>> {
+        self.inner.read_file(path)
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        let before = self.inner.read_file(path)?.map(|c| hash(c.as_bytes()));
+        self.inner.write_file(path, contents)?;
+        self.log.log(Event::WriteFile {
+            path: path.to_owned(),
+            before,
+            after: hash(contents),
+        })
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let before = self.inner.read_file(path)?.map(|c| hash(c.as_bytes()));
+        self.inner.remove_file(path)?;
+        self.log.log(Event::RemoveFile {
+            path: path.to_owned(),
+            before,
+        })
+    }
+
+    fn copy_dir(&self, src: &Path, dest: &Path) -> Result<()> {
+        self.inner.copy_dir(src, dest)?;
+        self.log.log(Event::CopyDir {
+            src: src.to_owned(),
+            dest: dest.to_owned(),
+        })
+    }
+
+    fn fetch_extract(&self, url: &str, dest: &Path) -> Result<()> {
+        self.inner.fetch_extract(url, dest)?;
+        self.log.log(Event::FetchExtract {
+            url: url.to_owned(),
+            dest: dest.to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Fake;
+    use crate::secret::Secrets;
+    use indexmap::indexmap;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn audited() -> Result<()> {
+        let dir = TempDir::new()?;
+        let config = dir.path().join("rj.toml");
+        fs::write(&config, "jails_dataset = \"zroot/jails\"\n")?;
+        let path = dir.path().join("log/audit.log");
+
+        let fake = Arc::new(Fake::default());
+        fake.on("zfs snapshot", 1, "")
+            .file("/etc/jail.test1.conf", "old");
+        let audited = Audited::new(fake.clone(), AuditLog::open(&path, &config, None)?);
+
+        set_jail(Some("test1"));
+        audited.output(Cmd::new("zfs").args(["snapshot", "zroot/jails/test1@ready"]))?;
+        audited.write_file(Path::new("/etc/jail.test1.conf"), b"new")?;
+        set_jail(None);
+        audited.remove_file(Path::new("/etc/jail.test1.conf"))?;
+        assert_eq!(audited.read_file(Path::new("/etc/jail.test1.conf"))?, None);
+
+        let entries = read(&path)?;
+        assert_eq!(entries.len(), 3);
+        for entry in entries.iter() {
+            assert_eq!(entry.run_id, entries[0].run_id);
+            assert_eq!(
+                entry.config_hash,
+                hash(b"jails_dataset = \"zroot/jails\"\n")
+            );
+            assert_eq!(entry.host, None);
+        }
+        assert_eq!(entries[0].jail, Some("test1".to_string()));
+        assert_eq!(entries[2].jail, None);
+
+        match &entries[0].event {
+            Event::Command {
+                program,
+                args,
+                code,
+                error,
+                ..
+            } => {
+                assert_eq!(program, "zfs");
+                assert_eq!(args, &["snapshot", "zroot/jails/test1@ready"]);
+                assert_eq!(*code, Some(1));
+                assert_eq!(*error, None);
+            },
+            event => panic!("not a command: {:?}", event),
+        }
+        assert_eq!(
+            entries[1].event,
+            Event::WriteFile {
+                path: PathBuf::from("/etc/jail.test1.conf"),
+                before: Some(hash(b"old")),
+                after: hash(b"new"),
+            }
+        );
+        assert_eq!(
+            entries[2].event.to_string(),
+            format!("removed /etc/jail.test1.conf ({})", &hash(b"new")[..12])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn redacted() -> Result<()> {
+        let dir = TempDir::new()?;
+        let config = dir.path().join("rj.toml");
+        fs::write(&config, "jails_dataset = \"zroot/jails\"\n")?;
+        let path = dir.path().join("audit.log");
+
+        // escaped in JSON, so only redacted before serialising
+        env::set_var("RJ_AUDIT_SECRET_TEST", "pa\"ss\\word");
+        let secrets = Secrets::new(
+            &indexmap! {"token".to_string() => "env:RJ_AUDIT_SECRET_TEST".to_string()},
+            &None,
+            "",
+        );
+        secrets.get("token")?;
+
+        let fake = Arc::new(Fake::default());
+        let audited = Audited::new(fake, AuditLog::open(&path, &config, None)?);
+        audited.output(Cmd::new("fetch").args(["--token", "pa\"ss\\word"]))?;
+
+        let content = fs::read_to_string(&path)?;
+        assert!(!content.contains("pa\\\"ss"), "{}", content);
+        assert!(!content.contains("word"), "{}", content);
+        match &read(&path)?[0].event {
+            Event::Command { args, .. } => assert_eq!(args, &["--token", "********"]),
+            event => panic!("not a command: {:?}", event),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_missing() -> Result<()> {
+        let dir = TempDir::new()?;
+        assert_eq!(read(&dir.path().join("audit.log"))?, Vec::new());
+        Ok(())
+    }
+}
@@ -1,11 +1,23 @@
+use crate::errors::Error;
 use clap::{crate_description, crate_name, crate_version, AppSettings, Arg, SubCommand};
 use std::path::Path;
+use std::process;
 
 // Create a clap app
 fn create_app<'a, 'b>() -> clap::App<'a, 'b> {
     clap::App::new(crate_name!())
         .version(crate_version!())
         .about(crate_description!())
+        .after_help(
+            "EXIT CODES:\n    \
+             1    other errors\n    \
+             2    the config or command line is wrong\n    \
+             3    the host isn't set up for rj or something a jail needs is missing\n    \
+             4    a zfs command failed\n    \
+             5    a jail failed to start or stop\n    \
+             6    a provisioner failed\n    \
+             130  aborted by the user or Ctrl-C",
+        )
         .set_term_width(80)
         .setting(AppSettings::InferSubcommands)
         .setting(AppSettings::SubcommandRequiredElseHelp)
@@ -37,21 +49,90 @@ fn create_app<'a, 'b>() -> clap::App<'a, 'b> {
                 .takes_value(false)
                 .help("Dry run"),
         )
+        .arg(
+            Arg::with_name("wait")
+                .short("w")
+                .long("wait")
+                .takes_value(false)
+                .help("Wait for other rj runs to finish instead of failing"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Wait up to SECONDS for other rj runs to finish")
+                .takes_value(true)
+                .validator(is_positive),
+        )
+        .arg(
+            Arg::with_name("lock_dir")
+                .env("RJ_LOCK_DIR")
+                .long("lock-dir")
+                .value_name("DIR")
+                .help("Directory for lock files, overrides lock_dir in the config")
+                .takes_value(true)
+                .hide_env_values(true),
+        )
+        .arg(
+            Arg::with_name("audit_log")
+                .env("RJ_AUDIT_LOG")
+                .long("audit-log")
+                .value_name("FILE")
+                .help("File to log changes to, overrides audit_log in the config")
+                .takes_value(true)
+                .hide_env_values(true),
+        )
+        .arg(
+            Arg::with_name("host")
+                .env("RJ_HOST")
+                .short("H")
+                .long("host")
+                .value_name("HOST")
+                .help("Manage the jails on HOST over ssh, a name in [hosts] or [user@]host")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .env("RJ_OUTPUT")
+                .short("o")
+                .long("output")
+                .value_name("FORMAT")
+                .help("Output format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text"),
+        )
         .subcommand(
             SubCommand::with_name("apply")
                 .about("Apply changes")
                 .arg(
                     Arg::with_name("jail_name")
                         .multiple(true)
-                        .help("Name of the jail to apply changes to")
+                        .help("Name or glob pattern of the jail to apply changes to")
                         .index(1)
-                        .required_unless("all"),
+                        .required_unless_one(&["all", "selector"]),
                 )
                 .arg(
                     Arg::with_name("all")
                         .short("a")
                         .long("all")
                         .help("Apply to all jails"),
+                )
+                .arg(selector_arg())
+                .arg(
+                    Arg::with_name("jobs")
+                        .short("j")
+                        .long("jobs")
+                        .value_name("N")
+                        .help("Apply up to N jails at once")
+                        .takes_value(true)
+                        .validator(is_positive)
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::with_name("auto-approve")
+                        .long("auto-approve")
+                        .help("Don't prompt before destroying surplus replicas"),
                 ),
         )
         .subcommand(
@@ -60,9 +141,9 @@ fn create_app<'a, 'b>() -> clap::App<'a, 'b> {
                 .arg(
                     Arg::with_name("jail_name")
                         .multiple(true)
-                        .help("Name of the jail to destroy")
+                        .help("Name or glob pattern of the jail to destroy")
                         .index(1)
-                        .required_unless("all"),
+                        .required_unless_one(&["all", "selector"]),
                 )
                 .arg(
                     Arg::with_name("all")
@@ -70,6 +151,7 @@ fn create_app<'a, 'b>() -> clap::App<'a, 'b> {
                         .long("all")
                         .help("Destroy all jails"),
                 )
+                .arg(selector_arg())
                 .arg(
                     Arg::with_name("auto-approve")
                         .long("auto-approve")
@@ -82,23 +164,277 @@ fn create_app<'a, 'b>() -> clap::App<'a, 'b> {
                 .arg(
                     Arg::with_name("jail_name")
                         .multiple(true)
-                        .help("Name of the jail to provision")
+                        .help("Name or glob pattern of the jail to provision")
                         .index(1)
-                        .required_unless("all"),
+                        .required_unless_one(&["all", "selector"]),
                 )
                 .arg(
                     Arg::with_name("all")
                         .short("a")
                         .long("all")
                         .help("Provision all jails"),
+                )
+                .arg(selector_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Destroy the snapshots the retention policy doesn't keep")
+                .arg(
+                    Arg::with_name("jail_name")
+                        .multiple(true)
+                        .help("Name or glob pattern of the jail to prune")
+                        .index(1)
+                        .required_unless_one(&["all", "selector"]),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .short("a")
+                        .long("all")
+                        .help("Prune all jails"),
+                )
+                .arg(selector_arg())
+                .arg(
+                    Arg::with_name("noop")
+                        .short("n")
+                        .long("noop")
+                        .help("Show what would be destroyed"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("start")
+                .about("Start jails")
+                .arg(
+                    Arg::with_name("jail_name")
+                        .multiple(true)
+                        .help("Name or glob pattern of the jail to start")
+                        .index(1)
+                        .required_unless_one(&["all", "selector"]),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .short("a")
+                        .long("all")
+                        .help("Start all jails"),
+                )
+                .arg(selector_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("stop")
+                .about("Stop jails")
+                .arg(
+                    Arg::with_name("jail_name")
+                        .multiple(true)
+                        .help("Name or glob pattern of the jail to stop")
+                        .index(1)
+                        .required_unless_one(&["all", "selector"]),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .short("a")
+                        .long("all")
+                        .help("Stop all jails"),
+                )
+                .arg(selector_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("restart")
+                .about("Restart jails")
+                .arg(
+                    Arg::with_name("jail_name")
+                        .multiple(true)
+                        .help("Name or glob pattern of the jail to restart")
+                        .index(1)
+                        .required_unless_one(&["all", "selector"]),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .short("a")
+                        .long("all")
+                        .help("Restart all jails"),
+                )
+                .arg(selector_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about("Roll a jail back to a snapshot")
+                .arg(
+                    Arg::with_name("jail_name")
+                        .multiple(true)
+                        .help("Name or glob pattern of the jail to roll back")
+                        .index(1)
+                        .required_unless("selector"),
+                )
+                .arg(selector_arg())
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("SNAPSHOT")
+                        .help("Snapshot name, or pattern to match the latest snapshot")
+                        .takes_value(true)
+                        .default_value("ready"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("upgrade")
+                .about("Upgrade a jail")
+                .arg(
+                    Arg::with_name("jail_name")
+                        .multiple(true)
+                        .help("Name or glob pattern of the jail to upgrade")
+                        .index(1)
+                        .required_unless("selector"),
+                )
+                .arg(selector_arg())
+                .arg(
+                    Arg::with_name("release")
+                        .long("release")
+                        .value_name("RELEASE")
+                        .help("Release to upgrade FreeBSD sourced jails to, defaults to the source release")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("auto-approve")
+                        .long("auto-approve")
+                        .help("Don't prompt before cloning jails again"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Create, list, destroy or compare the snapshots of a jail")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Snapshot a jail")
+                        .arg(snapshot_jail_arg())
+                        .arg(
+                            Arg::with_name("name")
+                                .help("Name of the snapshot, it's prefixed with the time")
+                                .index(2)
+                                .default_value("manual"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List the snapshots of a jail")
+                        .arg(snapshot_jail_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("destroy")
+                        .about("Destroy a snapshot")
+                        .arg(snapshot_jail_arg())
+                        .arg(
+                            Arg::with_name("snapshot")
+                                .help("Full name of the snapshot, after the @")
+                                .index(2)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("auto-approve")
+                                .long("auto-approve")
+                                .help("Don't prompt for confirmation"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("diff")
+                        .about("Show the files changed since a snapshot")
+                        .arg(snapshot_jail_arg())
+                        .arg(
+                            Arg::with_name("snapshot")
+                                .help("Snapshot name, or pattern to match the latest snapshot")
+                                .index(2)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("to")
+                                .help("Later snapshot to compare with, the jail as it is if not set")
+                                .index(3),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("Show the changes apply would make")
+                .arg(
+                    Arg::with_name("jail_name")
+                        .multiple(true)
+                        .help("Name or glob pattern of the jail to plan, all jails if not set")
+                        .index(1),
+                )
+                .arg(selector_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Show the state of jails")
+                .arg(
+                    Arg::with_name("jail_name")
+                        .multiple(true)
+                        .help("Name or glob pattern of the jail to show, all jails if not set")
+                        .index(1),
+                )
+                .arg(selector_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Show what rj has done, from the audit log")
+                .arg(
+                    Arg::with_name("jail_name")
+                        .help("Only what was done to this jail")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("run")
+                        .long("run")
+                        .value_name("RUN_ID")
+                        .help("Only what was done by this run")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .short("n")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Show the last N entries")
+                        .takes_value(true)
+                        .validator(is_positive),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check the config file without touching the host"),
+        )
         .subcommand(SubCommand::with_name("init").about("Initialise rj"))
 }
 
+// Select jails by label, shared by the subcommands that act on jails
+fn selector_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("selector")
+        .short("l")
+        .long("selector")
+        .value_name("SELECTOR")
+        .help("Only jails with labels matching the selector, e.g. label=web,label=prod")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+}
+
+// The jail the snapshot subcommands act on
+fn snapshot_jail_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("jail_name")
+        .help("Name or glob pattern of the jail")
+        .index(1)
+        .required(true)
+}
+
 // Parses the command line arguments and returns the matches.
 pub fn parse_args<'a>() -> clap::ArgMatches<'a> {
-    create_app().get_matches()
+    create_app().get_matches_safe().unwrap_or_else(|err| {
+        // --help and --version exit with 0
+        if !err.use_stderr() {
+            err.exit();
+        }
+        eprintln!("{}", err.message);
+        process::exit(Error::Config(err.message).exit_code());
+    })
 }
 
 fn is_valid_config(s: String) -> Result<(), String> {
@@ -107,3 +443,10 @@ fn is_valid_config(s: String) -> Result<(), String> {
     }
     Ok(())
 }
+
+fn is_positive(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("not a positive number: {}", &s)),
+    }
+}
@@ -1,27 +1,126 @@
-use crate::errors::CmdError;
-use anyhow::Result;
-use log::{error, info};
-use std::ffi::OsStr;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::path::Path;
-use std::process::{Command, Output, Stdio};
+use crate::errors::{CmdError, Error};
+use crate::executor::{self, CmdOutput, Executor, Stream, System};
+use anyhow::{bail, Result};
+use log::{error, info, warn};
+use std::cell::RefCell;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, OnceLock};
 use std::thread;
+use std::time::Duration;
 
-#[derive(Debug)]
+// how long to wait before retrying a failed command, doubled for each retry
+pub const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// runs the commands of threads that haven't set their own executor, set once
+// at startup when managing a remote host
+static DEFAULT_EXECUTOR: OnceLock<Arc<dyn Executor>> = OnceLock::new();
+
+// runs them when there's no default executor, one for all threads so that
+// they agree on which host they're using
+static SYSTEM: OnceLock<Arc<dyn Executor>> = OnceLock::new();
+
+// the timeout of commands that don't set their own
+static DEFAULT_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+thread_local! {
+    // prepended to streamed output, set by threads running a jail so that
+    // output from jails running in parallel can be told apart
+    static STREAM_PREFIX: RefCell<String> = RefCell::new(String::new());
+
+    // runs the commands, per thread so that each test can use its own
+    static EXECUTOR: RefCell<Option<Arc<dyn Executor>>> = RefCell::new(None);
+}
+
+pub fn set_stream_prefix(prefix: &str) {
+    STREAM_PREFIX.with(|p| *p.borrow_mut() = prefix.to_owned());
+}
+
+pub fn set_default_executor(executor: Arc<dyn Executor>) {
+    if DEFAULT_EXECUTOR.set(executor).is_err() {
+        panic!("default executor already set");
+    }
+}
+
+pub fn set_default_timeout(timeout: Duration) {
+    if DEFAULT_TIMEOUT.set(timeout).is_err() {
+        panic!("default timeout already set");
+    }
+}
+
+#[cfg(test)]
+pub fn set_executor(executor: Arc<dyn Executor>) {
+    EXECUTOR.with(|e| *e.borrow_mut() = Some(executor));
+}
+
+// the executor for the thread's commands, which stands for the host they run
+// on
+pub fn executor() -> Arc<dyn Executor> {
+    EXECUTOR
+        .with(|e| e.borrow().clone())
+        .unwrap_or_else(|| match DEFAULT_EXECUTOR.get() {
+            Some(executor) => executor.clone(),
+            None => SYSTEM.get_or_init(|| Arc::new(System)).clone(),
+        })
+}
+
+// Files on the host the commands run on, which may not be this one
+
+// the content of a file, None if there's no such file
+pub fn read_file(path: &Path) -> Result<Option<String>> {
+    executor().read_file(path)
+}
+
+pub fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    executor().write_file(path, contents)
+}
+
+pub fn remove_file(path: &Path) -> Result<()> {
+    executor().remove_file(path)
+}
+
+// copy a directory on this host to dest, removing anything else in dest
+pub fn copy_dir(src: &Path, dest: &Path) -> Result<()> {
+    executor().copy_dir(src, dest)
+}
+
+// download an xz archive and extract it to dest
+pub fn fetch_extract(url: &str, dest: &Path) -> Result<()> {
+    executor().fetch_extract(url, dest)
+}
+
+// A command to run, built up like std::process::Command and run by the
+// thread's Executor
+#[derive(Clone, Debug)]
 pub struct Cmd {
-    command: Command,
+    program: String,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+    current_dir: Option<PathBuf>,
+    input: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+    retries: u32,
+    retry_delay: Duration,
 }
 
 impl Cmd {
     pub fn new(program: &str) -> Cmd {
         Cmd {
-            command: Command::new(program.to_string()),
+            program: program.to_string(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            current_dir: None,
+            input: None,
+            timeout: None,
+            retries: 0,
+            retry_delay: RETRY_DELAY,
         }
     }
 
     pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Cmd {
-        self.command.arg(arg);
+        self.args.push(arg.as_ref().to_owned());
         self
     }
 
@@ -30,7 +129,9 @@ impl Cmd {
         I: IntoIterator<Item = S>,
         S: AsRef<OsStr>,
     {
-        self.command.args(args);
+        for arg in args {
+            self.arg(arg);
+        }
         self
     }
 
@@ -40,79 +141,259 @@ impl Cmd {
         K: AsRef<OsStr>,
         V: AsRef<OsStr>,
     {
-        self.command.envs(vars);
+        for (k, v) in vars {
+            self.envs
+                .push((k.as_ref().to_owned(), v.as_ref().to_owned()));
+        }
         self
     }
 
     #[allow(dead_code)]
     pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Cmd {
-        self.command.current_dir(dir);
+        self.current_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    // bytes to write to the command's stdin
+    pub fn stdin(&mut self, input: &[u8]) -> &mut Cmd {
+        self.input = Some(input.to_owned());
+        self
+    }
+
+    pub fn get_program(&self) -> &str {
+        &self.program
+    }
+
+    pub fn get_args(&self) -> impl Iterator<Item = &OsStr> {
+        self.args.iter().map(|a| a.as_os_str())
+    }
+
+    pub fn input(&self) -> Option<&[u8]> {
+        self.input.as_deref()
+    }
+
+    // Kill the command's process group if it runs for longer than timeout,
+    // None for the default timeout
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Cmd {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout.or_else(|| DEFAULT_TIMEOUT.get().copied())
+    }
+
+    // Run the command again if it fails, up to retries more times, waiting
+    // for delay before the first retry and twice as long before each after
+    pub fn retries(&mut self, retries: u32, delay: Duration) -> &mut Cmd {
+        self.retries = retries;
+        self.retry_delay = delay;
         self
     }
 
+    // A command that runs this one by passing it as a shell command line to
+    // program, e.g. ssh.  The directory, environment and stdin go with it.
+    pub fn wrap<I, S>(&self, program: &str, args: I) -> Cmd
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let mut line = String::new();
+        if let Some(dir) = &self.current_dir {
+            line.push_str(&format!("cd {} && ", quote(dir.as_os_str())));
+        }
+        if !self.envs.is_empty() {
+            line.push_str("env ");
+            for (k, v) in self.envs.iter() {
+                let mut var = k.to_owned();
+                var.push("=");
+                var.push(v);
+                line.push_str(&format!("{} ", quote(&var)));
+            }
+        }
+        line.push_str(&quote(OsStr::new(&self.program)));
+        for arg in self.args.iter() {
+            line.push(' ');
+            line.push_str(&quote(arg));
+        }
+
+        let mut wrapped = Cmd::new(program);
+        wrapped.args(args).arg(line);
+        wrapped.input = self.input.to_owned();
+        wrapped
+    }
+
+    // the std::process::Command to run this on the host
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args).envs(self.envs.iter().cloned());
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        command
+    }
+
     pub fn exec(&mut self) -> Result<()> {
-        let output = self.command.output()?;
-        Self::check_exit_status(&self, output)?;
-        Ok(())
+        self.retrying(|| {
+            let output = self.output()?;
+            Self::check_exit_status(&self, output)?;
+            Ok(())
+        })
     }
 
     pub fn capture(&mut self) -> Result<String> {
-        let output = self.command.output()?;
-        Self::check_exit_status(&self, output)
+        self.retrying(|| {
+            let output = self.output()?;
+            Self::check_exit_status(&self, output)
+        })
+    }
+
+    // Run a command and return whether it exited with 0, for commands used
+    // as tests.  Fails only if it couldn't be run or didn't finish.
+    pub fn succeeds(&mut self) -> Result<bool> {
+        let output = self.output()?;
+        let code = output.code;
+        if output.timed_out || output.interrupted {
+            self.check_exit_status(output)?;
+        }
+        Ok(code == Some(0))
     }
 
     // Run a command and stream stdout and stderr into the logger
     // Fail on exit status other than 0
     pub fn stream(&mut self) -> Result<()> {
-        let mut child = self
-            .command
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
-
-        let stdout = child.stdout.take().unwrap();
-        let stderr = child.stderr.take().unwrap();
-
-        let stderr_handle = thread::spawn(|| {
-            for line in BufReader::new(stderr).lines() {
-                error!("{}", line.unwrap());
+        let prefix = STREAM_PREFIX.with(|p| p.borrow().clone());
+        self.retrying(|| {
+            self.not_interrupted()?;
+            let output = executor().stream(self, &|stream, line| match stream {
+                Stream::Stdout => info!("{}{}", prefix, line),
+                Stream::Stderr => error!("{}{}", prefix, line),
+            })?;
+
+            if output.code == Some(0) && !output.timed_out && !output.interrupted {
+                Ok(())
+            } else {
+                let message = match self.unfinished(&output) {
+                    Some(message) => message,
+                    None => format!("Failed command: {}", &self),
+                };
+                Err(self.error(&output, message))
             }
-        });
+        })
+    }
 
-        for line in BufReader::new(stdout).lines() {
-            info!("{}", line?);
-        }
+    fn output(&self) -> Result<CmdOutput> {
+        self.not_interrupted()?;
+        executor().output(self)
+    }
 
-        stderr_handle.join().unwrap();
-        let status = child.wait()?;
+    // once rj is interrupted no more commands are started
+    fn not_interrupted(&self) -> Result<()> {
+        if executor::interrupted() {
+            bail!(Error::Aborted(format!("interrupted before {}", &self)));
+        }
+        Ok(())
+    }
 
-        if status.success() {
-            Ok(())
+    // the error for a command that didn't succeed, one that was interrupted
+    // was aborted by the user rather than failing
+    fn error(&self, output: &CmdOutput, message: String) -> anyhow::Error {
+        if output.interrupted {
+            anyhow::Error::new(Error::Aborted(message))
         } else {
             let err = CmdError {
-                code: status.code(),
-                message: format!("Failed command: {:?}", &self.command),
+                code: output.code,
+                message,
             };
-            Err(anyhow::Error::new(err))
+            anyhow::Error::new(err)
+        }
+    }
+
+    // why a command didn't finish, None if it did
+    fn unfinished(&self, output: &CmdOutput) -> Option<String> {
+        if output.interrupted {
+            Some(format!("interrupted during {}", &self))
+        } else if output.timed_out {
+            let timeout = self.get_timeout().unwrap_or_default();
+            Some(format!(
+                "timed out after {}s: {}",
+                timeout.as_secs_f64(),
+                &self
+            ))
+        } else {
+            None
+        }
+    }
+
+    // Run until run succeeds or the retries run out, an interrupted command
+    // isn't retried
+    fn retrying<T>(&self, run: impl Fn() -> Result<T>) -> Result<T> {
+        let mut delay = self.retry_delay;
+        let mut retry = 0;
+        loop {
+            match run() {
+                Err(err) if retry < self.retries && !executor::interrupted() => {
+                    retry += 1;
+                    warn!(
+                        "{}, retrying in {}s ({} of {})",
+                        err,
+                        delay.as_secs_f64(),
+                        retry,
+                        self.retries
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                },
+                result => return result,
+            }
         }
     }
 
+    // Run a command on this host even when managing a remote one, for
+    // commands that read local files
+    pub fn capture_local(&mut self) -> Result<String> {
+        let output = System.output(self)?;
+        self.check_exit_status(output)
+    }
+
     // Return Err if exit status is not 0
-    fn check_exit_status(&self, output: Output) -> Result<String> {
-        let stdout = String::from_utf8(output.stdout)?;
-        let stderr = String::from_utf8(output.stderr)?;
-        if output.status.success() {
-            Ok(stdout)
+    pub fn check_exit_status(&self, output: CmdOutput) -> Result<String> {
+        if let Some(message) = self.unfinished(&output) {
+            Err(self.error(&output, message))
+        } else if output.code == Some(0) {
+            Ok(output.stdout)
         } else {
             let cmd_err = CmdError {
-                code: output.status.code(),
-                message: format!("Failed command: '{:?}', stderr: {}", &self.command, stderr),
+                code: output.code,
+                message: format!("Failed command: '{}', stderr: {}", &self, output.stderr),
             };
             Err(anyhow::Error::new(cmd_err))
         }
     }
 }
 
+// the command line, as recorded by a Fake
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in self.args.iter() {
+            write!(f, " {}", arg.to_string_lossy())?;
+        }
+        Ok(())
+    }
+}
+
+// Quote a word for sh, words that don't need it are left as they are
+pub fn quote(word: &OsStr) -> String {
+    let word = word.to_string_lossy();
+    let plain = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.into_owned()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
 #[macro_export]
 macro_rules! cmd {
     ( $program:expr $(, $arg:expr )* $(,)? ) => {
@@ -158,6 +439,7 @@ mod tests {
     use pretty_assertions::assert_eq;
     use simplelog::{Config, LevelFilter, WriteLogger};
     use std::collections::HashMap;
+    use std::io::prelude::*;
     use tempfile::NamedTempFile;
 
     #[test]
@@ -238,4 +520,78 @@ mod tests {
     fn stream_error() {
         assert!(cmd_stream!("cat", "nonexistent").is_err());
     }
+
+    #[test]
+    fn stdin() -> Result<()> {
+        let output = Cmd::new("cat").stdin(b"from stdin").capture()?;
+        assert_eq!(output, "from stdin");
+        Ok(())
+    }
+
+    #[test]
+    fn wrap() -> Result<()> {
+        let mut cmd = Cmd::new("printf");
+        cmd.args(["%s|%s", "it's", "a b"])
+            .envs(vec![("A", "1 2")])
+            .current_dir("/tmp")
+            .stdin(b"input");
+        let wrapped = cmd.wrap("sh", ["-c"]);
+        assert_eq!(
+            wrapped.to_string(),
+            "sh -c cd /tmp && env 'A=1 2' printf '%s|%s' 'it'\\''s' 'a b'"
+        );
+        assert_eq!(wrapped.input(), Some(&b"input"[..]));
+
+        let output = Cmd::new("sh")
+            .args(["-c", "echo $A"])
+            .envs(vec![("A", "1 2")])
+            .wrap("sh", ["-c"])
+            .capture()?;
+        assert_eq!(output, "1 2\n");
+        Ok(())
+    }
+
+    #[test]
+    fn retries() -> Result<()> {
+        let fake = crate::executor::fake();
+        let failed = CmdOutput {
+            code: Some(1),
+            ..CmdOutput::default()
+        };
+        fake.once("pkg", failed.clone()).once("pkg", failed.clone());
+
+        Cmd::new("pkg")
+            .arg("install")
+            .retries(2, Duration::ZERO)
+            .exec()?;
+        assert_eq!(fake.calls(), vec!["pkg install"; 3]);
+
+        fake.once("pkg", failed.clone()).once("pkg", failed);
+        let err = Cmd::new("pkg")
+            .arg("install")
+            .retries(1, Duration::ZERO)
+            .stream()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "(1) Failed command: pkg install");
+        Ok(())
+    }
+
+    #[test]
+    fn timed_out() {
+        let fake = crate::executor::fake();
+        fake.on_output(
+            "sleep",
+            CmdOutput {
+                timed_out: true,
+                ..CmdOutput::default()
+            },
+        );
+        let err = Cmd::new("sleep")
+            .arg("10")
+            .timeout(Some(Duration::from_millis(500)))
+            .exec()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "timed out after 0.5s: sleep 10");
+        assert!(Cmd::new("sleep").arg("10").succeeds().is_err());
+    }
 }
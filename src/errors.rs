@@ -21,3 +21,119 @@ impl error::Error for CmdError {
         &self.message
     }
 }
+
+// The kinds of error rj fails with, each exits with its own code so that
+// scripts can tell them apart without parsing messages.  Anything else
+// exits with 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // the config file or command line is wrong
+    Config(String),
+    // the host isn't set up for rj or something a jail needs is missing
+    Prerequisite(String),
+    // a zfs command failed
+    Zfs(String),
+    // a jail failed to start or stop
+    Jail(String),
+    // a provisioner failed
+    Provisioner(String),
+    // the user said no or pressed Ctrl-C
+    Aborted(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => 2,
+            Error::Prerequisite(_) => 3,
+            Error::Zfs(_) => 4,
+            Error::Jail(_) => 5,
+            Error::Provisioner(_) => 6,
+            Error::Aborted(_) => 130,
+        }
+    }
+
+    // the same kind of error with another message
+    pub fn with_message(&self, message: String) -> Error {
+        match self {
+            Error::Config(_) => Error::Config(message),
+            Error::Prerequisite(_) => Error::Prerequisite(message),
+            Error::Zfs(_) => Error::Zfs(message),
+            Error::Jail(_) => Error::Jail(message),
+            Error::Provisioner(_) => Error::Provisioner(message),
+            Error::Aborted(_) => Error::Aborted(message),
+        }
+    }
+
+    // The kind of an error, if it has one
+    pub fn of(err: &anyhow::Error) -> Option<&Error> {
+        err.chain().find_map(|e| e.downcast_ref::<Error>())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Error::Config(message)
+            | Error::Prerequisite(message)
+            | Error::Zfs(message)
+            | Error::Jail(message)
+            | Error::Provisioner(message)
+            | Error::Aborted(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// Give the error of a result a kind, e.g. .kind(Error::Zfs).  Errors that
+// already have one keep it, so a provisioner that's interrupted is aborted
+// rather than failed.
+pub trait Kind<T> {
+    fn kind(self, kind: fn(String) -> Error) -> anyhow::Result<T>;
+}
+
+impl<T> Kind<T> for anyhow::Result<T> {
+    fn kind(self, kind: fn(String) -> Error) -> anyhow::Result<T> {
+        self.map_err(|err| match Error::of(&err) {
+            Some(_) => err,
+            None => kind(err.to_string()).into(),
+        })
+    }
+}
+
+// the code rj exits with for an error
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    Error::of(err).map_or(1, Error::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn kind() {
+        let result: anyhow::Result<()> = Err(anyhow!("cannot open 'zroot/nope'"));
+        let err = result.kind(Error::Zfs).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::Zfs("cannot open 'zroot/nope'".to_string()))
+        );
+        assert_eq!(exit_code(&err), 4);
+
+        // the first kind given sticks
+        let err = Err::<(), _>(err).kind(Error::Provisioner).unwrap_err();
+        assert_eq!(
+            Error::of(&err),
+            Some(&Error::Zfs("cannot open 'zroot/nope'".to_string()))
+        );
+
+        assert_eq!(exit_code(&anyhow!("other")), 1);
+        assert_eq!(
+            exit_code(&Error::Aborted("aborting".to_string()).into()),
+            130
+        );
+    }
+}
@@ -0,0 +1,663 @@
+use crate::cmd::{self, Cmd};
+use crate::errors::Error;
+use crate::util;
+use anyhow::Result;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// What a command printed, code is None if it was killed by a signal
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CmdOutput {
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    // killed for running longer than its timeout
+    pub timed_out: bool,
+    // stopped by Ctrl-C
+    pub interrupted: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+// Runs the commands built with Cmd and reads and writes the files of the host
+// they run on.  System runs them on this host and Ssh on another, tests swap
+// in a Fake to check which commands would be run without running them.
+pub trait Executor: Send + Sync {
+    // run a command to completion and collect its output
+    fn output(&self, cmd: &Cmd) -> Result<CmdOutput>;
+
+    // run a command handing each line it prints to line as it's printed,
+    // returns how it ended with stdout and stderr left empty
+    fn stream(&self, cmd: &Cmd, line: &(dyn Fn(Stream, &str) + Sync)) -> Result<CmdOutput>;
+
+    // the content of a file, None if there's no such file
+    fn read_file(&self, path: &Path) -> Result<Option<String>>;
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()>;
+
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    // copy a directory on this host to dest, removing anything else in dest
+    fn copy_dir(&self, src: &Path, dest: &Path) -> Result<()>;
+
+    // download an xz archive and extract it to dest
+    fn fetch_extract(&self, url: &str, dest: &Path) -> Result<()>;
+}
+
+// Set by Ctrl-C, running commands are interrupted and no more are started
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// the number of commands being waited for
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+// how long a command has to exit once signalled before it's killed
+const GRACE: Duration = Duration::from_secs(5);
+
+// how often to check whether a command has exited
+const POLL: Duration = Duration::from_millis(20);
+
+// Commands run in their own process group so that the terminal's Ctrl-C
+// only reaches rj, which passes it on to the commands it's running.  With
+// nothing running, or on a second Ctrl-C, rj exits straight away.
+extern "C" fn on_interrupt(_: libc::c_int) {
+    if RUNNING.load(Ordering::SeqCst) == 0 || INTERRUPTED.swap(true, Ordering::SeqCst) {
+        let msg = b"interrupted\n";
+        unsafe {
+            libc::write(2, msg.as_ptr() as *const libc::c_void, msg.len());
+            libc::_exit(130);
+        }
+    }
+}
+
+pub fn handle_interrupts() {
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = on_interrupt;
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Counts a command as running while it's held
+struct Running;
+
+impl Running {
+    fn start() -> Running {
+        RUNNING.fetch_add(1, Ordering::SeqCst);
+        Running
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct System;
+
+impl System {
+    fn spawn(cmd: &Cmd) -> Result<Child> {
+        let stdin = match cmd.input() {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        };
+        cmd.command()
+            .process_group(0)
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => {
+                    Error::Prerequisite(format!("{}: command not found", cmd.get_program())).into()
+                },
+                _ => anyhow::Error::new(e),
+            })
+    }
+
+    // Wait for a command to exit, signalling its process group if it runs
+    // out of time or rj is interrupted and killing it if that's not enough
+    fn wait(child: &mut Child, timeout: Option<Duration>) -> Result<CmdOutput> {
+        let group = -(child.id() as libc::pid_t);
+        let start = Instant::now();
+        let mut output = CmdOutput::default();
+        let mut signalled: Option<Instant> = None;
+        let mut killed = false;
+
+        loop {
+            if let Some(status) = child.try_wait()? {
+                output.code = status.code();
+                return Ok(output);
+            }
+            match signalled {
+                None if interrupted() => {
+                    output.interrupted = true;
+                    unsafe { libc::kill(group, libc::SIGINT) };
+                    signalled = Some(Instant::now());
+                },
+                None if timeout.is_some_and(|t| start.elapsed() >= t) => {
+                    output.timed_out = true;
+                    unsafe { libc::kill(group, libc::SIGTERM) };
+                    signalled = Some(Instant::now());
+                },
+                Some(at) if !killed && at.elapsed() >= GRACE => {
+                    unsafe { libc::kill(group, libc::SIGKILL) };
+                    killed = true;
+                },
+                _ => (),
+            }
+            thread::sleep(POLL);
+        }
+    }
+}
+
+impl Executor for System {
+    fn output(&self, cmd: &Cmd) -> Result<CmdOutput> {
+        let _running = Running::start();
+        let mut child = Self::spawn(cmd)?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        thread::scope(|s| {
+            // write and read from other threads so that a command printing a
+            // lot before reading its input can't block us both.  A command
+            // that exits without reading it all fails the write, it's the
+            // exit code that counts.
+            if let (Some(mut stdin), Some(input)) = (stdin, cmd.input()) {
+                s.spawn(move || stdin.write_all(input));
+            }
+            let out = s.spawn(|| read_all(stdout));
+            let err = s.spawn(|| read_all(stderr));
+
+            let mut output = Self::wait(&mut child, cmd.get_timeout())?;
+            output.stdout = out.join().unwrap()?;
+            output.stderr = err.join().unwrap()?;
+            Ok(output)
+        })
+    }
+
+    fn stream(&self, cmd: &Cmd, line: &(dyn Fn(Stream, &str) + Sync)) -> Result<CmdOutput> {
+        let _running = Running::start();
+        let mut child = Self::spawn(cmd)?;
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        thread::scope(|s| {
+            let out = s.spawn(|| -> Result<()> {
+                for l in BufReader::new(stdout).lines() {
+                    line(Stream::Stdout, &l?);
+                }
+                Ok(())
+            });
+            let err = s.spawn(|| -> Result<()> {
+                for l in BufReader::new(stderr).lines() {
+                    line(Stream::Stderr, &l?);
+                }
+                Ok(())
+            });
+
+            let output = Self::wait(&mut child, cmd.get_timeout())?;
+            out.join().unwrap()?;
+            err.join().unwrap()?;
+            Ok(output)
+        })
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Option<String>> {
+        if path.is_file() {
+            Ok(Some(fs::read_to_string(path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        Ok(fs::write(path, contents)?)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        Ok(fs::remove_file(path)?)
+    }
+
+    fn copy_dir(&self, src: &Path, dest: &Path) -> Result<()> {
+        let mut cmd = Cmd::new("rsync");
+        cmd.args(["-r", "--delete"]).arg(src).arg(dest);
+        cmd.check_exit_status(self.output(&cmd)?)?;
+        Ok(())
+    }
+
+    fn fetch_extract(&self, url: &str, dest: &Path) -> Result<()> {
+        util::fetch_extract(url, dest)
+    }
+}
+
+fn read_all(mut from: impl Read) -> Result<String> {
+    let mut read = String::new();
+    from.read_to_string(&mut read)?;
+    Ok(read)
+}
+
+// Runs commands on another host by passing them to ssh, files are read and
+// written by running commands there too.  The remote user's login shell has
+// to accept sh quoting.
+pub struct Ssh {
+    // the ssh command, a stand-in in tests
+    program: String,
+    // [user@]host or an ssh:// URI
+    destination: String,
+    options: Vec<String>,
+}
+
+// exit code of the read_file script for a missing file
+const NO_FILE: i32 = 100;
+
+impl Ssh {
+    pub fn new(destination: &str, options: &[String]) -> Ssh {
+        Ssh {
+            program: "ssh".to_string(),
+            destination: destination.to_string(),
+            options: options.to_owned(),
+        }
+    }
+
+    // the options passed to ssh, never prompt as there may be no terminal
+    // to prompt on
+    fn ssh_args(&self) -> Vec<String> {
+        let mut args = vec!["-o".to_string(), "BatchMode=yes".to_string()];
+        args.extend(self.options.iter().cloned());
+        args
+    }
+
+    // The ssh command running cmd on the host.  A command with a timeout
+    // runs under timeout(1) there so that it's stopped on the host too, ssh
+    // is given long enough for timeout to kill it and report back.
+    fn wrap(&self, cmd: &Cmd) -> Cmd {
+        let mut args = self.ssh_args();
+        args.push(self.destination.to_owned());
+        match cmd.get_timeout() {
+            Some(timeout) => {
+                let secs = timeout.as_secs_f64().to_string();
+                let grace = GRACE.as_secs().to_string();
+                let mut ssh = cmd
+                    .wrap("timeout", ["-k", &grace, &secs, "sh", "-c"])
+                    .wrap(&self.program, args);
+                ssh.timeout(Some(timeout + GRACE * 2));
+                ssh
+            },
+            None => cmd.wrap(&self.program, args),
+        }
+    }
+
+    // timeout(1) exits with 124 when the command ran out of time
+    fn ended(cmd: &Cmd, mut output: CmdOutput) -> CmdOutput {
+        if cmd.get_timeout().is_some() && output.code == Some(124) {
+            output.timed_out = true;
+        }
+        output
+    }
+
+    // run a sh script on the host, failing if it exits with other than 0
+    fn script(&self, script: &str, args: &[&str], input: Option<&[u8]>) -> Result<String> {
+        let cmd = Self::script_cmd(script, args, input);
+        cmd.check_exit_status(self.output(&cmd)?)
+    }
+
+    fn script_cmd(script: &str, args: &[&str], input: Option<&[u8]>) -> Cmd {
+        let mut cmd = Cmd::new("sh");
+        cmd.args(["-c", script, "sh"]).args(args);
+        if let Some(input) = input {
+            cmd.stdin(input);
+        }
+        cmd
+    }
+}
+
+impl Executor for Ssh {
+    fn output(&self, cmd: &Cmd) -> Result<CmdOutput> {
+        Ok(Self::ended(cmd, System.output(&self.wrap(cmd))?))
+    }
+
+    fn stream(&self, cmd: &Cmd, line: &(dyn Fn(Stream, &str) + Sync)) -> Result<CmdOutput> {
+        Ok(Self::ended(cmd, System.stream(&self.wrap(cmd), line)?))
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Option<String>> {
+        let script = format!("[ -f \"$1\" ] || exit {}; cat \"$1\"", NO_FILE);
+        let cmd = Self::script_cmd(&script, &[&path.to_string_lossy()], None);
+        let output = self.output(&cmd)?;
+        if output.code == Some(NO_FILE) {
+            return Ok(None);
+        }
+        Ok(Some(cmd.check_exit_status(output)?))
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        self.script("cat > \"$1\"", &[&path.to_string_lossy()], Some(contents))?;
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.script("rm -- \"$1\"", &[&path.to_string_lossy()], None)?;
+        Ok(())
+    }
+
+    // rsync runs here and copies over its own ssh connection
+    fn copy_dir(&self, src: &Path, dest: &Path) -> Result<()> {
+        let rsh = std::iter::once(&self.program)
+            .chain(self.ssh_args().iter())
+            .map(|a| cmd::quote(OsStr::new(a)))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let mut cmd = Cmd::new("rsync");
+        cmd.args(["-r", "--delete", "-e", &rsh])
+            .arg(src)
+            .arg(format!("{}:{}", self.destination, dest.display()));
+        cmd.check_exit_status(System.output(&cmd)?)?;
+        Ok(())
+    }
+
+    // fetch and extract with FreeBSD's fetch and tar on the host, so that
+    // the archive doesn't come through here
+    fn fetch_extract(&self, url: &str, dest: &Path) -> Result<()> {
+        self.script(
+            "fetch -o - \"$1\" | tar -xpf - -C \"$2\"",
+            &[url, &dest.to_string_lossy()],
+            None,
+        )?;
+        Ok(())
+    }
+}
+
+// Records the commands it's given and answers them from a script instead of
+// running them.  Commands the script doesn't cover succeed with no output.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Fake {
+    // command line prefixes, the output of commands starting with them and
+    // whether to only answer once
+    script: std::sync::Mutex<Vec<(String, CmdOutput, bool)>>,
+    calls: std::sync::Mutex<Vec<String>>,
+    files: std::sync::Mutex<std::collections::HashMap<std::path::PathBuf, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl Fake {
+    // answer commands starting with prefix, earlier answers win
+    pub fn on(&self, prefix: &str, code: i32, stdout: &str) -> &Fake {
+        self.on_output(
+            prefix,
+            CmdOutput {
+                code: Some(code),
+                stdout: stdout.to_string(),
+                ..CmdOutput::default()
+            },
+        )
+    }
+
+    pub fn on_output(&self, prefix: &str, output: CmdOutput) -> &Fake {
+        self.script
+            .lock()
+            .unwrap()
+            .push((prefix.to_string(), output, false));
+        self
+    }
+
+    // answer the next command starting with prefix, then forget the answer
+    pub fn once(&self, prefix: &str, output: CmdOutput) -> &Fake {
+        self.script
+            .lock()
+            .unwrap()
+            .push((prefix.to_string(), output, true));
+        self
+    }
+
+    // a file for read_file to find
+    pub fn file(&self, path: &str, contents: &str) -> &Fake {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.into(), contents.as_bytes().to_owned());
+        self
+    }
+
+    // the command lines run and the files changed so far
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, call: String) {
+        self.calls.lock().unwrap().push(call);
+    }
+
+    fn answer(&self, cmd: &Cmd) -> CmdOutput {
+        let line = cmd.to_string();
+        self.record(line.to_owned());
+        let mut script = self.script.lock().unwrap();
+        match script
+            .iter()
+            .position(|(prefix, _, _)| line.starts_with(prefix.as_str()))
+        {
+            Some(i) if script[i].2 => script.remove(i).1,
+            Some(i) => script[i].1.to_owned(),
+            None => CmdOutput {
+                code: Some(0),
+                ..CmdOutput::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+impl Executor for Fake {
+    fn output(&self, cmd: &Cmd) -> Result<CmdOutput> {
+        Ok(self.answer(cmd))
+    }
+
+    fn stream(&self, cmd: &Cmd, line: &(dyn Fn(Stream, &str) + Sync)) -> Result<CmdOutput> {
+        let output = self.answer(cmd);
+        for l in output.stdout.lines() {
+            line(Stream::Stdout, l);
+        }
+        for l in output.stderr.lines() {
+            line(Stream::Stderr, l);
+        }
+        Ok(CmdOutput {
+            stdout: String::new(),
+            stderr: String::new(),
+            ..output
+        })
+    }
+
+    fn read_file(&self, path: &Path) -> Result<Option<String>> {
+        match self.files.lock().unwrap().get(path) {
+            Some(contents) => Ok(Some(String::from_utf8(contents.to_owned())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        self.record(format!("write_file {}", path.display()));
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), contents.to_owned());
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.record(format!("remove_file {}", path.display()));
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => anyhow::bail!("{}: no such file", path.display()),
+        }
+    }
+
+    fn copy_dir(&self, src: &Path, dest: &Path) -> Result<()> {
+        self.record(format!("copy_dir {} {}", src.display(), dest.display()));
+        Ok(())
+    }
+
+    fn fetch_extract(&self, url: &str, dest: &Path) -> Result<()> {
+        self.record(format!("fetch_extract {} {}", url, dest.display()));
+        Ok(())
+    }
+}
+
+// Run commands on this thread with a new Fake
+#[cfg(test)]
+pub fn fake() -> std::sync::Arc<Fake> {
+    let fake = std::sync::Arc::new(Fake::default());
+    crate::cmd::set_executor(fake.clone());
+    fake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    #[test]
+    fn system() -> Result<()> {
+        let output =
+            System.output(Cmd::new("sh").args(["-c", "echo out; echo err >&2; exit 3"]))?;
+        assert_eq!(
+            output,
+            CmdOutput {
+                code: Some(3),
+                stdout: "out\n".to_string(),
+                stderr: "err\n".to_string(),
+                ..CmdOutput::default()
+            }
+        );
+
+        let lines = Mutex::new(Vec::new());
+        let output = System.stream(
+            Cmd::new("sh").args(["-c", "echo a; echo b >&2"]),
+            &|s, l| lines.lock().unwrap().push((s, l.to_string())),
+        )?;
+        assert_eq!(output.code, Some(0));
+        let mut lines = lines.into_inner().unwrap();
+        lines.sort_by_key(|(s, _)| *s == Stream::Stderr);
+        assert_eq!(
+            lines,
+            vec![
+                (Stream::Stdout, "a".to_string()),
+                (Stream::Stderr, "b".to_string())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fake() -> Result<()> {
+        let fake = Fake::default();
+        fake.on("zfs list zroot/a", 1, "")
+            .on("zfs list", 0, "zroot\n");
+
+        assert_eq!(
+            fake.output(Cmd::new("zfs").args(["list", "zroot/a"]))?.code,
+            Some(1)
+        );
+        assert_eq!(fake.output(Cmd::new("zfs").arg("list"))?.stdout, "zroot\n");
+        assert_eq!(fake.output(Cmd::new("sysrc").arg("-n"))?.code, Some(0));
+        assert_eq!(
+            fake.calls(),
+            vec!["zfs list zroot/a", "zfs list", "sysrc -n"]
+        );
+        Ok(())
+    }
+
+    // ssh stand-in that runs the command line it's given here
+    const SSH: &str = r#"#!/bin/sh
+while [ "$1" = "-o" ]; do shift 2; done
+shift
+exec sh -c "$*"
+"#;
+
+    #[test]
+    fn ssh() -> Result<()> {
+        let dir = TempDir::new()?;
+        let program = dir.path().join("ssh");
+        fs::write(&program, SSH)?;
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755))?;
+        let ssh = Ssh {
+            program: program.to_string_lossy().into_owned(),
+            ..Ssh::new("root@web1", &[])
+        };
+
+        let output = ssh.output(Cmd::new("printf").args(["%s|", "it's", "a b"]))?;
+        assert_eq!(output.stdout, "it's|a b|");
+
+        let lines = Mutex::new(Vec::new());
+        let output = ssh.stream(Cmd::new("sh").args(["-c", "echo a; exit 2"]), &|s, l| {
+            lines.lock().unwrap().push((s, l.to_string()))
+        })?;
+        assert_eq!(output.code, Some(2));
+        assert_eq!(
+            lines.into_inner().unwrap(),
+            vec![(Stream::Stdout, "a".to_string())]
+        );
+
+        let path = dir.path().join("jail conf");
+        assert_eq!(ssh.read_file(&path)?, None);
+        ssh.write_file(&path, b"a \"quoted\" 'value'\n")?;
+        assert_eq!(
+            ssh.read_file(&path)?,
+            Some("a \"quoted\" 'value'\n".to_string())
+        );
+        ssh.remove_file(&path)?;
+        assert!(!path.exists());
+        assert!(ssh.remove_file(&path).is_err());
+
+        let mut cmd = Cmd::new("pkg");
+        cmd.arg("install")
+            .timeout(Some(Duration::from_millis(1500)));
+        let wrapped = ssh.wrap(&cmd);
+        assert!(wrapped
+            .to_string()
+            .ends_with(" root@web1 timeout -k 5 1.5 sh -c 'pkg install'"));
+        assert_eq!(wrapped.get_timeout(), Some(Duration::from_millis(11500)));
+        let timed_out = CmdOutput {
+            code: Some(124),
+            ..CmdOutput::default()
+        };
+        assert!(Ssh::ended(&cmd, timed_out).timed_out);
+        Ok(())
+    }
+
+    #[test]
+    fn timeout() -> Result<()> {
+        // the background sleep holds stdout open, only killing the whole
+        // process group lets the output be read
+        let start = Instant::now();
+        let output = System.output(
+            Cmd::new("sh")
+                .args(["-c", "sleep 10 & echo started; wait"])
+                .timeout(Some(Duration::from_millis(200))),
+        )?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(output.timed_out);
+        assert_eq!(output.stdout, "started\n");
+        Ok(())
+    }
+}
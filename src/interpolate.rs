@@ -0,0 +1,145 @@
+use crate::secret::Secrets;
+use anyhow::{bail, Result};
+use indexmap::IndexMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+// The variables that can be used in config values:
+//   ${jail.name} and ${jail.mountpoint} for the jail being configured
+//   ${count.index} for jails made from a definition with a count
+//   ${env.NAME} for environment variables
+//   ${secret.NAME} for the secrets defined in [secret]
+//   ${NAME} for the variables defined in [vars]
+// '$${' is a literal '${'.
+pub struct Vars<'a> {
+    jail_name: &'a str,
+    jail_mountpoint: &'a Path,
+    index: Option<u32>,
+    user: &'a IndexMap<String, String>,
+    secrets: &'a Secrets,
+}
+
+impl<'a> Vars<'a> {
+    pub fn new(
+        jail_name: &'a str,
+        jail_mountpoint: &'a Path,
+        index: Option<u32>,
+        user: &'a IndexMap<String, String>,
+        secrets: &'a Secrets,
+    ) -> Vars<'a> {
+        Vars {
+            jail_name,
+            jail_mountpoint,
+            index,
+            user,
+            secrets,
+        }
+    }
+
+    fn get(&self, name: &str) -> Result<Option<String>> {
+        Ok(match name {
+            "jail.name" => Some(self.jail_name.to_owned()),
+            "jail.mountpoint" => Some(self.jail_mountpoint.display().to_string()),
+            "count.index" => self.index.map(|i| i.to_string()),
+            _ if name.starts_with("env.") => env::var(&name[4..]).ok(),
+            _ if name.starts_with("secret.") => self.secrets.get(&name[7..])?,
+            _ => self.user.get(name).cloned(),
+        })
+    }
+
+    pub fn expand(&self, value: &str) -> Result<String> {
+        let mut expanded = String::new();
+        let mut rest = value;
+
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                expanded.push_str(&rest[..start - 1]);
+                expanded.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            expanded.push_str(&rest[..start]);
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => bail!("unterminated variable: {}", &rest[start..]),
+            };
+            let name = &rest[start + 2..end];
+            match self.get(name)? {
+                Some(v) => expanded.push_str(&v),
+                None => bail!("undefined variable: ${{{}}}", name),
+            }
+            rest = &rest[end + 1..];
+        }
+        expanded.push_str(rest);
+
+        Ok(expanded)
+    }
+
+    // Expand a value in place, errors name the key the value came from
+    pub fn expand_string(&self, key: &str, value: &mut String, errors: &mut Vec<String>) {
+        match self.expand(value) {
+            Ok(v) => *value = v,
+            Err(e) => errors.push(format!("{}: {}", key, e)),
+        }
+    }
+
+    pub fn expand_path(&self, key: &str, value: &mut PathBuf, errors: &mut Vec<String>) {
+        let mut s = value.display().to_string();
+        self.expand_string(key, &mut s, errors);
+        *value = PathBuf::from(s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::indexmap;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn expand() -> Result<()> {
+        let user = indexmap! { "domain".to_owned() => "example.net".to_owned() };
+        let secrets = Secrets::default();
+        let vars = Vars::new("web1", Path::new("/jails/web1"), Some(3), &user, &secrets);
+        env::set_var("RJ_INTERPOLATE_TEST", "from env");
+
+        assert_eq!(vars.expand("plain")?, "plain");
+        assert_eq!(vars.expand("${jail.name}.${domain}")?, "web1.example.net");
+        assert_eq!(vars.expand("${jail.mountpoint}/etc")?, "/jails/web1/etc");
+        assert_eq!(vars.expand("10.0.0.1${count.index}")?, "10.0.0.13");
+        assert_eq!(vars.expand("${env.RJ_INTERPOLATE_TEST}!")?, "from env!");
+        assert_eq!(
+            vars.expand("cost: $5, $${jail.name}")?,
+            "cost: $5, ${jail.name}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let user = IndexMap::new();
+        let secrets = Secrets::default();
+        let vars = Vars::new("web1", Path::new("/jails/web1"), None, &user, &secrets);
+
+        for (value, error) in &[
+            ("${nope}", "undefined variable: ${nope}"),
+            ("${count.index}", "undefined variable: ${count.index}"),
+            ("${secret.nope}", "undefined variable: ${secret.nope}"),
+            (
+                "${env.RJ_UNDEFINED_TEST}",
+                "undefined variable: ${env.RJ_UNDEFINED_TEST}",
+            ),
+            ("a ${jail.name", "unterminated variable: ${jail.name"),
+        ] {
+            assert_eq!(vars.expand(value).unwrap_err().to_string(), *error);
+        }
+
+        let mut errors = Vec::new();
+        let mut value = "${nope}".to_owned();
+        vars.expand_string("conf.host_hostname", &mut value, &mut errors);
+        assert_eq!(
+            errors,
+            vec!["conf.host_hostname: undefined variable: ${nope}"]
+        );
+    }
+}
@@ -1,22 +1,29 @@
 #![allow(dead_code)]
 use crate::cmd;
+use crate::cmd::Cmd;
 use crate::cmd_capture;
+use crate::errors::{Error, Kind};
+use crate::lock::{Lock, Wait};
+use crate::plan;
+use crate::plan::{Action, Plan};
 use crate::provisioner::Provisioner;
 use crate::settings;
+use crate::source::freebsd::RELEASE_PROPERTY;
 use crate::source::Source;
 use crate::template::fstab::Fstab;
 use crate::template::jail_conf::JailConf;
 use crate::volumes::Volume;
 use crate::zfs;
-use anyhow::Result;
+use crate::zfs::FileChange;
+use anyhow::{bail, Result};
 use askama::Template;
-use difference::Changeset;
+use chrono::Utc;
 use indexmap::{indexmap, IndexMap};
 use log::info;
+use serde::Serialize;
 use settings::{JailConfValue, JailSettings};
-use std::fs;
+use std::fmt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 
 enum Change {
     Created,
@@ -24,28 +31,83 @@ enum Change {
     None,
 }
 
+// State of a rendered config file compared with the one on disk
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileState {
+    Missing,
+    Differs,
+    Matches,
+}
+
+impl fmt::Display for FileState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileState::Missing => write!(f, "missing"),
+            FileState::Differs => write!(f, "differs"),
+            FileState::Matches => write!(f, "ok"),
+        }
+    }
+}
+
+// Read-only snapshot of a jail's state on the host
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct JailStatus {
+    pub name: String,
+    pub exists: bool,
+    pub running: bool,
+    pub enabled: bool,
+    pub last_ready_snap: Option<String>,
+    pub jail_conf: FileState,
+    // None when the jail has no volumes
+    pub fstab: Option<FileState>,
+}
+
+// A snapshot of a jail as listed by rj snapshot list
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SnapshotStatus {
+    pub name: String,
+    // UTC
+    pub creation: String,
+    // bytes only this snapshot holds
+    pub used: u64,
+    // the datasets cloned from it
+    pub clones: Vec<String>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Jail<'a> {
-    jail_conf_defaults: &'a IndexMap<String, JailConfValue>,
+    jail_conf_defaults: IndexMap<String, JailConfValue>,
     jail_conf_path: PathBuf,
     fstab_path: PathBuf,
     mountpoint: PathBuf,
     name: String,
     noop: &'a bool,
     noop_suffix: String,
-    provisioners: Vec<&'a Provisioner>,
-    jail_settings: &'a JailSettings,
+    provisioners: Vec<Provisioner>,
+    rc_conf_lock_path: PathBuf,
+    jail_settings: JailSettings,
     source: &'a Source,
-    volumes: Vec<&'a Volume>,
+    volumes: Vec<Volume>,
     zfs_ds: zfs::DataSet,
     zfs_ds_path: PathBuf,
 }
 
+impl fmt::Display for Jail<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
 impl Jail<'_> {
     pub fn name(&self) -> &String {
         &self.name
     }
 
+    pub fn labels(&self) -> &[String] {
+        &self.jail_settings.labels
+    }
+
     pub fn mountpoint(&self) -> &PathBuf {
         &self.mountpoint
     }
@@ -67,11 +129,12 @@ impl Jail<'_> {
         jails_mountpoint: &Path,
         jails_dataset: &Path,
         source: &'a Source,
-        jail_settings: &'a JailSettings,
-        jail_conf_defaults: &'a IndexMap<String, JailConfValue>,
-        provisioners: Vec<&'a Provisioner>,
+        jail_settings: JailSettings,
+        jail_conf_defaults: IndexMap<String, JailConfValue>,
+        provisioners: Vec<Provisioner>,
         noop: &'a bool,
-        volumes: Vec<&'a Volume>,
+        volumes: Vec<Volume>,
+        lock_dir: &Path,
     ) -> Jail<'a> {
         //
         // Set the noop suffix which is displayed in log messages when noop is set
@@ -83,7 +146,7 @@ impl Jail<'_> {
             mountpoint: jails_mountpoint.join(name),
             source,
             zfs_ds_path: zfs_dataset_path.to_owned(),
-            zfs_ds: zfs::DataSet::new(&zfs_dataset_path),
+            zfs_ds: zfs::DataSet::in_root(&zfs_dataset_path, jails_dataset),
             jail_settings,
             jail_conf_defaults,
             jail_conf_path: PathBuf::from(format!("/etc/jail.{}.conf", name)),
@@ -92,6 +155,7 @@ impl Jail<'_> {
             noop,
             noop_suffix: Self::make_noop_suffix(noop),
             volumes,
+            rc_conf_lock_path: lock_dir.join("rc.conf.lock"),
         }
     }
 
@@ -104,118 +168,423 @@ impl Jail<'_> {
     }
 
     pub fn apply(&self) -> Result<()> {
-        info!("{}: applying changes", self.name());
+        self.run("apply", &mut vec![])
+    }
+
+    // Plan and carry out one of apply, destroy, provision, start, stop or
+    // restart.  Actions are added to done as they complete so callers can
+    // report how far it got on failure.
+    pub fn run(&self, action: &str, done: &mut Vec<Action>) -> Result<()> {
+        let plan = match action {
+            "apply" => {
+                info!("{}: applying changes", self.name());
+                self.plan()?
+            },
+            "destroy" => self.destroy_plan()?,
+            "start" | "stop" | "restart" => self.service_plan(action)?,
+            "provision" => {
+                info!("{}: provisioning{}", &self.name, &self.noop_suffix);
+                self.provision_plan()?
+            },
+            "prune" => self.prune_plan()?,
+            _ => bail!("unknown action {}", action),
+        };
+        self.execute_plan(&plan, done)
+    }
+
+    // Carry out the actions of a plan in order, adding each to done as it
+    // completes.
+    pub fn execute_plan(&self, plan: &Plan, done: &mut Vec<Action>) -> Result<()> {
+        for action in plan.actions.iter() {
+            self.execute(action)?;
+            done.push(action.to_owned());
+        }
+        Ok(())
+    }
+
+    // Work out the actions needed to bring the jail in line with its settings
+    // without changing anything.
+    pub fn plan(&self) -> Result<Plan> {
+        self.plan_from(self.exists()?)
+    }
 
+    // Plan an apply assuming the dataset does or doesn't exist
+    fn plan_from(&self, exists: bool) -> Result<Plan> {
+        let mut plan = Plan::new(&self.name);
+        let mut running = exists && self.is_running()?;
         let mut restart = false;
 
-        if !self.exists()? {
-            self.install()?;
+        if !exists {
+            plan.push(Action::Install {
+                source: self.jail_settings.source.to_owned(),
+                dataset: self.zfs_ds_path.to_owned(),
+            });
         }
 
-        if let Change::Modified = self.configure()? {
+        self.plan_zfs_properties(&mut plan, exists)?;
+
+        if let Change::Modified = self.plan_files(&mut plan)? {
             restart = true;
         }
 
-        // FIXME - what if all volumes are removed?
-        if !self.volumes.is_empty() {
-            self.write_fstab()?;
+        let enabled = self.is_enabled()?;
+        if self.jail_settings.enable && !enabled {
+            plan.push(Action::Enable);
+        } else if !self.jail_settings.enable && enabled {
+            plan.push(Action::Disable);
+        }
+
+        if self.jail_settings.start && !running {
+            plan.push(Action::Start);
+            running = true;
+        } else if !self.jail_settings.start && running {
+            plan.push(Action::Stop);
+            running = false;
+        }
+
+        self.plan_provision(&mut plan, &mut running);
+
+        if running && restart {
+            plan.push(Action::Restart);
+        }
+
+        Ok(plan)
+    }
+
+    // Add an action to set the zfs properties of the dataset that differ from
+    // the settings, all of them when it's about to be installed
+    fn plan_zfs_properties(&self, plan: &mut Plan, exists: bool) -> Result<()> {
+        let wanted = &self.jail_settings.zfs;
+        if wanted.is_empty() {
+            return Ok(());
+        }
+        if !exists {
+            plan.push(Action::SetZfsProperties {
+                dataset: self.zfs_ds_path.to_owned(),
+                properties: wanted.to_owned(),
+                diff: None,
+            });
+            return Ok(());
         }
 
-        if self.jail_settings.enable {
-            if !(self.is_enabled()?) {
-                self.enable()?
+        let names: Vec<&str> = wanted.keys().map(String::as_str).collect();
+        let actual = self.zfs_ds.get_all(&names)?;
+        let mut properties = IndexMap::new();
+        // one property=value line each so drift shows as a diff
+        let mut current = String::new();
+        let mut rendered = String::new();
+        for (property, value) in wanted.iter() {
+            let line = format!("{}={}\n", property, value);
+            match actual.get(property) {
+                Some(a) if zfs::same_value(value, a) => current.push_str(&line),
+                a => {
+                    let a = a.map(String::as_str).unwrap_or("-");
+                    current.push_str(&format!("{}={}\n", property, a));
+                    properties.insert(property.to_owned(), value.to_owned());
+                },
             }
-        } else {
-            if self.is_enabled()? {
-                self.disable()?
+            rendered.push_str(&line);
+        }
+        if !properties.is_empty() {
+            plan.push(Action::SetZfsProperties {
+                dataset: self.zfs_ds_path.to_owned(),
+                properties,
+                diff: Some(plan::diff(&current, &rendered)),
+            });
+        }
+        Ok(())
+    }
+
+    // Add actions to write jail.conf and fstab when they differ from what's
+    // on disk.  Returns how jail.conf changed as that needs a restart.
+    fn plan_files(&self, plan: &mut Plan) -> Result<Change> {
+        let mut change = Change::None;
+        let rendered = self.render_jail_conf()?;
+        match Self::file_state(&self.jail_conf_path, &rendered)? {
+            FileState::Missing => {
+                change = Change::Created;
+                plan.push(Action::WriteJailConf {
+                    path: self.jail_conf_path.to_owned(),
+                    diff: None,
+                });
+            },
+            FileState::Differs => {
+                change = Change::Modified;
+                let current = cmd::read_file(&self.jail_conf_path)?.unwrap_or_default();
+                plan.push(Action::WriteJailConf {
+                    path: self.jail_conf_path.to_owned(),
+                    diff: Some(plan::diff(&current, &rendered)),
+                });
+            },
+            FileState::Matches => (),
+        }
+
+        // FIXME - what if all volumes are removed?
+        if !self.volumes.is_empty() {
+            let rendered = self.render_fstab()?;
+            match Self::file_state(&self.fstab_path, &rendered)? {
+                FileState::Missing => plan.push(Action::WriteFstab {
+                    path: self.fstab_path.to_owned(),
+                    diff: None,
+                }),
+                FileState::Differs => {
+                    let current = cmd::read_file(&self.fstab_path)?.unwrap_or_default();
+                    plan.push(Action::WriteFstab {
+                        path: self.fstab_path.to_owned(),
+                        diff: Some(plan::diff(&current, &rendered)),
+                    });
+                },
+                FileState::Matches => (),
             }
         }
+        Ok(change)
+    }
 
-        if self.jail_settings.start {
-            if !(self.is_running()?) {
-                self.start()?
+    // Add the provisioning actions to a plan.  running tracks whether the jail
+    // will be running at this point of the plan.
+    fn plan_provision(&self, plan: &mut Plan, running: &mut bool) {
+        if !self.provisioners.is_empty() {
+            plan.push(Action::Snapshot {
+                name: "pre-provision".to_owned(),
+            });
+            if !*running {
+                plan.push(Action::Start);
+                *running = true;
             }
-        } else {
-            if self.is_running()? {
-                self.stop()?
+        }
+        for p in self.provisioners.iter() {
+            plan.push(Action::Provision {
+                provisioner: p.get_name().to_owned(),
+            });
+        }
+        plan.push(Action::Snapshot {
+            name: "ready".to_owned(),
+        });
+        if self.jail_settings.retention.is_set() {
+            plan.push(Action::Prune);
+        }
+        if self.jail_settings.stop_after && *running {
+            plan.push(Action::Stop);
+            *running = false;
+        }
+    }
+
+    pub fn provision_plan(&self) -> Result<Plan> {
+        let mut plan = Plan::new(&self.name);
+        let mut running = self.exists()? && self.is_running()?;
+        self.plan_provision(&mut plan, &mut running);
+        Ok(plan)
+    }
+
+    // Plan for starting, stopping or restarting.  Jails that are already in
+    // the requested state get an empty plan.
+    pub fn service_plan(&self, action: &str) -> Result<Plan> {
+        let mut plan = Plan::new(&self.name);
+        if !self.exists()? {
+            if action == "stop" {
+                info!("{}: doesn't exist, skipping", &self.name);
+                return Ok(plan);
             }
+            bail!(Error::Prerequisite(format!(
+                "{}: doesn't exist",
+                &self.name
+            )));
         }
 
-        self.provision()?;
+        let running = self.is_running()?;
+        match action {
+            "start" | "restart" if !running => plan.push(Action::Start),
+            "start" => info!("{}: already running", &self.name),
+            "stop" if running => plan.push(Action::Stop),
+            "stop" => info!("{}: already stopped", &self.name),
+            "restart" => plan.push(Action::Restart),
+            _ => bail!("unknown action {}", action),
+        }
+        Ok(plan)
+    }
 
-        if self.is_running()? && restart {
-            self.stop()?;
-            self.start()?;
+    // Plan destroying the snapshots the retention policy doesn't keep
+    pub fn prune_plan(&self) -> Result<Plan> {
+        let mut plan = Plan::new(&self.name);
+        if !self.exists()? {
+            info!("{}: doesn't exist, skipping", &self.name);
+            return Ok(plan);
+        }
+        let snapshots = self.expired_snaps()?;
+        if !snapshots.is_empty() {
+            plan.push(Action::DestroySnapshots { snapshots });
         }
+        Ok(plan)
+    }
 
-        Ok(())
+    // the snapshots the retention policy doesn't keep, skipping the ones
+    // clones were made from
+    fn expired_snaps(&self) -> Result<Vec<String>> {
+        self.jail_settings.retention.expired(
+            &self.zfs_ds.snapshots()?,
+            &self.zfs_ds.clones()?,
+            Utc::now().naive_utc(),
+        )
     }
 
-    pub fn destroy(&self) -> Result<()> {
+    pub fn destroy_plan(&self) -> Result<Plan> {
+        let mut plan = Plan::new(&self.name);
         if !self.exists()? {
             info!("{}: doesn't exist, skipping", &self.name);
-            return Ok(());
+            return Ok(plan);
         }
 
         if self.is_running()? {
-            self.stop()?;
+            plan.push(Action::Stop);
         }
 
         // disable in rc.conf
         if self.is_enabled()? {
-            self.disable()?;
+            plan.push(Action::Disable);
         }
 
-        // remove jail config file
-        if Path::new(&self.jail_conf_path).is_file() {
-            info!(
-                "{}: removing config file: {}{}",
-                &self.name,
-                &self.jail_conf_path.display(),
-                &self.noop_suffix
-            );
-            if !self.noop {
-                fs::remove_file(&self.jail_conf_path)?;
+        for path in [&self.jail_conf_path, &self.fstab_path].iter() {
+            if cmd::read_file(path)?.is_some() {
+                plan.push(Action::RemoveFile {
+                    path: path.to_path_buf(),
+                });
             }
         }
 
-        // remove fstab
-        if Path::new(&self.fstab_path).is_file() {
-            info!(
-                "{}: removing fstab: {}{}",
-                &self.name,
-                &self.fstab_path.display(),
-                &self.noop_suffix
-            );
-            if !self.noop {
-                fs::remove_file(&self.fstab_path)?;
-            }
+        let snapshots = self.zfs_ds.list_snaps()?;
+        if !snapshots.is_empty() {
+            plan.push(Action::DestroySnapshots { snapshots });
         }
 
-        // remove zfs snapshots
-        let snaps = self.zfs_ds.list_snaps()?;
-        if !(snaps.is_empty()) {
-            info!("{}: destroying snapshots{}", &self.name, &self.noop_suffix);
-            if !self.noop {
-                for snap in snaps {
-                    self.zfs_ds.snap_destroy(&snap)?;
+        plan.push(Action::DestroyDataset {
+            dataset: self.zfs_ds_path.to_owned(),
+        });
+        Ok(plan)
+    }
+
+    // Carry out a single planned action
+    fn execute(&self, action: &Action) -> Result<()> {
+        match action {
+            Action::Install { .. } => self.install(),
+            Action::WriteJailConf { .. } => self.configure(),
+            Action::WriteFstab { .. } => self.write_fstab(),
+            Action::SetZfsProperties {
+                properties, diff, ..
+            } => {
+                match diff {
+                    Some(diff) => info!(
+                        "{}: updating zfs properties{}\n{}",
+                        &self.name, &self.noop_suffix, diff
+                    ),
+                    None => info!(
+                        "{}: setting zfs properties{}",
+                        &self.name, &self.noop_suffix
+                    ),
                 }
-            }
+                if !self.noop {
+                    for (property, value) in properties.iter() {
+                        self.zfs_ds.set(property, value)?;
+                    }
+                }
+                Ok(())
+            },
+            Action::Enable => self.enable(),
+            Action::Disable => self.disable(),
+            Action::Start => self.start(),
+            Action::Stop => self.stop(),
+            Action::Restart => {
+                self.stop()?;
+                self.start()
+            },
+            Action::Provision { provisioner } => {
+                // in noop mode the jail may not have been installed
+                if !self.exists()? {
+                    return Ok(());
+                }
+                match self
+                    .provisioners
+                    .iter()
+                    .find(|p| p.get_name() == provisioner)
+                {
+                    // provisioners implement noop themselves
+                    Some(p) => p.provision(&self).kind(Error::Provisioner),
+                    None => bail!("{}: unknown provisioner: {}", &self.name, provisioner),
+                }
+            },
+            Action::Snapshot { name } => self.snap(name),
+            Action::Prune => {
+                // in noop mode the jail may not have been installed
+                if !self.exists()? {
+                    return Ok(());
+                }
+                let snapshots = self.expired_snaps()?;
+                if snapshots.is_empty() {
+                    return Ok(());
+                }
+                info!(
+                    "{}: pruning snapshots{}: {}",
+                    &self.name,
+                    &self.noop_suffix,
+                    snapshots.join(", ")
+                );
+                if !self.noop {
+                    for snap in snapshots.iter() {
+                        self.zfs_ds.snap_destroy(snap)?;
+                    }
+                }
+                Ok(())
+            },
+            Action::Upgrade { release, .. } => self.source.upgrade(&self, release),
+            Action::Rollback { snapshot } => {
+                info!(
+                    "{}: rolling back to snapshot {}{}",
+                    &self.name, snapshot, &self.noop_suffix
+                );
+                if !self.noop {
+                    self.zfs_ds.rollback(snapshot)?;
+                }
+                Ok(())
+            },
+            Action::RemoveFile { path } => {
+                info!(
+                    "{}: removing {}{}",
+                    &self.name,
+                    path.display(),
+                    &self.noop_suffix
+                );
+                if !self.noop {
+                    cmd::remove_file(path)?;
+                }
+                Ok(())
+            },
+            Action::DestroySnapshots { snapshots } => {
+                info!("{}: destroying snapshots{}", &self.name, &self.noop_suffix);
+                if !self.noop {
+                    for snap in snapshots {
+                        self.zfs_ds.snap_destroy(snap)?;
+                    }
+                }
+                Ok(())
+            },
+            Action::DestroyDataset { .. } => {
+                info!("{}: destroying dataset{}", &self.name, &self.noop_suffix);
+                if !self.noop {
+                    self.zfs_ds.destroy()?;
+                }
+                Ok(())
+            },
         }
+    }
 
-        // destroy zfs dataset
-        info!("{}: destroying dataset{}", &self.name, &self.noop_suffix);
-        if !self.noop {
-            self.zfs_ds.destroy()?;
-        }
-        Ok(())
+    pub fn destroy(&self) -> Result<()> {
+        self.run("destroy", &mut vec![])
     }
 
     fn install(&self) -> Result<()> {
         self.source.install(&self)
     }
 
-    fn configure(&self) -> Result<Change> {
+    fn render_jail_conf(&self) -> Result<String> {
         // add any additional config params
         let mut extra_conf = indexmap! {
             "path".to_owned() => JailConfValue::Path(self.mountpoint.to_owned()),
@@ -234,15 +603,26 @@ impl Jail<'_> {
             &self.jail_settings.conf,
             &extra_conf,
         )?;
-        let rendered = jail_conf_template.render()?;
-        let mut change = Change::None;
+        Ok(jail_conf_template.render()?)
+    }
+
+    fn render_fstab(&self) -> Result<String> {
+        let volumes: Vec<&Volume> = self.volumes.iter().collect();
+        let fstab = Fstab {
+            volumes: &volumes,
+            jail_mountpoint: &self.mountpoint,
+        };
+        Ok(fstab.render()?)
+    }
+
+    fn configure(&self) -> Result<()> {
+        let rendered = self.render_jail_conf()?;
 
         // FIXME - DRY this up
-        if self.jail_conf_path.is_file() {
-            let current = fs::read_to_string(&self.jail_conf_path)?;
+        if let Some(current) = cmd::read_file(&self.jail_conf_path)? {
             if current != rendered {
-                change = Change::Modified;
-                let diff = Changeset::new(&current, &rendered, "");
+                // line based so that secret values are redacted whole
+                let diff = plan::diff(&current, &rendered);
                 info!(
                     "{}: updating {}{}\n{}",
                     &self.name,
@@ -252,7 +632,6 @@ impl Jail<'_> {
                 );
             }
         } else {
-            change = Change::Created;
             info!(
                 "{}: creating {}{}",
                 &self.name,
@@ -262,24 +641,20 @@ impl Jail<'_> {
         }
 
         if !self.noop {
-            fs::write(&self.jail_conf_path, &rendered)?;
+            cmd::write_file(&self.jail_conf_path, rendered.as_bytes())?;
         }
 
-        Ok(change)
+        Ok(())
     }
 
     fn write_fstab(&self) -> Result<()> {
-        let fstab = Fstab {
-            volumes: &self.volumes,
-            jail_mountpoint: &self.mountpoint,
-        };
-        let rendered = fstab.render()?;
+        let rendered = self.render_fstab()?;
 
         // FIXME - DRY this up
-        if self.fstab_path.is_file() {
-            let current = fs::read_to_string(&self.fstab_path)?;
+        if let Some(current) = cmd::read_file(&self.fstab_path)? {
             if current != rendered {
-                let diff = Changeset::new(&current, &rendered, "");
+                // line based so that secret values are redacted whole
+                let diff = plan::diff(&current, &rendered);
                 info!(
                     "{}: updating {}{}\n{}",
                     &self.name,
@@ -298,24 +673,272 @@ impl Jail<'_> {
         }
 
         if !self.noop {
-            fs::write(&self.fstab_path, &rendered)?;
+            cmd::write_file(&self.fstab_path, rendered.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    // compare a rendered file with the one on disk
+    fn file_state(path: &Path, rendered: &str) -> Result<FileState> {
+        match cmd::read_file(path)? {
+            None => Ok(FileState::Missing),
+            Some(current) if current == rendered => Ok(FileState::Matches),
+            Some(_) => Ok(FileState::Differs),
+        }
+    }
+
+    // gather the jail state without changing anything
+    pub fn status(&self) -> Result<JailStatus> {
+        let exists = self.exists()?;
+        let last_ready_snap = if exists {
+            self.zfs_ds.last_snap("ready")?
+        } else {
+            None
+        };
+
+        let fstab = if self.volumes.is_empty() {
+            None
+        } else {
+            Some(Self::file_state(&self.fstab_path, &self.render_fstab()?)?)
+        };
+
+        Ok(JailStatus {
+            name: self.name.to_owned(),
+            exists,
+            running: self.is_running()?,
+            enabled: self.is_enabled()?,
+            last_ready_snap,
+            jail_conf: Self::file_state(&self.jail_conf_path, &self.render_jail_conf()?)?,
+            fstab,
+        })
+    }
+
+    pub fn upgrade(&self, release: Option<&str>) -> Result<()> {
+        self.execute_plan(&self.upgrade_plan(release)?, &mut vec![])
+    }
+
+    // Plan an upgrade.  FreeBSD sourced jails are snapshotted and upgraded in
+    // place to release, or the source release if not set.  Clone sourced jails
+    // are destroyed and cloned again from the latest 'ready' snapshot of their
+    // source.
+    pub fn upgrade_plan(&self, release: Option<&str>) -> Result<Plan> {
+        let mut plan = Plan::new(&self.name);
+        if !self.exists()? {
+            bail!(Error::Prerequisite(format!(
+                "{}: doesn't exist",
+                &self.name
+            )));
+        }
+        let running = self.is_running()?;
+
+        match self.source {
+            Source::FreeBSD(src) => {
+                let release = release.unwrap_or(&src.release);
+                if self.release()?.as_deref() == Some(release) {
+                    info!("{}: already at release {}", &self.name, release);
+                    return Ok(plan);
+                }
+
+                plan.push(Action::Snapshot {
+                    name: "pre-upgrade".to_owned(),
+                });
+                if running {
+                    plan.push(Action::Stop);
+                }
+                plan.push(Action::Upgrade {
+                    source: self.jail_settings.source.to_owned(),
+                    release: release.to_owned(),
+                });
+                if running {
+                    plan.push(Action::Start);
+                }
+            },
+            Source::ZfsClone(src) => {
+                if release.is_some() {
+                    bail!(Error::Config(format!(
+                        "{}: a release can only be set for FreeBSD sourced jails",
+                        &self.name
+                    )));
+                }
+
+                // the dataset can't be destroyed while clones depend on it
+                let clones: Vec<String> =
+                    self.zfs_ds.clones()?.values().flatten().cloned().collect();
+                if !clones.is_empty() {
+                    bail!(Error::Prerequisite(format!(
+                        "{}: can't be cloned again, datasets depend on it: {}",
+                        &self.name,
+                        clones.join(", ")
+                    )));
+                }
+
+                let src_ds = zfs::DataSet::in_root(&src.path, self.zfs_ds.root());
+                if let Some(snapshot) = src_ds.last_snap("ready")? {
+                    let latest = format!("{}@{}", src.path.display(), snapshot);
+                    let origin = self.zfs_ds.properties()?.and_then(|p| p.origin);
+                    if origin.as_deref() == Some(latest.as_str()) {
+                        info!("{}: already cloned from {}", &self.name, latest);
+                        return Ok(plan);
+                    }
+                }
+
+                if running {
+                    plan.push(Action::Stop);
+                }
+                let snapshots = self.zfs_ds.list_snaps()?;
+                if !snapshots.is_empty() {
+                    plan.push(Action::DestroySnapshots { snapshots });
+                }
+                plan.push(Action::DestroyDataset {
+                    dataset: self.zfs_ds_path.to_owned(),
+                });
+                plan.actions.extend(self.plan_from(false)?.actions);
+            },
         }
+        Ok(plan)
+    }
+
+    // The release recorded on the dataset when it was installed or upgraded
+    // from a FreeBSD source
+    pub fn release(&self) -> Result<Option<String>> {
+        Ok(self
+            .zfs_ds
+            .properties()?
+            .and_then(|p| p.user.get(RELEASE_PROPERTY).cloned()))
+    }
 
+    pub fn rollback(&self, to: &str) -> Result<()> {
+        self.execute_plan(&self.rollback_plan(to)?, &mut vec![])
+    }
+
+    // fail with a prerequisite error if the jail's dataset doesn't exist
+    fn require_exists(&self) -> Result<()> {
+        if !self.exists()? {
+            bail!(Error::Prerequisite(format!(
+                "{}: doesn't exist",
+                &self.name
+            )));
+        }
         Ok(())
     }
 
-    pub fn upgrade(&self) -> Result<()> {
-        todo!()
+    // the snapshot with this name, or else the latest one matching it
+    fn find_snap(&self, pattern: &str) -> Result<String> {
+        if self.zfs_ds.snap_exists(pattern)? {
+            return Ok(pattern.to_owned());
+        }
+        match self.zfs_ds.last_snap(pattern)? {
+            Some(snapshot) => Ok(snapshot),
+            None => bail!(Error::Prerequisite(format!(
+                "{}: no snapshot matching '{}'",
+                &self.name, pattern
+            ))),
+        }
+    }
+
+    // Plan a rollback to a snapshot.  to is either the exact name of a
+    // snapshot or a pattern such as 'ready' or 'pre-provision', in which case
+    // the latest matching snapshot is used.
+    pub fn rollback_plan(&self, to: &str) -> Result<Plan> {
+        let mut plan = Plan::new(&self.name);
+        self.require_exists()?;
+        let snapshot = self.find_snap(to)?;
+
+        let running = self.is_running()?;
+        if running {
+            plan.push(Action::Stop);
+        }
+        plan.push(Action::Rollback { snapshot });
+        self.plan_files(&mut plan)?;
+        if running {
+            plan.push(Action::Start);
+        }
+        Ok(plan)
+    }
+
+    // the jail's snapshots, oldest first
+    pub fn snapshots(&self) -> Result<Vec<SnapshotStatus>> {
+        self.require_exists()?;
+        let clones = self.zfs_ds.clones()?;
+        Ok(self
+            .zfs_ds
+            .snapshots()?
+            .iter()
+            .filter_map(|p| {
+                let name = p.snapshot()?;
+                Some(SnapshotStatus {
+                    name: name.to_owned(),
+                    creation: p.creation.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+                    used: p.used,
+                    clones: clones.get(name).cloned().unwrap_or_default(),
+                })
+            })
+            .collect())
+    }
+
+    // Plan taking a snapshot named after the time and name, like the ones
+    // taken when provisioning
+    pub fn snapshot_create_plan(&self, name: &str) -> Result<Plan> {
+        let mut plan = Plan::new(&self.name);
+        self.require_exists()?;
+        plan.push(Action::Snapshot {
+            name: name.to_owned(),
+        });
+        Ok(plan)
+    }
+
+    // Plan destroying a snapshot, which has to be named in full and can't
+    // have clones
+    pub fn snapshot_destroy_plan(&self, snapshot: &str) -> Result<Plan> {
+        let mut plan = Plan::new(&self.name);
+        self.require_exists()?;
+        if !self.zfs_ds.snap_exists(snapshot)? {
+            bail!(Error::Prerequisite(format!(
+                "{}: no snapshot {}",
+                &self.name, snapshot
+            )));
+        }
+        if let Some(clones) = self.zfs_ds.clones()?.get(snapshot) {
+            bail!(Error::Prerequisite(format!(
+                "{}: snapshot {} has clones: {}",
+                &self.name,
+                snapshot,
+                clones.join(", ")
+            )));
+        }
+        plan.push(Action::DestroySnapshots {
+            snapshots: vec![snapshot.to_owned()],
+        });
+        Ok(plan)
     }
 
-    pub fn rollback(&self) -> Result<()> {
-        todo!()
+    // The files changed in the jail since a snapshot, up to another snapshot
+    // or now, with paths from the jail's root.  Snapshots are found the same
+    // way as for rollback.
+    pub fn snapshot_diff(&self, from: &str, to: Option<&str>) -> Result<Vec<FileChange>> {
+        self.require_exists()?;
+        let from = self.find_snap(from)?;
+        let to = match to {
+            Some(to) => Some(self.find_snap(to)?),
+            None => None,
+        };
+        let in_jail = |path: &Path| match path.strip_prefix(&self.mountpoint) {
+            Ok(path) => Path::new("/").join(path),
+            Err(_) => path.to_owned(),
+        };
+        let mut changes = self.zfs_ds.diff(&from, to.as_deref())?;
+        for change in changes.iter_mut() {
+            change.path = in_jail(&change.path);
+            change.new_path = change.new_path.as_deref().map(in_jail);
+        }
+        Ok(changes)
     }
 
     pub fn start(&self) -> Result<()> {
         info!("{}: starting{}", &self.name, &self.noop_suffix);
         if !self.noop {
-            cmd!("service", "jail", "start", &self.name)?;
+            cmd!("service", "jail", "start", &self.name).kind(Error::Jail)?;
         }
         Ok(())
     }
@@ -323,14 +946,13 @@ impl Jail<'_> {
     pub fn stop(&self) -> Result<()> {
         info!("{}: stopping{}", &self.name, &self.noop_suffix);
         if !self.noop {
-            cmd!("service", "jail", "stop", &self.name)?;
+            cmd!("service", "jail", "stop", &self.name).kind(Error::Jail)?;
         }
         Ok(())
     }
 
     pub fn is_running(&self) -> Result<bool> {
-        let output = Command::new("jls").arg("-j").arg(&self.name).output()?;
-        Ok(output.status.success())
+        Cmd::new("jls").arg("-j").arg(&self.name).succeeds()
     }
 
     pub fn is_enabled(&self) -> Result<bool> {
@@ -342,6 +964,8 @@ impl Jail<'_> {
         info!("{}: enabling in rc.conf{}", &self.name, &self.noop_suffix);
         let arg = format!("jail_list+={}", &self.name);
         if !self.noop {
+            // jail_list is shared by all jails, serialise changes to it
+            let _lock = Lock::acquire(&self.rc_conf_lock_path, Wait::Forever)?;
             cmd!("sysrc", arg)?;
         }
         Ok(())
@@ -351,38 +975,15 @@ impl Jail<'_> {
         info!("{}: disabling in rc.conf{}", &self.name, &self.noop_suffix);
         let arg = format!("jail_list-={}", &self.name);
         if !self.noop {
+            // jail_list is shared by all jails, serialise changes to it
+            let _lock = Lock::acquire(&self.rc_conf_lock_path, Wait::Forever)?;
             cmd!("sysrc", arg)?;
         }
         Ok(())
     }
 
     pub fn provision(&self) -> Result<()> {
-        if !self.provisioners.is_empty() {
-            self.snap("pre-provision")?;
-
-            if !self.is_running()? {
-                self.start()?;
-            }
-        }
-
-        info!("{}: provisioning{}", &self.name, &self.noop_suffix);
-        // TODO - in case of noop before the jail exists, show a list of provisioners that would be run.
-        if self.exists()? {
-            for p in self.provisioners.iter() {
-                // provisioners implement noop themselves
-                p.provision(&self)?;
-            }
-        }
-
-        self.snap("ready")?;
-
-        if self.jail_settings.stop_after {
-            if self.is_running()? {
-                self.stop()?;
-            }
-        }
-
-        Ok(())
+        self.run("provision", &mut vec![])
     }
 
     fn snap(&self, snap_name: &str) -> Result<()> {
@@ -430,7 +1031,7 @@ mod tests {
             // TermLogger::init(LevelFilter::Debug, Config::default(), TerminalMode::Mixed).unwrap();
 
             // Initialise
-            crate::init(&S).unwrap();
+            crate::init(&S, &mut crate::report::Report::default()).unwrap();
 
             // clean up test jails that may be left over from a failed run
             if jails["test1"].exists().unwrap() {
@@ -641,4 +1242,76 @@ mod tests {
         assert_eq!(Jail::make_noop_suffix(&true), String::from(" (noop)"));
         assert_eq!(Jail::make_noop_suffix(&false), String::new());
     }
+
+    #[test]
+    fn destroy_commands() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let mut s = Settings::load("testdata/config.toml", false)?;
+        s.lock_dir = dir.path().to_owned();
+        let jails = s.to_jails()?;
+
+        let fake = crate::executor::fake();
+        fake.on("sysrc -n jail_list", 0, "test1 test2\n")
+            .on(
+                "zfs list",
+                0,
+                "zroot/jails/test1\t800\t1100\t1577923100\t-\t/jails/test1\t-\n\
+                 zroot/jails/test1@2020-01-02T00:00:00.000_ready\t0\t1100\t1577923200\t-\t-\t-\n",
+            )
+            .file("/etc/jail.test1.conf", "test1 {}\n");
+
+        jails["test1"].destroy()?;
+        assert_eq!(
+            fake.calls(),
+            vec![
+                "zfs list -H -p -o name,used,referenced,creation,origin,mountpoint,rj:release -r -t filesystem,snapshot zroot/jails",
+                "jls -j test1",
+                "sysrc -n jail_list",
+                "service jail stop test1",
+                "sysrc jail_list-=test1",
+                "remove_file /etc/jail.test1.conf",
+                "zfs destroy zroot/jails/test1@2020-01-02T00:00:00.000_ready",
+                "zfs destroy zroot/jails/test1",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn zfs_properties() -> Result<()> {
+        let mut s = Settings::load("testdata/config.toml", false)?;
+        s.jail["test1"].zfs = indexmap! {
+            "quota".to_string() => "20G".to_string(),
+            "compression".to_string() => "lz4".to_string(),
+        };
+        let jails = s.to_jails()?;
+
+        let get = "zfs get -H -p -o property,value quota,compression zroot/jails/test1";
+        let fake = crate::executor::fake();
+        fake.on(get, 0, "quota\t0\ncompression\tlz4\n");
+
+        let mut plan = Plan::new("test1");
+        jails["test1"].plan_zfs_properties(&mut plan, true)?;
+        let action = Action::SetZfsProperties {
+            dataset: PathBuf::from("zroot/jails/test1"),
+            properties: indexmap! { "quota".to_string() => "20G".to_string() },
+            diff: Some("-quota=0\n+quota=20G\n compression=lz4".to_string()),
+        };
+        assert_eq!(plan.actions, vec![action.to_owned()]);
+
+        jails["test1"].execute(&action)?;
+        assert_eq!(
+            fake.calls(),
+            vec![get, "zfs set quota=20G zroot/jails/test1"]
+        );
+
+        // everything is set when the jail is installed
+        let mut plan = Plan::new("test1");
+        jails["test1"].plan_zfs_properties(&mut plan, false)?;
+        assert_eq!(
+            plan.to_string(),
+            "test1: set zfs properties of zroot/jails/test1: quota=20G, compression=lz4"
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,179 @@
+use anyhow::{bail, Result};
+use log::{debug, info};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long to block when a lock is held by someone else
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wait {
+    No,
+    For(Duration),
+    Forever,
+}
+
+// An advisory lock on a file, held until dropped.  The holder's pid and
+// command line are written to the file so that others can tell who holds it.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+    path: PathBuf,
+}
+
+impl Lock {
+    pub fn acquire(path: &Path, wait: Wait) -> Result<Lock> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // keep the holder's details until the lock is ours
+            .truncate(false)
+            .open(path)?;
+
+        let start = Instant::now();
+        let mut waiting = false;
+
+        while !Self::try_lock(&file)? {
+            let holder = Self::holder(&mut file)?;
+            match wait {
+                Wait::No => bail!(
+                    "{} is locked by {}, use --wait to wait for it",
+                    path.display(),
+                    holder
+                ),
+                Wait::For(timeout) if start.elapsed() >= timeout => bail!(
+                    "timed out after {}s waiting for {}, locked by {}",
+                    timeout.as_secs(),
+                    path.display(),
+                    holder
+                ),
+                _ => {
+                    if !waiting {
+                        info!("waiting for {}, locked by {}", path.display(), holder);
+                        waiting = true;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                },
+            }
+        }
+
+        let args: Vec<String> = env::args().collect();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{} {}", process::id(), args.join(" "))?;
+        debug!("locked {}", path.display());
+
+        Ok(Lock {
+            file,
+            path: path.to_owned(),
+        })
+    }
+
+    // true if the lock was taken, false if someone else holds it
+    fn try_lock(file: &File) -> Result<bool> {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            Ok(false)
+        } else {
+            Err(err.into())
+        }
+    }
+
+    // describe the process holding the lock from the file content
+    fn holder(file: &mut File) -> Result<String> {
+        let mut content = String::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut content)?;
+        let mut parts = content.trim().splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(pid), Some(cmdline)) if !pid.is_empty() => {
+                Ok(format!("pid {} ({})", pid, cmdline))
+            },
+            _ => Ok("another process".to_string()),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // the lock itself goes when the file is closed
+        let _ = self.file.set_len(0);
+        debug!("unlocked {}", self.path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn exclusive() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("locks/rj.lock");
+
+        let lock = Lock::acquire(&path, Wait::No)?;
+        let content = fs::read_to_string(&path)?;
+        assert!(content.starts_with(&format!("{} ", process::id())));
+
+        // flock locks are per open file so a second one conflicts
+        let err = Lock::acquire(&path, Wait::No).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} is locked by pid {} ({}), use --wait to wait for it",
+                path.display(),
+                process::id(),
+                env::args().collect::<Vec<String>>().join(" ")
+            )
+        );
+
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path)?, "");
+        Lock::acquire(&path, Wait::No)?;
+        Ok(())
+    }
+
+    #[test]
+    fn timeout() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("rj.lock");
+        let _lock = Lock::acquire(&path, Wait::No)?;
+
+        let start = Instant::now();
+        let err = Lock::acquire(&path, Wait::For(Duration::from_secs(1))).unwrap_err();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(err.to_string().starts_with(&format!(
+            "timed out after 1s waiting for {}",
+            path.display()
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn wait() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("rj.lock");
+        let lock = Lock::acquire(&path, Wait::No)?;
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(lock);
+        });
+        Lock::acquire(&path, Wait::Forever)?;
+        handle.join().unwrap();
+        Ok(())
+    }
+}
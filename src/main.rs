@@ -1,17 +1,30 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use clap::ArgMatches;
+use indicatif::HumanBytes;
 use log::{debug, error, info};
 use simplelog::{Config, LevelFilter, TermLogger, TerminalMode};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use text_io::read;
 
+mod audit;
 mod cli;
 mod cmd;
 mod errors;
+mod executor;
+mod interpolate;
 mod jail;
+mod lock;
+mod parallel;
 mod pkg;
+mod plan;
 mod provisioner;
+mod report;
+mod retention;
+mod secret;
+mod selector;
 mod settings;
 mod source;
 mod template;
@@ -19,78 +32,471 @@ mod util;
 mod volumes;
 mod zfs;
 
+use audit::{AuditLog, Audited};
+use errors::Error;
+use executor::{Executor, Ssh, System};
 use jail::Jail;
+use lock::{Lock, Wait};
+use plan::Action;
 use provisioner::Provisioner;
+use report::{InitAction, JailReport, Report};
+use selector::Selector;
 use settings::Settings;
 use source::Source;
 use volumes::Volume;
 
-fn jail_action(action: &str, jail: &Jail) -> Result<()> {
+fn jail_action(
+    action: &str,
+    jail: &Jail,
+    sub_matches: &ArgMatches,
+    done: &mut Vec<Action>,
+) -> Result<()> {
     debug!("action {}", action);
     match action {
-        "apply" => jail.apply(),
-        "destroy" => jail.destroy(),
-        "provision" => jail.provision(),
-        _ => panic!("unknown action {}", action),
+        "rollback" => {
+            let plan = jail.rollback_plan(sub_matches.value_of("to").unwrap())?;
+            jail.execute_plan(&plan, done)
+        },
+        "upgrade" => {
+            let plan = jail.upgrade_plan(sub_matches.value_of("release"))?;
+            // clone sourced jails are destroyed and cloned again
+            let destroys = plan
+                .actions
+                .iter()
+                .any(|a| matches!(a, Action::DestroyDataset { .. }));
+            if destroys && !sub_matches.is_present("auto-approve") {
+                confirm(&format!(
+                    "{}: upgrading will destroy the jail dataset and clone it again",
+                    jail.name()
+                ))?;
+            }
+            jail.execute_plan(&plan, done)
+        },
+        _ => jail.run(action, done),
     }
 }
 
 // process the subcommand
-fn subcommand(sub_name: &str, sub_matches: &ArgMatches, settings: Settings) -> Result<()> {
+fn subcommand(
+    sub_name: &str,
+    sub_matches: &ArgMatches,
+    settings: Settings,
+    report: &mut Report,
+    json: bool,
+    wait: Wait,
+) -> Result<()> {
+    // read-only commands and dry runs don't need to lock anything
+    let read_only = read_only_command(sub_name, sub_matches) || settings.noop;
+
+    // the snapshot subcommands take the jail after their own name
+    let (snapshot_action, sub_matches) = match sub_matches.subcommand() {
+        (action, Some(matches)) if sub_name == "snapshot" => (action, matches),
+        _ => ("", sub_matches),
+    };
+    let mut locks = Vec::new();
+    if !read_only && (sub_name == "init" || !settings.lock_per_jail) {
+        locks.push(Lock::acquire(&settings.lock_dir.join("rj.lock"), wait)?);
+    }
+
     if sub_name == "init" {
-        init(&settings)?;
-        return Ok(());
-    } else {
+        return init(&settings, report);
+    } else if sub_name != "status" && sub_name != "plan" {
+        // read-only commands report what's missing themselves
         check_init(&settings)?
     }
 
     // Workout which jails to operate on
 
     let jails = settings.to_jails()?;
-    let mut selected_jails = Vec::new();
 
-    if sub_matches.is_present("all") {
-        if sub_name == "destroy" {
-            // order jails in reverse when destroying all
-            for (_, jail) in jails.iter().rev() {
-                selected_jails.push(jail);
-            }
-        } else {
-            for (_, jail) in jails.iter() {
-                selected_jails.push(jail);
+    let names: Vec<&str> = match sub_matches.values_of("jail_name") {
+        Some(names) if !sub_matches.is_present("all") => names.collect(),
+        _ => Vec::new(),
+    };
+    let selectors = match sub_matches.values_of("selector") {
+        Some(selectors) => selectors.map(Selector::parse).collect::<Result<_>>()?,
+        None => Vec::new(),
+    };
+
+    let mut selected_jails = selector::select(&jails, &names, &selectors)?;
+
+    if sub_name == "destroy" || sub_name == "stop" {
+        // jails are in dependency order, dependents go first
+        selected_jails.reverse();
+    }
+
+    // replicas left over from lowering a count, apply destroys them once
+    // confirmed
+    let surplus = if sub_name == "apply" || sub_name == "plan" {
+        let mut definitions: Vec<&String> = Vec::new();
+        for jail in selected_jails.iter() {
+            if let Some(replica) = &settings.jail[jail.name()].replica {
+                if !definitions.contains(&&replica.of) {
+                    definitions.push(&replica.of);
+                }
             }
         }
+        if names.is_empty() && selectors.is_empty() {
+            // all jails
+            definitions = settings.replicated.keys().collect();
+        }
+        settings.surplus(&definitions)?
     } else {
-        for jail_name in sub_matches.values_of("jail_name").unwrap() {
-            if !jails.contains_key(jail_name) {
-                bail!("jail '{}' is not defined", jail_name);
-            }
-            selected_jails.push(&jails[jail_name]);
+        Vec::new()
+    };
+
+    if !read_only && settings.lock_per_jail {
+        // lock in name order so that runs waiting on each other can't deadlock
+        let mut names: Vec<&String> = selected_jails
+            .iter()
+            .copied()
+            .chain(surplus.iter())
+            .map(|j| j.name())
+            .collect();
+        names.sort();
+        for name in names {
+            let path = settings.lock_dir.join(format!("jail.{}.lock", name));
+            locks.push(Lock::acquire(&path, wait)?);
         }
     }
 
+    if sub_name == "status" {
+        return status(&selected_jails, report, json);
+    }
+
+    if sub_name == "plan" {
+        return plan(&selected_jails, &surplus, report, json);
+    }
+
+    if sub_name == "snapshot" {
+        return snapshot(snapshot_action, sub_matches, &selected_jails, report, json);
+    }
+
     // Confirm before destroying
 
     if sub_name == "destroy" && !sub_matches.is_present("auto-approve") {
-        info!(
+        confirm(&format!(
             "You are about to destroy jails: {}",
             selected_jails
                 .iter()
                 .map(|j| j.name().to_owned())
                 .collect::<Vec<String>>()
                 .join(", ")
+        ))?;
+    }
+
+    if sub_name == "apply"
+        && !surplus.is_empty()
+        && !settings.noop
+        && !sub_matches.is_present("auto-approve")
+    {
+        confirm(&format!(
+            "You are about to destroy surplus replicas: {}",
+            surplus
+                .iter()
+                .map(|j| j.name().to_owned())
+                .collect::<Vec<String>>()
+                .join(", ")
+        ))?;
+    }
+
+    let jobs: usize = sub_matches.value_of("jobs").unwrap_or("1").parse()?;
+    if sub_name == "apply" && jobs > 1 {
+        apply_parallel(&settings, &selected_jails, jobs, report)?;
+    } else {
+        // run actions on selected jails
+        for jail in selected_jails.iter() {
+            let mut jail_report = JailReport::new(jail.name());
+            audit::set_jail(Some(jail.name()));
+            let result = jail_action(sub_name, jail, sub_matches, &mut jail_report.actions);
+            if let Err(err) = &result {
+                jail_report.error = Some(err.to_string());
+            }
+            report.jails.push(jail_report);
+            result?
+        }
+    }
+
+    // dependents go first
+    for jail in surplus.iter().rev() {
+        info!(
+            "{}: surplus replica, destroying{}",
+            jail.name(),
+            jail.noop_suffix()
         );
-        info!("Are you sure? [y/n]");
-        let answer: String = read!("{}\n");
-        if answer != "y" {
-            bail!("aborting");
+        let mut jail_report = JailReport::new(jail.name());
+        audit::set_jail(Some(jail.name()));
+        let result = jail.run("destroy", &mut jail_report.actions);
+        if let Err(err) = &result {
+            jail_report.error = Some(err.to_string());
         }
+        report.jails.push(jail_report);
+        result?
     }
 
-    // run actions on selected jails
+    Ok(())
+}
 
-    for jail in selected_jails.iter() {
-        jail_action(&sub_name, &jail)?
+// Apply jails using up to `jobs` threads.  A jail is applied once the
+// selected jails it depends on have been, failures are reported at the end.
+fn apply_parallel(
+    settings: &Settings,
+    jails: &[&Jail],
+    jobs: usize,
+    report: &mut Report,
+) -> Result<()> {
+    let mut deps = Vec::new();
+    for jail in jails.iter() {
+        deps.push(
+            settings
+                .dependencies(jail.name())?
+                .iter()
+                .filter_map(|d| jails.iter().position(|j| j.name() == *d))
+                .collect(),
+        );
+    }
+
+    let jail_reports = Mutex::new(
+        jails
+            .iter()
+            .map(|j| JailReport::new(j.name()))
+            .collect::<Vec<JailReport>>(),
+    );
+
+    let results = parallel::run(jails, &deps, jobs, |jail| {
+        cmd::set_stream_prefix(&format!("{}: ", jail.name()));
+        audit::set_jail(Some(jail.name()));
+        let mut done = Vec::new();
+        let result = jail.run("apply", &mut done);
+        let i = jails.iter().position(|j| j.name() == jail.name()).unwrap();
+        jail_reports.lock().unwrap()[i].actions = done;
+        result
+    });
+
+    let mut failed = Vec::new();
+    // the kind of the first failure with one, for the exit code
+    let mut kind = None;
+    for (mut jail_report, result) in jail_reports.into_inner().unwrap().into_iter().zip(results) {
+        if let Err(err) = result {
+            if kind.is_none() {
+                kind = Error::of(&err).cloned();
+            }
+            let msg = err.to_string();
+            if msg.starts_with(&format!("{}:", jail_report.name)) {
+                error!("{}", msg);
+            } else {
+                error!("{}: {}", jail_report.name, msg);
+            }
+            failed.push(jail_report.name.clone());
+            jail_report.error = Some(msg);
+        }
+        report.jails.push(jail_report);
+    }
+
+    if !failed.is_empty() {
+        let msg = format!(
+            "{} of {} jails failed: {}",
+            failed.len(),
+            jails.len(),
+            failed.join(", ")
+        );
+        match kind {
+            Some(kind) => bail!(kind.with_message(msg)),
+            None => bail!(msg),
+        }
+    }
+
+    Ok(())
+}
+
+// Commands that only look at the host and print what they find
+fn read_only_command(sub_name: &str, sub_matches: &ArgMatches) -> bool {
+    match sub_name {
+        "status" | "plan" => true,
+        "snapshot" => matches!(sub_matches.subcommand_name(), Some("list") | Some("diff")),
+        _ => false,
+    }
+}
+
+// ask for confirmation before doing something destructive
+fn confirm(msg: &str) -> Result<()> {
+    info!("{}", msg);
+    info!("Are you sure? [y/n]");
+    let answer: String = read!("{}\n");
+    if answer != "y" {
+        bail!(Error::Aborted("aborting".to_string()));
+    }
+    Ok(())
+}
+
+// print a table with the state of each selected jail
+fn status(jails: &[&Jail], report: &mut Report, json: bool) -> Result<()> {
+    let mut rows = vec![[
+        "NAME", "EXISTS", "RUNNING", "ENABLED", "READY SNAPSHOT", "JAIL.CONF", "FSTAB",
+    ]
+    .iter()
+    .map(|h| h.to_string())
+    .collect::<Vec<String>>()];
+
+    let yes_no = |b: bool| if b { "yes" } else { "no" }.to_string();
+
+    for jail in jails.iter() {
+        let s = jail.status()?;
+        let mut jail_report = JailReport::new(jail.name());
+        jail_report.status = Some(s.clone());
+        report.jails.push(jail_report);
+
+        rows.push(vec![
+            s.name,
+            yes_no(s.exists),
+            yes_no(s.running),
+            yes_no(s.enabled),
+            s.last_ready_snap.unwrap_or_else(|| "-".to_string()),
+            s.jail_conf.to_string(),
+            s.fstab.map_or("-".to_string(), |f| f.to_string()),
+        ]);
+    }
+
+    if !json {
+        print_table(&rows);
+    }
+
+    Ok(())
+}
+
+// print rows with each column padded to its widest value
+fn print_table(rows: &[Vec<String>]) {
+    let mut widths = vec![0; rows[0].len()];
+    for row in rows.iter() {
+        for (i, col) in row.iter().enumerate() {
+            widths[i] = widths[i].max(col.len());
+        }
+    }
+    for row in rows.iter() {
+        let line = row
+            .iter()
+            .enumerate()
+            .map(|(i, col)| format!("{:width$}", col, width = widths[i]))
+            .collect::<Vec<String>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+// create, list, destroy or diff the snapshots of the selected jails
+fn snapshot(
+    action: &str,
+    sub_matches: &ArgMatches,
+    jails: &[&Jail],
+    report: &mut Report,
+    json: bool,
+) -> Result<()> {
+    if action == "diff" && jails.len() > 1 {
+        bail!(Error::Config(
+            "snapshot diff compares the snapshots of a single jail".to_string()
+        ));
+    }
+
+    // one table for all the jails listed
+    let mut rows = vec![["JAIL", "SNAPSHOT", "CREATION", "USED", "CLONES"]
+        .iter()
+        .map(|h| h.to_string())
+        .collect::<Vec<String>>()];
+
+    for jail in jails.iter() {
+        let mut jail_report = JailReport::new(jail.name());
+        audit::set_jail(Some(jail.name()));
+        let result = match action {
+            "create" => jail
+                .snapshot_create_plan(sub_matches.value_of("name").unwrap())
+                .and_then(|plan| jail.execute_plan(&plan, &mut jail_report.actions)),
+            "destroy" => {
+                let snapshot = sub_matches.value_of("snapshot").unwrap();
+                jail.snapshot_destroy_plan(snapshot).and_then(|plan| {
+                    if !sub_matches.is_present("auto-approve") {
+                        confirm(&format!(
+                            "{}: you are about to destroy snapshot {}",
+                            jail.name(),
+                            snapshot
+                        ))?;
+                    }
+                    jail.execute_plan(&plan, &mut jail_report.actions)
+                })
+            },
+            "list" => jail.snapshots().map(|snapshots| {
+                for s in snapshots.iter() {
+                    rows.push(vec![
+                        jail.name().to_owned(),
+                        s.name.to_owned(),
+                        s.creation.to_owned(),
+                        HumanBytes(s.used).to_string(),
+                        if s.clones.is_empty() {
+                            "-".to_string()
+                        } else {
+                            s.clones.join(",")
+                        },
+                    ]);
+                }
+                jail_report.snapshots = Some(snapshots);
+            }),
+            "diff" => jail
+                .snapshot_diff(
+                    sub_matches.value_of("snapshot").unwrap(),
+                    sub_matches.value_of("to"),
+                )
+                .map(|changes| {
+                    if !json {
+                        for c in changes.iter() {
+                            match &c.new_path {
+                                Some(new_path) => println!(
+                                    "{}\t{} -> {}",
+                                    c.change,
+                                    c.path.display(),
+                                    new_path.display()
+                                ),
+                                None => println!("{}\t{}", c.change, c.path.display()),
+                            }
+                        }
+                    }
+                    jail_report.changes = Some(changes);
+                }),
+            _ => Err(anyhow!("unknown snapshot action {}", action)),
+        };
+        if let Err(err) = &result {
+            jail_report.error = Some(err.to_string());
+        }
+        report.jails.push(jail_report);
+        result?
+    }
+
+    if action == "list" && !json {
+        print_table(&rows);
+    }
+    Ok(())
+}
+
+// print the actions apply would take for each selected jail and the surplus
+// replicas it would destroy
+fn plan(jails: &[&Jail], surplus: &[Jail], report: &mut Report, json: bool) -> Result<()> {
+    for jail in jails.iter() {
+        let plan = jail.plan()?;
+        if !json {
+            println!("{}", secret::redact(&plan.to_string()));
+        }
+        let mut jail_report = JailReport::new(jail.name());
+        jail_report.actions = plan.actions;
+        report.jails.push(jail_report);
+    }
+
+    for jail in surplus.iter().rev() {
+        let plan = jail.destroy_plan()?;
+        if !json {
+            println!("{}: surplus replica, would be destroyed", jail.name());
+            println!("{}", secret::redact(&plan.to_string()));
+        }
+        let mut jail_report = JailReport::new(jail.name());
+        jail_report.actions = plan.actions;
+        report.jails.push(jail_report);
     }
 
     Ok(())
@@ -99,7 +505,7 @@ fn subcommand(sub_name: &str, sub_matches: &ArgMatches, settings: Settings) -> R
 // check that rj has been initialised properly
 fn check_init(settings: &Settings) -> Result<()> {
     debug!("checking init");
-    let jails_ds = zfs::DataSet::new(&settings.jails_dataset);
+    let jails_ds = zfs::DataSet::in_root(&settings.jails_dataset, &settings.jails_dataset);
     let mut error_msgs: Vec<String> = Vec::new();
 
     if !jails_ds.exists()? {
@@ -115,34 +521,152 @@ fn check_init(settings: &Settings) -> Result<()> {
 
     if !error_msgs.is_empty() {
         error_msgs.push("Run 'init' to fix".to_string());
-        bail!(error_msgs.join(" "));
+        bail!(Error::Prerequisite(error_msgs.join(" ")));
     }
 
     Ok(())
 }
 
 // initialise rj - currently it creates the jails root dataset and enables jails in rc.conf
-fn init(settings: &Settings) -> Result<()> {
+fn init(settings: &Settings, report: &mut Report) -> Result<()> {
     info!("initializing");
     // Create jails root ZFS dataset
-    let jails_ds = zfs::DataSet::new(&settings.jails_dataset);
-    jails_ds.create()?;
+    let jails_ds = zfs::DataSet::in_root(&settings.jails_dataset, &settings.jails_dataset);
+    if jails_ds.create()? {
+        report.init.push(InitAction::CreateDataset {
+            dataset: settings.jails_dataset.to_owned(),
+        });
+    }
     if Path::new(&jails_ds.get("mountpoint")?) != settings.jails_mountpoint {
         jails_ds.set("mountpoint", &settings.jails_mountpoint.to_str().unwrap())?;
+        report.init.push(InitAction::SetMountpoint {
+            dataset: settings.jails_dataset.to_owned(),
+            mountpoint: settings.jails_mountpoint.to_owned(),
+        });
+    }
+    if cmd!("sysrc", "-c", "jail_enable=YES").is_err() {
+        info!("enabling jails in rc.conf");
+        cmd!("sysrc", "jail_enable=YES")?;
+        report.init.push(InitAction::EnableJails);
     }
-    info!("enabling jails in rc.conf");
-    cmd!("sysrc", "jail_enable=YES")
+    Ok(())
 }
 
-fn make_it_so(matches: ArgMatches) -> Result<()> {
-    // Load settings
+// check the whole config and report every problem found, without
+// running any commands on the host
+fn validate(conf_file: &str, report: &mut Report) -> Result<()> {
+    let settings = Settings::load(conf_file, report.noop)?;
+    report.errors = settings.validate();
+
+    if !report.errors.is_empty() {
+        for err in report.errors.iter() {
+            error!("{}", err);
+        }
+        bail!(Error::Config(format!(
+            "{}: {} errors found",
+            conf_file,
+            report.errors.len()
+        )));
+    }
+
+    info!("{}: ok", conf_file);
+    Ok(())
+}
+
+// print the audit log entries for a jail, a run or all of them
+fn history(matches: &ArgMatches, report: &mut Report, json: bool) -> Result<()> {
+    let conf_file = matches.value_of("config").unwrap();
+    let settings = Settings::load(conf_file, report.noop)?;
+    let path = match matches.value_of("audit_log") {
+        Some(path) => PathBuf::from(path),
+        None => settings.audit_log,
+    };
+    let sub_matches = matches.subcommand_matches("history").unwrap();
+    let jail = sub_matches.value_of("jail_name");
+    let run = sub_matches.value_of("run");
+    let host = matches.value_of("host");
+
+    let mut entries: Vec<audit::Entry> = audit::read(&path)?
+        .into_iter()
+        .filter(|e| jail.is_none() || e.jail.as_deref() == jail)
+        .filter(|e| run.is_none() || Some(e.run_id.as_str()) == run)
+        .filter(|e| host.is_none() || e.host.as_deref() == host)
+        .collect();
+    if let Some(limit) = sub_matches.value_of("limit") {
+        let limit: usize = limit.parse()?;
+        entries.drain(..entries.len().saturating_sub(limit));
+    }
+
+    if !json {
+        for e in entries.iter() {
+            println!(
+                "{} {} {} {}: {}",
+                e.time,
+                e.run_id,
+                e.user,
+                e.jail.as_deref().unwrap_or("-"),
+                e.event
+            );
+        }
+    }
+    report.history = entries;
+    Ok(())
+}
+
+fn make_it_so(matches: &ArgMatches, report: &mut Report, json: bool) -> Result<()> {
     let conf_file = matches.value_of("config").unwrap();
-    let noop = matches.is_present("noop");
-    let settings = Settings::new(conf_file, noop)?;
+    match matches.subcommand_name() {
+        Some("validate") => return validate(conf_file, report),
+        Some("history") => return history(matches, report, json),
+        _ => (),
+    }
+
+    // Load settings
+    let mut settings = Settings::new(conf_file, report.noop)?;
+    if let Some(lock_dir) = matches.value_of("lock_dir") {
+        settings.lock_dir = PathBuf::from(lock_dir);
+    }
+    if let Some(audit_log) = matches.value_of("audit_log") {
+        settings.audit_log = PathBuf::from(audit_log);
+    }
+
+    if let Some(timeout) = settings.command_timeout {
+        cmd::set_default_timeout(Duration::from_secs(timeout));
+    }
+
+    // run commands and write files on another host
+    let host = matches.value_of("host");
+    let mut executor: Arc<dyn Executor> = Arc::new(System);
+    if let Some(host) = host {
+        executor = match settings.hosts.get(host) {
+            Some(h) => Arc::new(Ssh::new(&h.address, &h.ssh_options)),
+            None => Arc::new(Ssh::new(host, &[])),
+        };
+        debug!("managing {} over ssh", host);
+        // locks are taken here, keep each host's apart
+        settings.lock_dir = settings.lock_dir.join(host);
+    }
+
+    // log what's done to the host, only commands that can change it need to
+    let read_only = match matches.subcommand() {
+        (sub_name, Some(sub_matches)) => read_only_command(sub_name, sub_matches),
+        _ => false,
+    };
+    if !read_only && !settings.noop {
+        let log = AuditLog::open(&settings.audit_log, Path::new(conf_file), host)?;
+        executor = Arc::new(Audited::new(executor, log));
+    }
+    cmd::set_default_executor(executor);
+
+    let wait = match matches.value_of("timeout") {
+        Some(timeout) => Wait::For(Duration::from_secs(timeout.parse()?)),
+        None if matches.is_present("wait") => Wait::Forever,
+        None => Wait::No,
+    };
 
     // Execute the subcommand
     if let (sub_name, Some(sub_matches)) = matches.subcommand() {
-        subcommand(sub_name, sub_matches, settings)?;
+        subcommand(sub_name, sub_matches, settings, report, json, wait)?;
     }
 
     Ok(())
@@ -150,18 +674,51 @@ fn make_it_so(matches: ArgMatches) -> Result<()> {
 
 fn main() {
     let matches = cli::parse_args();
+    let json = matches.value_of("output") == Some("json");
+
+    // keep stdout clean for commands that print data
+    let log_mode = match matches.subcommand() {
+        (sub_name, Some(sub_matches)) if read_only_command(sub_name, sub_matches) => {
+            TerminalMode::Stderr
+        },
+        _ if json => TerminalMode::Stderr,
+        _ => TerminalMode::Mixed,
+    };
 
-    if matches.is_present("debug") {
-        TermLogger::init(LevelFilter::Debug, Config::default(), TerminalMode::Mixed)
-            .expect("No interactive terminal");
+    let level = if matches.is_present("debug") {
+        LevelFilter::Debug
     } else {
-        TermLogger::init(LevelFilter::Info, Config::default(), TerminalMode::Mixed)
-            .expect("No interactive terminal");
+        LevelFilter::Info
+    };
+    // secret values are redacted from every log line
+    let logger =
+        TermLogger::new(level, Config::default(), log_mode).expect("No interactive terminal");
+    log::set_max_level(level);
+    log::set_boxed_logger(Box::new(secret::RedactingLogger(logger)))
+        .expect("Logger already set");
+    executor::handle_interrupts();
+
+    // prune also takes --noop after the subcommand
+    let noop = matches.is_present("noop")
+        || matches
+            .subcommand_matches("prune")
+            .is_some_and(|m| m.is_present("noop"));
+    let mut report = Report::new(matches.subcommand_name().unwrap_or_default(), noop);
+    let result = make_it_so(&matches, &mut report, json);
+
+    if json {
+        if let Err(err) = &result {
+            report.error = Some(err.to_string());
+        }
+        match report.to_json() {
+            Ok(output) => println!("{}", secret::redact(&output)),
+            Err(err) => error!("{}", err),
+        }
     }
 
-    make_it_so(matches).unwrap_or_else(|err| {
+    result.unwrap_or_else(|err| {
         error!("{}", err);
-        process::exit(1);
+        process::exit(errors::exit_code(&err));
     });
 
     info!("done");
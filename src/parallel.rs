@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Result};
+use std::fmt::Display;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+// Run f on each item using up to `jobs` threads and return the results in
+// the order of the items.  deps[i] lists the indexes of the items that must
+// succeed before item i is run, items whose dependencies failed are skipped.
+pub fn run<T, F>(items: &[T], deps: &[Vec<usize>], jobs: usize, f: F) -> Vec<Result<()>>
+where
+    T: Display + Sync,
+    F: Fn(&T) -> Result<()> + Sync,
+{
+    let states = Mutex::new(vec![State::Pending; items.len()]);
+    let changed = Condvar::new();
+    let results: Mutex<Vec<Option<Result<()>>>> = Mutex::new(items.iter().map(|_| None).collect());
+
+    // pick the next item that can run, None once nothing is left
+    let next = || -> Option<usize> {
+        let mut states = states.lock().unwrap();
+        loop {
+            let mut pending = false;
+            for i in 0..items.len() {
+                if states[i] != State::Pending {
+                    continue;
+                }
+                if let Some(&d) = deps[i].iter().find(|&&d| states[d] == State::Failed) {
+                    states[i] = State::Failed;
+                    results.lock().unwrap()[i] = Some(Err(anyhow!(
+                        "{}: skipped, dependency {} failed",
+                        items[i],
+                        items[d]
+                    )));
+                    continue;
+                }
+                if deps[i].iter().all(|&d| states[d] == State::Done) {
+                    states[i] = State::Running;
+                    return Some(i);
+                }
+                pending = true;
+            }
+            if !pending {
+                changed.notify_all();
+                return None;
+            }
+            states = changed.wait(states).unwrap();
+        }
+    };
+
+    thread::scope(|s| {
+        for _ in 0..jobs.max(1).min(items.len()) {
+            s.spawn(|| {
+                while let Some(i) = next() {
+                    let result = f(&items[i]);
+                    let state = if result.is_ok() {
+                        State::Done
+                    } else {
+                        State::Failed
+                    };
+                    results.lock().unwrap()[i] = Some(result);
+                    states.lock().unwrap()[i] = state;
+                    changed.notify_all();
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn dependencies_first() {
+        let order = Mutex::new(Vec::new());
+        // 2 depends on 0 and 1 which are slow
+        let deps = vec![vec![], vec![], vec![0, 1], vec![]];
+        let results = run(&[0, 1, 2, 3], &deps, 4, |&i| {
+            if i < 2 {
+                thread::sleep(Duration::from_millis(100));
+            }
+            order.lock().unwrap().push(i);
+            Ok(())
+        });
+
+        assert!(results.iter().all(|r| r.is_ok()));
+        let order = order.into_inner().unwrap();
+        assert_eq!(order[0], 3);
+        assert_eq!(order[3], 2);
+    }
+
+    #[test]
+    fn limit() {
+        let running = Mutex::new((0, 0)); // (now, max)
+        let deps = vec![vec![]; 8];
+        run(&[0; 8], &deps, 3, |_| {
+            {
+                let mut r = running.lock().unwrap();
+                r.0 += 1;
+                r.1 = r.1.max(r.0);
+            }
+            thread::sleep(Duration::from_millis(20));
+            running.lock().unwrap().0 -= 1;
+            Ok(())
+        });
+        assert_eq!(running.into_inner().unwrap().1, 3);
+    }
+
+    #[test]
+    fn failures() {
+        // 1 fails, 2 depends on it, 3 depends on 2
+        let deps = vec![vec![], vec![], vec![1], vec![2]];
+        let results = run(&[0, 1, 2, 3], &deps, 2, |&i| {
+            if i == 1 {
+                bail!("failed");
+            }
+            Ok(())
+        });
+
+        let errors: Vec<String> = results
+            .iter()
+            .map(|r| r.as_ref().err().map_or("ok".to_string(), |e| e.to_string()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "ok",
+                "failed",
+                "2: skipped, dependency 1 failed",
+                "3: skipped, dependency 2 failed"
+            ]
+        );
+    }
+}
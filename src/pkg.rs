@@ -1,14 +1,20 @@
-use crate::cmd::Cmd;
+use crate::cmd::{Cmd, RETRY_DELAY};
 use crate::errors::CmdError;
 use anyhow::Result;
 use log::debug;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
+
+// fetching packages fails now and then, installs are retried this many times
+const RETRIES: u32 = 2;
 
 pub struct Pkg {
     name: String,
     chroot: PathBuf,
     env: HashMap<String, String>,
+    pub timeout: Option<Duration>,
+    pub retries: u32,
 }
 
 impl Pkg {
@@ -21,6 +27,8 @@ impl Pkg {
             name: name.to_string(),
             chroot: chroot.to_owned(),
             env: env,
+            timeout: None,
+            retries: RETRIES,
         }
     }
 
@@ -32,6 +40,8 @@ impl Pkg {
             .arg("install")
             .arg(&self.name)
             .envs(&self.env)
+            .timeout(self.timeout)
+            .retries(self.retries, RETRY_DELAY)
             .exec()
     }
 
@@ -94,4 +104,24 @@ mod tests {
         jail.destroy()?;
         Ok(())
     }
+
+    #[test]
+    fn is_installed_commands() -> Result<()> {
+        let fake = crate::executor::fake();
+        fake.on("pkg -c /jails/a info tokei", 0, "")
+            .on("pkg -c /jails/b info tokei", 70, "");
+
+        assert!(Pkg::new("tokei", &PathBuf::from("/jails/a")).is_installed()?);
+        assert!(!Pkg::new("tokei", &PathBuf::from("/jails/b")).is_installed()?);
+        Pkg::new("tokei", &PathBuf::from("/jails/b")).install()?;
+        assert_eq!(
+            fake.calls(),
+            vec![
+                "pkg -c /jails/a info tokei",
+                "pkg -c /jails/b info tokei",
+                "pkg -c /jails/b install tokei",
+            ]
+        );
+        Ok(())
+    }
 }
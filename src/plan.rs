@@ -0,0 +1,222 @@
+use crate::secret;
+use difference::{Changeset, Difference};
+use indexmap::IndexMap;
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+
+// A single change that applying a jail would make to the host
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Install { source: String, dataset: PathBuf },
+    // diff is None when the file is created
+    WriteJailConf { path: PathBuf, diff: Option<String> },
+    WriteFstab { path: PathBuf, diff: Option<String> },
+    // the properties to set, diff is None when the dataset is installed
+    SetZfsProperties {
+        dataset: PathBuf,
+        properties: IndexMap<String, String>,
+        diff: Option<String>,
+    },
+    Enable,
+    Disable,
+    Start,
+    Stop,
+    Restart,
+    Provision { provisioner: String },
+    Snapshot { name: String },
+    // destroy the snapshots the retention policy doesn't keep, worked out
+    // when it's carried out
+    Prune,
+    Rollback { snapshot: String },
+    Upgrade { source: String, release: String },
+    RemoveFile { path: PathBuf },
+    DestroySnapshots { snapshots: Vec<String> },
+    DestroyDataset { dataset: PathBuf },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Install { source, dataset } => {
+                write!(f, "install source '{}' into {}", source, dataset.display())
+            },
+            Action::WriteJailConf { path, diff } | Action::WriteFstab { path, diff } => {
+                match diff {
+                    Some(diff) => write!(f, "update {}\n{}", path.display(), diff),
+                    None => write!(f, "create {}", path.display()),
+                }
+            },
+            Action::SetZfsProperties {
+                dataset,
+                properties,
+                diff,
+            } => match diff {
+                Some(diff) => write!(
+                    f,
+                    "update zfs properties of {}\n{}",
+                    dataset.display(),
+                    diff
+                ),
+                None => {
+                    let properties: Vec<String> = properties
+                        .iter()
+                        .map(|(property, value)| format!("{}={}", property, value))
+                        .collect();
+                    write!(
+                        f,
+                        "set zfs properties of {}: {}",
+                        dataset.display(),
+                        properties.join(", ")
+                    )
+                },
+            },
+            Action::Enable => write!(f, "enable in rc.conf"),
+            Action::Disable => write!(f, "disable in rc.conf"),
+            Action::Start => write!(f, "start"),
+            Action::Stop => write!(f, "stop"),
+            Action::Restart => write!(f, "restart"),
+            Action::Provision { provisioner } => write!(f, "run provisioner '{}'", provisioner),
+            Action::Snapshot { name } => write!(f, "create '{}' snapshot", name),
+            Action::Prune => write!(f, "prune snapshots"),
+            Action::Rollback { snapshot } => write!(f, "roll back to snapshot {}", snapshot),
+            Action::Upgrade { source, release } => {
+                write!(f, "upgrade to release {} from source '{}'", release, source)
+            },
+            Action::RemoveFile { path } => write!(f, "remove {}", path.display()),
+            Action::DestroySnapshots { snapshots } => {
+                write!(f, "destroy snapshots: {}", snapshots.join(", "))
+            },
+            Action::DestroyDataset { dataset } => {
+                write!(f, "destroy dataset {}", dataset.display())
+            },
+        }
+    }
+}
+
+// The ordered list of actions needed to bring a jail in line with its settings
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Plan {
+    pub jail: String,
+    pub actions: Vec<Action>,
+}
+
+impl Plan {
+    pub fn new(jail: &str) -> Plan {
+        Plan {
+            jail: jail.to_owned(),
+            actions: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, action: Action) {
+        self.actions.push(action);
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.actions.is_empty() {
+            return write!(f, "{}: no changes", self.jail);
+        }
+        let lines: Vec<String> = self
+            .actions
+            .iter()
+            .map(|a| format!("{}: {}", self.jail, a))
+            .collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+// line based diff without terminal colours, each line is prefixed with '+', '-' or ' '
+// and secret values are redacted
+pub fn diff(current: &str, rendered: &str) -> String {
+    let changeset = Changeset::new(current, rendered, "\n");
+    let mut lines = vec![];
+    for d in changeset.diffs.iter() {
+        let (prefix, text) = match d {
+            Difference::Same(x) => (" ", x),
+            Difference::Add(x) => ("+", x),
+            Difference::Rem(x) => ("-", x),
+        };
+        for line in text.lines() {
+            lines.push(format!("{}{}", prefix, line));
+        }
+    }
+    secret::redact(&lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::indexmap;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn diff_lines() {
+        let current = "a = 1;\nb = 2;\nc = 3;\n";
+        let rendered = "a = 1;\nb = 4;\nc = 3;\n";
+        assert_eq!(
+            diff(current, rendered),
+            " a = 1;\n-b = 2;\n+b = 4;\n c = 3;"
+        );
+    }
+
+    #[test]
+    fn display() {
+        let mut plan = Plan::new("test");
+        assert_eq!(plan.to_string(), "test: no changes");
+
+        plan.push(Action::Install {
+            source: "base".to_string(),
+            dataset: PathBuf::from("zroot/jails/test"),
+        });
+        plan.push(Action::WriteJailConf {
+            path: PathBuf::from("/etc/jail.test.conf"),
+            diff: None,
+        });
+        plan.push(Action::SetZfsProperties {
+            dataset: PathBuf::from("zroot/jails/test"),
+            properties: indexmap! {
+                "quota".to_string() => "20G".to_string(),
+                "compression".to_string() => "lz4".to_string(),
+            },
+            diff: None,
+        });
+        plan.push(Action::Enable);
+        plan.push(Action::Provision {
+            provisioner: "exec".to_string(),
+        });
+        plan.push(Action::Snapshot {
+            name: "ready".to_string(),
+        });
+
+        let ok = indoc!(
+            r#"
+            test: install source 'base' into zroot/jails/test
+            test: create /etc/jail.test.conf
+            test: set zfs properties of zroot/jails/test: quota=20G, compression=lz4
+            test: enable in rc.conf
+            test: run provisioner 'exec'
+            test: create 'ready' snapshot"#
+        );
+        assert_eq!(plan.to_string(), ok);
+    }
+
+    #[test]
+    fn serialize() {
+        let mut plan = Plan::new("test");
+        plan.push(Action::WriteFstab {
+            path: PathBuf::from("/etc/fstab.test"),
+            diff: Some("-a\n+b".to_string()),
+        });
+        plan.push(Action::Start);
+
+        assert_eq!(
+            serde_json::to_string(&plan).unwrap(),
+            r#"{"jail":"test","actions":[{"action":"write_fstab","path":"/etc/fstab.test","diff":"-a\n+b"},{"action":"start"}]}"#
+        );
+    }
+}
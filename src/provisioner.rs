@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+use crate::interpolate::Vars;
 use crate::jail::Jail;
 use anyhow::Result;
 use serde::Deserialize;
@@ -40,6 +41,24 @@ impl Provisioner {
         }
     }
 
+    // Expand variables in the values that can use them
+    pub fn expand(&mut self, vars: &Vars, errors: &mut Vec<String>) {
+        match self {
+            Provisioner::File(p) => p.expand(vars, errors),
+            Provisioner::Exec(p) => p.expand(vars, errors),
+            Provisioner::Test(_) | Provisioner::Puppet(_) => (),
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        match self {
+            Provisioner::File(p) => &p.name,
+            Provisioner::Exec(p) => &p.name,
+            Provisioner::Test(p) => &p.name,
+            Provisioner::Puppet(p) => &p.name,
+        }
+    }
+
     pub fn name(&mut self, name: &str) {
         match self {
             Provisioner::File(p) => p.name = name.to_owned(),
@@ -1,8 +1,10 @@
-use crate::cmd::Cmd;
+use crate::cmd::{self, Cmd};
+use crate::interpolate::Vars;
 use crate::jail::Jail;
 use anyhow::Result;
 use log::{debug, info};
 use serde::Deserialize;
+use std::time::Duration;
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -12,6 +14,11 @@ pub struct Exec {
     pub cmd: String,
     #[serde(default = "default_mode")]
     pub mode: ExecMode,
+    // seconds the command may run before it's killed
+    pub timeout: Option<u64>,
+    // times to run the command again if it fails
+    #[serde(default)]
+    pub retries: u32,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -27,6 +34,11 @@ fn default_mode() -> ExecMode {
 }
 
 impl Exec {
+    pub fn expand(&mut self, vars: &Vars, errors: &mut Vec<String>) {
+        let key = format!("provisioner.{}.cmd", self.name);
+        vars.expand_string(&key, &mut self.cmd, errors);
+    }
+
     pub fn provision(&self, jail: &Jail) -> Result<()> {
         info!("{}: running exec provisioner: {}", jail.name(), self.name);
         info!(
@@ -41,7 +53,12 @@ impl Exec {
         match self.mode {
             ExecMode::Jexec => {
                 if !jail.noop() {
-                    Cmd::new("jexec").arg(jail.name()).args(args).stream()?;
+                    Cmd::new("jexec")
+                        .arg(jail.name())
+                        .args(args)
+                        .timeout(self.timeout.map(Duration::from_secs))
+                        .retries(self.retries, cmd::RETRY_DELAY)
+                        .stream()?;
                 }
             }
             ExecMode::Chroot => {
@@ -49,6 +66,8 @@ impl Exec {
                     Cmd::new("chroot")
                         .arg(jail.mountpoint())
                         .args(args)
+                        .timeout(self.timeout.map(Duration::from_secs))
+                        .retries(self.retries, cmd::RETRY_DELAY)
                         .stream()?;
                 }
             }
@@ -65,6 +84,7 @@ impl Exec {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::executor::CmdOutput;
     use crate::provisioner::test_helpers::setup;
     use crate::settings::Settings;
     use serial_test::serial;
@@ -146,4 +166,49 @@ mod tests {
         jail.destroy()?;
         Ok(())
     }
+
+    #[test]
+    fn provision_commands() -> Result<()> {
+        let s = Settings::load("testdata/config.toml", false)?;
+        let jails = s.to_jails()?;
+        let fake = crate::executor::fake();
+
+        s.provisioner["exec"].provision(&jails["test2"])?;
+        s.provisioner["exec_chroot"].provision(&jails["test2"])?;
+        assert_eq!(
+            fake.calls(),
+            vec![
+                "jexec test2 touch /tmp/exec_test",
+                "chroot /jails/test2 touch /tmp/exec_chroot_test",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn provision_timeout() -> Result<()> {
+        let s = Settings::load("testdata/config.toml", false)?;
+        let jails = s.to_jails()?;
+        let fake = crate::executor::fake();
+        fake.on_output(
+            "jexec",
+            CmdOutput {
+                timed_out: true,
+                ..CmdOutput::default()
+            },
+        );
+
+        let exec: Exec = toml::from_str(
+            r#"
+            cmd = "pkg fetch -y nginx"
+            timeout = 60
+            "#,
+        )?;
+        let err = exec.provision(&jails["test2"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "timed out after 60s: jexec test2 pkg fetch -y nginx"
+        );
+        Ok(())
+    }
 }
@@ -1,12 +1,11 @@
 use crate::cmd;
+use crate::interpolate::Vars;
 use crate::jail::Jail;
 use anyhow::{bail, Result};
 use log::{debug, info};
 use regex::Regex;
 use serde::Deserialize;
-use std::fs::copy;
-use std::fs::{set_permissions, Permissions};
-use std::os::unix::prelude::*;
+use std::fs;
 use std::path::PathBuf;
 
 #[derive(Clone, Debug, Deserialize)]
@@ -14,7 +13,9 @@ use std::path::PathBuf;
 pub struct ProvFile {
     #[serde(skip)] // set in Settings based on the IndexMap key
     pub name: String,
-    source: PathBuf,
+    // either a file to copy or the content to write
+    source: Option<PathBuf>,
+    content: Option<String>,
     dest: PathBuf,
     #[serde(default = "default_mode")]
     mode: String,
@@ -37,21 +38,45 @@ fn default_mode() -> String {
 }
 
 impl ProvFile {
+    pub fn expand(&mut self, vars: &Vars, errors: &mut Vec<String>) {
+        let key = format!("provisioner.{}.dest", self.name);
+        vars.expand_path(&key, &mut self.dest, errors);
+        if let Some(content) = &mut self.content {
+            let key = format!("provisioner.{}.content", self.name);
+            vars.expand_string(&key, content, errors);
+        }
+    }
+
     pub fn provision(&self, jail: &Jail) -> Result<()> {
         info!("{}: running file provisioner: {}", jail.name(), self.name);
 
         let full_dest = jail.mountpoint().join(&self.dest.strip_prefix("/")?);
 
-        // copy the file
-        info!(
-            "{}: {} -> {}{}",
-            jail.name(),
-            self.source.display(),
-            full_dest.display(),
-            jail.noop_suffix(),
-        );
-        if !jail.noop() {
-            copy(&self.source, &full_dest)?;
+        match (&self.source, &self.content) {
+            (Some(source), _) => {
+                info!(
+                    "{}: {} -> {}{}",
+                    jail.name(),
+                    source.display(),
+                    full_dest.display(),
+                    jail.noop_suffix(),
+                );
+                if !jail.noop() {
+                    cmd::write_file(&full_dest, &fs::read(source)?)?;
+                }
+            },
+            (None, content) => {
+                info!(
+                    "{}: writing {}{}",
+                    jail.name(),
+                    full_dest.display(),
+                    jail.noop_suffix(),
+                );
+                if !jail.noop() {
+                    let content = content.as_deref().unwrap_or_default();
+                    cmd::write_file(&full_dest, content.as_bytes())?;
+                }
+            },
         }
 
         // set permissions
@@ -63,7 +88,7 @@ impl ProvFile {
             jail.noop_suffix()
         );
         if !jail.noop() {
-            set_permissions(&full_dest, Permissions::from_mode(mode_u32))?;
+            cmd!("chmod", format!("{:o}", mode_u32), &full_dest)?;
         }
 
         // set ownership
@@ -89,14 +114,18 @@ impl ProvFile {
     }
 
     fn validate_source(&self) -> Result<()> {
-        if self.source.is_file() {
-            Ok(())
-        } else {
-            bail!(
+        match (&self.source, &self.content) {
+            (Some(source), None) if source.is_file() => Ok(()),
+            (Some(source), None) => bail!(
                 "file provisioner {}, invalid source: {}",
                 self.name,
-                self.source.display()
-            );
+                source.display()
+            ),
+            (None, Some(_)) => Ok(()),
+            _ => bail!(
+                "file provisioner {}, needs one of source or content",
+                self.name
+            ),
         }
     }
 
@@ -129,12 +158,14 @@ impl ProvFile {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::executor::Executor;
     use crate::provisioner::test_helpers::setup;
     use crate::settings::Settings;
     use pretty_assertions::assert_eq;
     use serial_test::serial;
     use std::fs;
     use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
 
     #[test]
     #[serial]
@@ -184,14 +215,15 @@ mod tests {
     fn validate() {
         let mut file = ProvFile {
             name: "test".to_owned(),
-            source: PathBuf::from("/tmp/whatever123"),
+            source: Some(PathBuf::from("/tmp/whatever123")),
+            content: None,
             dest: PathBuf::from("/tmp/desttest"),
             mode: "640".to_string(),
             owner: "root".to_string(),
             group: "wheel".to_string(),
         };
         assert!(file.validate().is_err());
-        file.source = PathBuf::from("/etc/hosts");
+        file.source = Some(PathBuf::from("/etc/hosts"));
         assert!(file.validate().is_ok());
         file.dest = PathBuf::from("tmp/desttest");
         assert!(file.validate().is_err());
@@ -202,5 +234,33 @@ mod tests {
         assert!(file.validate().is_err());
         file.mode = "0644".to_string();
         assert!(file.validate().is_ok());
+        file.content = Some("token".to_string());
+        assert!(file.validate().is_err());
+        file.source = None;
+        assert!(file.validate().is_ok());
+        file.content = None;
+        assert!(file.validate().is_err());
+    }
+
+    #[test]
+    fn provision_commands() -> Result<()> {
+        let s = Settings::load("testdata/config.toml", false)?;
+        let jails = s.to_jails()?;
+        let fake = crate::executor::fake();
+
+        s.provisioner["file"].provision(&jails["file_test"])?;
+        assert_eq!(
+            fake.calls(),
+            vec![
+                "write_file /jails/file_test/tmp/file.txt",
+                "chmod 640 /jails/file_test/tmp/file.txt",
+                "chroot /jails/file_test chown nobody:nobody /tmp/file.txt",
+            ]
+        );
+        assert_eq!(
+            fake.read_file(Path::new("/jails/file_test/tmp/file.txt"))?,
+            Some(fs::read_to_string("testdata/provisioners/file.txt")?)
+        );
+        Ok(())
     }
 }
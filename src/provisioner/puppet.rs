@@ -1,15 +1,12 @@
 use crate::cmd;
 use crate::cmd::Cmd;
-use crate::cmd_stream;
 use crate::jail::Jail;
 use crate::pkg::Pkg;
 use anyhow::{bail, Result};
 use log::{debug, info};
 use serde::Deserialize;
-use std::fs;
-use std::fs::{set_permissions, Permissions};
-use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -29,6 +26,11 @@ pub struct Puppet {
     pub tmp_dir: PathBuf,
     #[serde(default = "default_version")]
     pub puppet_version: String,
+    // seconds each command may run before it's killed
+    pub timeout: Option<u64>,
+    // times to run a failed command again, package installs are retried
+    // a few times if not set
+    pub retries: Option<u32>,
 }
 
 fn default_manifest_file() -> PathBuf {
@@ -69,7 +71,8 @@ impl Puppet {
         let wrapper_inside_path = manifest_inside_path.join("wrapper.sh");
 
         self.make_wrapper(&manifest_inside_path, &wrapper_outside_path)?;
-        if manifest_outside_path.join("Puppetfile").is_file() {
+        // the copy on the host matches the one here
+        if self.path.join("Puppetfile").is_file() {
             self.run_r10k(jail, &wrapper_inside_path)?;
         }
         self.run_puppet(jail, &wrapper_inside_path)?;
@@ -79,7 +82,7 @@ impl Puppet {
 
     fn install_puppet(&self, jail: &Jail) -> Result<()> {
         let pkg_name = format!("puppet{}", &self.puppet_version);
-        let pkg = Pkg::new(&pkg_name, &jail.mountpoint());
+        let pkg = self.pkg(&pkg_name, jail);
 
         if !pkg.is_installed()? {
             info!("{}: installing {}", jail.name(), pkg_name);
@@ -89,40 +92,45 @@ impl Puppet {
     }
 
     fn copy_manifest(&self, src: &Path, dest: &Path) -> Result<()> {
-        fs::create_dir_all(&dest)?;
-        set_permissions(&dest, Permissions::from_mode(0o700))?;
-        cmd!(
-            "rsync",
-            "-r",
-            "--delete",
-            src.to_str().unwrap(),
-            dest.to_str().unwrap()
-        )
+        cmd!("mkdir", "-p", dest)?;
+        cmd!("chmod", "700", dest)?;
+        cmd::copy_dir(src, dest)
     }
 
     // Wrapper changes into the root of the manifest directory before executing a command.  Used for executing puppet and r10k.
     fn make_wrapper(&self, manifest_path: &Path, wrapper_path: &Path) -> Result<()> {
         let wrapper = format!("#!/bin/sh\ncd {} && $@\n", &manifest_path.to_str().unwrap());
-        fs::write(&wrapper_path, wrapper)?;
-        set_permissions(&wrapper_path, Permissions::from_mode(0o755))?;
-        Ok(())
+        cmd::write_file(wrapper_path, wrapper.as_bytes())?;
+        cmd!("chmod", "755", wrapper_path)
     }
 
     fn run_r10k(&self, jail: &Jail, wrapper_path: &Path) -> Result<()> {
-        let pkg = Pkg::new("rubygem-r10k", &jail.mountpoint());
+        let pkg = self.pkg("rubygem-r10k", jail);
         if !pkg.is_installed()? {
             info!("{}: installing {}", jail.name(), "rubygem-r10k");
             pkg.install()?;
         }
 
-        cmd_stream!(
-            "jexec",
-            jail.name(),
-            wrapper_path.to_str().unwrap(),
-            "r10k",
-            "puppetfile",
-            "install"
-        )
+        Cmd::new("jexec")
+            .arg(jail.name())
+            .arg(wrapper_path)
+            .args(["r10k", "puppetfile", "install"])
+            .timeout(self.timeout())
+            .retries(self.retries.unwrap_or(0), cmd::RETRY_DELAY)
+            .stream()
+    }
+
+    fn pkg(&self, name: &str, jail: &Jail) -> Pkg {
+        let mut pkg = Pkg::new(name, jail.mountpoint());
+        pkg.timeout = self.timeout();
+        if let Some(retries) = self.retries {
+            pkg.retries = retries;
+        }
+        pkg
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
     }
 
     fn run_puppet(&self, jail: &Jail, wrapper_path: &Path) -> Result<()> {
@@ -145,7 +153,9 @@ impl Puppet {
         if *jail.noop() {
             cmd.arg("--noop");
         }
-        cmd.arg(&self.manifest_file);
+        cmd.arg(&self.manifest_file)
+            .timeout(self.timeout())
+            .retries(self.retries.unwrap_or(0), cmd::RETRY_DELAY);
         debug!("{:?}", &cmd);
         cmd.stream()?;
         Ok(())
@@ -222,6 +232,7 @@ mod tests {
     use crate::settings::Settings;
     use pretty_assertions::assert_eq;
     use serial_test::serial;
+    use std::fs;
 
     #[test]
     #[serial]
@@ -292,6 +303,8 @@ mod tests {
             extra_args: vec![],
             tmp_dir: default_tmp_dir(),
             puppet_version: default_version(),
+            timeout: None,
+            retries: None,
         };
 
         assert!(puppet.validate().is_ok());
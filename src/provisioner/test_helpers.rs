@@ -10,7 +10,7 @@ pub fn setup<'a>(s: &'a Settings, name: &str) -> Result<Jail<'a>> {
 
     // make sure we have a basejail set up
     if !basejail.exists()? {
-        crate::init(&s).unwrap();
+        crate::init(&s, &mut crate::report::Report::default()).unwrap();
         basejail.apply()?;
     }
 
@@ -0,0 +1,115 @@
+use crate::audit::Entry;
+use crate::jail::{JailStatus, SnapshotStatus};
+use crate::plan::Action;
+use crate::zfs::FileChange;
+use anyhow::Result;
+use serde::Serialize;
+use std::path::PathBuf;
+
+// Machine readable summary of a run, printed when `--output json` is set
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub command: String,
+    pub noop: bool,
+    pub jails: Vec<JailReport>,
+    // problems found in the config by validate
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+    // audit log entries shown by history
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<Entry>,
+    // what init changed on the host
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub init: Vec<InitAction>,
+    pub error: Option<String>,
+}
+
+// What happened to a single jail.  For apply, destroy and provision the
+// actions are the ones that were carried out, for plan the ones that would be.
+#[derive(Debug, Serialize)]
+pub struct JailReport {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<JailStatus>,
+    // listed by snapshot list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshots: Option<Vec<SnapshotStatus>>,
+    // files found changed by snapshot diff
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changes: Option<Vec<FileChange>>,
+    pub actions: Vec<Action>,
+    pub error: Option<String>,
+}
+
+// A change init made to set the host up for rj
+#[derive(Debug, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum InitAction {
+    CreateDataset {
+        dataset: PathBuf,
+    },
+    SetMountpoint {
+        dataset: PathBuf,
+        mountpoint: PathBuf,
+    },
+    EnableJails,
+}
+
+impl Report {
+    pub fn new(command: &str, noop: bool) -> Report {
+        Report {
+            command: command.to_owned(),
+            noop,
+            ..Default::default()
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(&self)?)
+    }
+}
+
+impl JailReport {
+    pub fn new(name: &str) -> JailReport {
+        JailReport {
+            name: name.to_owned(),
+            status: None,
+            snapshots: None,
+            changes: None,
+            actions: Vec::new(),
+            error: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn serialize() -> Result<()> {
+        let mut report = Report::new("apply", true);
+        let mut jail = JailReport::new("test");
+        jail.actions.push(Action::Start);
+        jail.error = Some("failed".to_owned());
+        report.jails.push(jail);
+
+        let value: serde_json::Value = serde_json::from_str(&report.to_json()?)?;
+        assert_eq!(
+            value,
+            json!({
+                "command": "apply",
+                "noop": true,
+                "jails": [{
+                    "name": "test",
+                    "actions": [{"action": "start"}],
+                    "error": "failed",
+                }],
+                "error": null,
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use crate::zfs::{Properties, SNAP_TIME_FORMAT};
+use anyhow::{anyhow, bail, Result};
+use chrono::{Duration, NaiveDateTime};
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+// Which of a jail's timestamped snapshots to keep.  A snapshot is kept if
+// it's one of the last keep_last with its name, e.g. ready, or newer than
+// keep_newer_than.  Every snapshot is kept when neither is set.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    pub keep_last: Option<u32>,
+    // an age such as 12h or 30d, in s, m, h, d or w
+    pub keep_newer_than: Option<String>,
+}
+
+// Parse an age such as 30d into a duration
+fn parse_age(age: &str) -> Result<Duration> {
+    let invalid = || anyhow!("invalid age: {}, expected e.g. 12h or 30d", age);
+    let unit = age.chars().last().ok_or_else(invalid)?;
+    let number: u32 = age[..age.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    let number = i64::from(number);
+    match unit {
+        's' => Ok(Duration::seconds(number)),
+        'm' => Ok(Duration::minutes(number)),
+        'h' => Ok(Duration::hours(number)),
+        'd' => Ok(Duration::days(number)),
+        'w' => Ok(Duration::weeks(number)),
+        _ => Err(invalid()),
+    }
+}
+
+// The name a snapshot was taken with by snap_with_time, None for snapshots
+// named some other way
+fn snapshot_name(snapshot: &str) -> Option<&str> {
+    let (time, name) = snapshot.split_once('_')?;
+    NaiveDateTime::parse_from_str(time, SNAP_TIME_FORMAT).ok()?;
+    Some(name)
+}
+
+impl Retention {
+    // Apply other on top of self
+    pub fn merge(&self, other: &Retention) -> Retention {
+        Retention {
+            keep_last: other.keep_last.or(self.keep_last),
+            keep_newer_than: other
+                .keep_newer_than
+                .to_owned()
+                .or_else(|| self.keep_newer_than.to_owned()),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.keep_last.is_some() || self.keep_newer_than.is_some()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.keep_last == Some(0) {
+            bail!("retention keep_last must be at least 1");
+        }
+        if let Some(age) = &self.keep_newer_than {
+            parse_age(age).map_err(|e| anyhow!("retention keep_newer_than: {}", e))?;
+        }
+        Ok(())
+    }
+
+    // The snapshots the policy doesn't keep, oldest first.  snapshots are a
+    // dataset's, oldest first, and the ones in clones have clones so are
+    // always kept.
+    pub fn expired(
+        &self,
+        snapshots: &[Properties],
+        clones: &IndexMap<String, Vec<String>>,
+        now: NaiveDateTime,
+    ) -> Result<Vec<String>> {
+        let mut expired = Vec::new();
+        if !self.is_set() {
+            return Ok(expired);
+        }
+        let cutoff = match &self.keep_newer_than {
+            Some(age) => Some(now - parse_age(age)?),
+            None => None,
+        };
+
+        // newest first, counting the snapshots seen with each name
+        let mut seen: IndexMap<&str, u32> = IndexMap::new();
+        for s in snapshots.iter().rev() {
+            let snap = match s.snapshot() {
+                Some(snap) => snap,
+                None => continue,
+            };
+            let name = match snapshot_name(snap) {
+                Some(name) => name,
+                None => continue,
+            };
+            let count = seen.entry(name).or_insert(0);
+            *count += 1;
+
+            let last = self.keep_last.is_some_and(|n| *count <= n);
+            let newer = cutoff.is_some_and(|c| s.creation >= c);
+            if !last && !newer && !clones.contains_key(snap) {
+                expired.push(snap.to_owned());
+            }
+        }
+        expired.reverse();
+        Ok(expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indexmap::indexmap;
+    use pretty_assertions::assert_eq;
+
+    fn snapshots(names: &[(&str, i64)]) -> Vec<Properties> {
+        names
+            .iter()
+            .map(|(name, creation)| Properties {
+                name: format!("zroot/jails/test@{}", name),
+                used: 0,
+                referenced: 0,
+                creation: NaiveDateTime::from_timestamp(*creation, 0),
+                origin: None,
+                mountpoint: None,
+                user: IndexMap::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn age() {
+        assert_eq!(parse_age("90s").unwrap(), Duration::seconds(90));
+        assert_eq!(parse_age("12h").unwrap(), Duration::hours(12));
+        assert_eq!(parse_age("30d").unwrap(), Duration::days(30));
+        assert_eq!(parse_age("2w").unwrap(), Duration::weeks(2));
+        for age in &["", "d", "30", "30y", "-1d"] {
+            assert!(parse_age(age).is_err(), "{}", age);
+        }
+    }
+
+    #[test]
+    fn expired() -> Result<()> {
+        let day = 86400;
+        let snaps = snapshots(&[
+            ("2020-01-01T00:00:00.000_pre-provision", 0),
+            ("2020-01-01T00:01:00.000_ready", 60),
+            ("2020-01-02T00:00:00.000_pre-provision", day),
+            ("2020-01-02T00:01:00.000_ready", day + 60),
+            ("manual", day + 120),
+            ("2020-01-03T00:00:00.000_pre-provision", 2 * day),
+            ("2020-01-03T00:01:00.000_ready", 2 * day + 60),
+        ]);
+        let now = NaiveDateTime::from_timestamp(3 * day, 0);
+        let none: Vec<String> = Vec::new();
+        let no_clones = IndexMap::new();
+
+        assert_eq!(Retention::default().expired(&snaps, &no_clones, now)?, none);
+
+        let last = Retention {
+            keep_last: Some(1),
+            keep_newer_than: None,
+        };
+        assert_eq!(
+            last.expired(&snaps, &no_clones, now)?,
+            vec![
+                "2020-01-01T00:00:00.000_pre-provision",
+                "2020-01-01T00:01:00.000_ready",
+                "2020-01-02T00:00:00.000_pre-provision",
+                "2020-01-02T00:01:00.000_ready",
+            ]
+        );
+
+        // either rule keeps a snapshot
+        let both = Retention {
+            keep_last: Some(1),
+            keep_newer_than: Some("2d".to_string()),
+        };
+        assert_eq!(
+            both.expired(&snaps, &no_clones, now)?,
+            vec![
+                "2020-01-01T00:00:00.000_pre-provision",
+                "2020-01-01T00:01:00.000_ready",
+            ]
+        );
+
+        // clones depend on their origin
+        let clones = indexmap! {
+            "2020-01-01T00:01:00.000_ready".to_string() => vec!["zroot/jails/web".to_string()],
+        };
+        assert_eq!(
+            both.expired(&snaps, &clones, now)?,
+            vec!["2020-01-01T00:00:00.000_pre-provision"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn merge() {
+        let global = Retention {
+            keep_last: Some(5),
+            keep_newer_than: Some("30d".to_string()),
+        };
+        let jail = Retention {
+            keep_last: Some(2),
+            keep_newer_than: None,
+        };
+        assert_eq!(
+            global.merge(&jail),
+            Retention {
+                keep_last: Some(2),
+                keep_newer_than: Some("30d".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Retention::default().validate().is_ok());
+        let zero = Retention {
+            keep_last: Some(0),
+            keep_newer_than: None,
+        };
+        assert_eq!(
+            zero.validate().unwrap_err().to_string(),
+            "retention keep_last must be at least 1"
+        );
+        let age = Retention {
+            keep_last: None,
+            keep_newer_than: Some("soon".to_string()),
+        };
+        assert_eq!(
+            age.validate().unwrap_err().to_string(),
+            "retention keep_newer_than: invalid age: soon, expected e.g. 12h or 30d"
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use crate::cmd::Cmd;
+use crate::errors::Error;
+use anyhow::{anyhow, bail, Result};
+use indexmap::IndexMap;
+use log::{Log, Metadata, Record};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+// Secret values that have been read, replaced in anything rj prints
+static RESOLVED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+const REDACTED: &str = "********";
+
+// The secrets named in the [secret] table, each given as where to read it:
+//   file:/path  the content of a file, less the trailing newline
+//   env:NAME    an environment variable
+//   store:KEY   a key in the encrypted secrets file
+// The secrets file is a TOML table of keys and values encrypted with
+// 'openssl enc -aes-256-cbc -pbkdf2', secrets_pass is passed to openssl's
+// -pass option.  Secrets are only read when a value uses them.
+#[derive(Clone, Debug, Default)]
+pub struct Secrets {
+    specs: IndexMap<String, String>,
+    store_path: Option<PathBuf>,
+    store_pass: String,
+    store: OnceLock<IndexMap<String, String>>,
+}
+
+impl Secrets {
+    pub fn new(
+        specs: &IndexMap<String, String>,
+        store_path: &Option<PathBuf>,
+        store_pass: &str,
+    ) -> Secrets {
+        Secrets {
+            specs: specs.to_owned(),
+            store_path: store_path.to_owned(),
+            store_pass: store_pass.to_owned(),
+            store: OnceLock::new(),
+        }
+    }
+
+    // check where each secret comes from without reading any of them
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (name, spec) in self.specs.iter() {
+            if spec.starts_with("store:") && self.store_path.is_none() {
+                errors.push(format!(
+                    "secret {}: {} needs secrets_file to be set",
+                    name, spec
+                ));
+            } else if !["file:", "env:", "store:"]
+                .iter()
+                .any(|p| spec.starts_with(p))
+            {
+                errors.push(format!(
+                    "secret {}: unknown source: {}, expected file:, env: or store:",
+                    name, spec
+                ));
+            }
+        }
+        errors
+    }
+
+    // the value of a secret, None if there's no secret by that name
+    pub fn get(&self, name: &str) -> Result<Option<String>> {
+        let spec = match self.specs.get(name) {
+            Some(spec) => spec,
+            None => return Ok(None),
+        };
+
+        let value = if let Some(path) = spec.strip_prefix("file:") {
+            let content = fs::read_to_string(path)
+                .map_err(|e| anyhow!("secret {}: can't read {}: {}", name, path, e))?;
+            content.trim_end_matches('\n').to_owned()
+        } else if let Some(var) = spec.strip_prefix("env:") {
+            match env::var(var) {
+                Ok(value) => value,
+                Err(_) => bail!(Error::Config(format!(
+                    "secret {}: environment variable {} is not set",
+                    name, var
+                ))),
+            }
+        } else if let Some(key) = spec.strip_prefix("store:") {
+            match self.store()?.get(key) {
+                Some(value) => value.to_owned(),
+                None => bail!(Error::Config(format!(
+                    "secret {}: {} is not in the secrets file",
+                    name, key
+                ))),
+            }
+        } else {
+            bail!(Error::Config(format!(
+                "secret {}: unknown source: {}",
+                name, spec
+            )));
+        };
+
+        register(&value);
+        Ok(Some(value))
+    }
+
+    // decrypt the secrets file the first time it's needed
+    fn store(&self) -> Result<&IndexMap<String, String>> {
+        if let Some(store) = self.store.get() {
+            return Ok(store);
+        }
+        let path = match &self.store_path {
+            Some(path) => path,
+            None => bail!(Error::Config("no secrets_file set".to_string())),
+        };
+
+        // the secrets file is on this host even when managing another
+        let decrypted = Cmd::new("openssl")
+            .args(["enc", "-d", "-aes-256-cbc", "-pbkdf2", "-in"])
+            .arg(path)
+            .arg("-pass")
+            .arg(&self.store_pass)
+            .capture_local()?;
+        let store: IndexMap<String, String> =
+            toml::from_str(&decrypted).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        for value in store.values() {
+            register(value);
+        }
+
+        Ok(self.store.get_or_init(|| store))
+    }
+}
+
+// Redact a value from now on, multi line values line by line too
+fn register(value: &str) {
+    let mut resolved = RESOLVED.lock().unwrap();
+    for v in std::iter::once(value).chain(value.lines()) {
+        let v = v.trim();
+        if !v.is_empty() && !resolved.iter().any(|r| r == v) {
+            resolved.push(v.to_owned());
+        }
+    }
+    // longest first so that values containing others are replaced whole
+    resolved.sort_by_key(|v| std::cmp::Reverse(v.len()));
+}
+
+// Replace the secret values that have been read
+pub fn redact(s: &str) -> String {
+    let mut redacted = s.to_owned();
+    for value in RESOLVED.lock().unwrap().iter() {
+        redacted = redacted.replace(value.as_str(), REDACTED);
+    }
+    redacted
+}
+
+// Passes log records on to another logger with secret values redacted
+pub struct RedactingLogger(pub Box<dyn Log>);
+
+impl Log for RedactingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let message = redact(&record.args().to_string());
+        self.0.log(
+            &Record::builder()
+                .metadata(record.metadata().clone())
+                .args(format_args!("{}", message))
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd_capture;
+    use indexmap::indexmap;
+    use pretty_assertions::assert_eq;
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    fn get() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        fs::write(file.path(), "from a file\n")?;
+        env::set_var("RJ_SECRET_TEST", "from the env");
+
+        let secrets = Secrets::new(
+            &indexmap! {
+                "file".to_string() => format!("file:{}", file.path().display()),
+                "env".to_string() => "env:RJ_SECRET_TEST".to_string(),
+                "unset".to_string() => "env:RJ_SECRET_UNSET_TEST".to_string(),
+            },
+            &None,
+            "env:RJ_SECRETS_PASS",
+        );
+        assert_eq!(secrets.validate(), Vec::<String>::new());
+        assert_eq!(secrets.get("file")?, Some("from a file".to_string()));
+        assert_eq!(secrets.get("env")?, Some("from the env".to_string()));
+        assert_eq!(secrets.get("nope")?, None);
+        assert_eq!(
+            secrets.get("unset").unwrap_err().to_string(),
+            "secret unset: environment variable RJ_SECRET_UNSET_TEST is not set"
+        );
+
+        assert_eq!(
+            redact("token=from the env, from a file"),
+            "token=********, ********"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn store() -> Result<()> {
+        let dir = TempDir::new()?;
+        let plain = dir.path().join("secrets.toml");
+        let encrypted = dir.path().join("secrets.toml.enc");
+        fs::write(&plain, "api_token = \"s3cr3t-store-value\"\n")?;
+        cmd_capture!(
+            "openssl",
+            "enc",
+            "-aes-256-cbc",
+            "-pbkdf2",
+            "-in",
+            &plain,
+            "-out",
+            &encrypted,
+            "-pass",
+            "pass:testing"
+        )?;
+
+        let secrets = Secrets::new(
+            &indexmap! {
+                "token".to_string() => "store:api_token".to_string(),
+                "missing".to_string() => "store:nope".to_string(),
+            },
+            &Some(encrypted),
+            "pass:testing",
+        );
+        assert_eq!(
+            secrets.get("token")?,
+            Some("s3cr3t-store-value".to_string())
+        );
+        assert_eq!(
+            secrets.get("missing").unwrap_err().to_string(),
+            "secret missing: nope is not in the secrets file"
+        );
+        assert_eq!(redact("Bearer s3cr3t-store-value"), "Bearer ********");
+        Ok(())
+    }
+
+    #[test]
+    fn validate() {
+        let secrets = Secrets::new(
+            &indexmap! {
+                "a".to_string() => "store:a".to_string(),
+                "b".to_string() => "vault:b".to_string(),
+            },
+            &None,
+            "env:RJ_SECRETS_PASS",
+        );
+        assert_eq!(
+            secrets.validate(),
+            vec![
+                "secret a: store:a needs secrets_file to be set",
+                "secret b: unknown source: vault:b, expected file:, env: or store:",
+            ]
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use crate::errors::Error;
+use crate::jail::Jail;
+use anyhow::{bail, Result};
+use glob::Pattern;
+use indexmap::IndexMap;
+
+// Labels a jail must have, parsed from '--selector label=web,label=prod'
+#[derive(Debug, PartialEq)]
+pub struct Selector {
+    labels: Vec<String>,
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Selector> {
+        let mut labels = Vec::new();
+        for term in selector.split(',') {
+            match term.trim().splitn(2, '=').collect::<Vec<&str>>().as_slice() {
+                ["label", label] if !label.is_empty() => labels.push(label.to_string()),
+                _ => bail!(Error::Config(format!(
+                    "invalid selector: {}, expected label=NAME",
+                    term
+                ))),
+            }
+        }
+        Ok(Selector { labels })
+    }
+
+    pub fn matches(&self, labels: &[String]) -> bool {
+        self.labels.iter().all(|l| labels.contains(l))
+    }
+}
+
+// names containing these are treated as glob patterns
+fn is_pattern(name: &str) -> bool {
+    name.contains(&['*', '?', '['][..])
+}
+
+// Select jails by name or glob pattern, all jails when no names are given,
+// and keep the ones matching every selector.  Jails are returned in the order
+// of the jails map.
+pub fn select<'a, 'b>(
+    jails: &'b IndexMap<String, Jail<'a>>,
+    names: &[&str],
+    selectors: &[Selector],
+) -> Result<Vec<&'b Jail<'a>>> {
+    let mut patterns = Vec::new();
+
+    for name in names.iter() {
+        if is_pattern(name) {
+            let pattern = Pattern::new(name)?;
+            if !jails.keys().any(|j| pattern.matches(j)) {
+                bail!(Error::Config(format!("no jails match '{}'", name)));
+            }
+            patterns.push(pattern);
+        } else if jails.contains_key(*name) {
+            patterns.push(Pattern::new(&Pattern::escape(name))?);
+        } else {
+            bail!(Error::Config(format!("jail '{}' is not defined", name)));
+        }
+    }
+
+    let selected: Vec<&Jail> = jails
+        .values()
+        .filter(|j| names.is_empty() || patterns.iter().any(|p| p.matches(j.name())))
+        .filter(|j| selectors.iter().all(|s| s.matches(j.labels())))
+        .collect();
+
+    if selected.is_empty() && !selectors.is_empty() {
+        bail!(Error::Config("no jails match the selector".to_string()));
+    }
+
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+    use pretty_assertions::assert_eq;
+
+    fn names(jails: &[&Jail]) -> Vec<String> {
+        jails.iter().map(|j| j.name().to_owned()).collect()
+    }
+
+    #[test]
+    fn parse() -> Result<()> {
+        let s = Selector::parse("label=web, label=prod")?;
+        assert_eq!(s.labels, vec!["web", "prod"]);
+        assert!(s.matches(&["prod".to_owned(), "web".to_owned(), "x".to_owned()]));
+        assert!(!s.matches(&["web".to_owned()]));
+
+        for invalid in &["web", "label=", "name=web", "label=web,"] {
+            assert!(Selector::parse(invalid).is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn select_names() -> Result<()> {
+        let s = Settings::new("testdata/config.toml", false)?;
+        let jails = s.to_jails()?;
+
+        assert_eq!(names(&select(&jails, &[], &[])?).len(), jails.len());
+        assert_eq!(
+            names(&select(&jails, &["test2", "exec*", "test?", "base"], &[])?),
+            vec!["base", "test1", "test2", "exec_test", "exec_chroot_test"]
+        );
+
+        let err = select(&jails, &["nope"], &[]).unwrap_err();
+        assert_eq!(err.to_string(), "jail 'nope' is not defined");
+        let err = select(&jails, &["nope*"], &[]).unwrap_err();
+        assert_eq!(err.to_string(), "no jails match 'nope*'");
+        Ok(())
+    }
+
+    #[test]
+    fn select_labels() -> Result<()> {
+        let mut s = Settings::new("testdata/config.toml", false)?;
+        s.jail["test1"].labels = vec!["web".to_owned(), "prod".to_owned()];
+        s.jail["test2"].labels = vec!["web".to_owned()];
+        let jails = s.to_jails()?;
+
+        let web = Selector::parse("label=web")?;
+        assert_eq!(names(&select(&jails, &[], &[web])?), vec!["test1", "test2"]);
+
+        let web_prod = Selector::parse("label=web,label=prod")?;
+        assert_eq!(names(&select(&jails, &[], &[web_prod])?), vec!["test1"]);
+
+        let prod = Selector::parse("label=prod")?;
+        assert_eq!(names(&select(&jails, &["test*"], &[prod])?), vec!["test1"]);
+
+        let db = Selector::parse("label=db")?;
+        let err = select(&jails, &[], &[db]).unwrap_err();
+        assert_eq!(err.to_string(), "no jails match the selector");
+        Ok(())
+    }
+}
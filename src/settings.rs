@@ -1,10 +1,16 @@
-use anyhow::{bail, Result};
+use crate::errors::{Error, Kind};
+use crate::interpolate::Vars;
+use crate::retention::Retention;
+use crate::secret::Secrets;
+use anyhow::{anyhow, bail, Result};
+use glob::glob;
 use indexmap::IndexMap; // like HashMap but preserves insertion order
 use serde::Deserialize;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use toml;
 
+use super::zfs;
 use super::Jail;
 use super::Provisioner;
 use super::Source;
@@ -21,22 +27,74 @@ pub enum JailConfValue {
     Path(PathBuf),
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
+// A jail's settings once the templates it extends have been applied
+#[derive(Clone, Debug)]
 pub struct JailSettings {
     pub source: String,
-    #[serde(default = "default_true")]
     pub start: bool,
-    #[serde(default = "default_true")]
     pub enable: bool,
-    #[serde(default)]
     pub conf: IndexMap<String, JailConfValue>,
-    #[serde(default)]
+    // zfs properties set on the jail's dataset, e.g. quota = "20G"
+    pub zfs: IndexMap<String, String>,
     pub provisioners: Vec<String>,
-    #[serde(default)]
     pub volumes: Vec<String>,
-    #[serde(default)]
+    pub labels: Vec<String>,
+    pub depends_on: Vec<String>,
     pub stop_after: bool,
+    // the jail's own snapshot retention, the global one applies where it
+    // leaves a field unset
+    pub retention: Retention,
+    // set for the jails a definition with a count expands into
+    pub replica: Option<Replica>,
+}
+
+// One of the jails made from a [jail.*] table with a count, named
+// <definition>-<index> with index counting from 1
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replica {
+    pub of: String,
+    pub index: u32,
+}
+
+// How a table's lists are combined with the ones from the templates it extends
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListMerge {
+    Append,
+    Replace,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Extends {
+    One(String),
+    Many(Vec<String>),
+}
+
+// A [jail_template.*] or [jail.*] table as written.  Fields that aren't set
+// come from the templates it extends: conf and zfs are merged key by key,
+// lists are appended to or replace the templates' lists and other values
+// override them.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JailTemplate {
+    pub extends: Option<Extends>,
+    pub list_merge: Option<ListMerge>,
+    pub source: Option<String>,
+    pub start: Option<bool>,
+    pub enable: Option<bool>,
+    #[serde(default)]
+    pub conf: IndexMap<String, JailConfValue>,
+    #[serde(default)]
+    pub zfs: IndexMap<String, String>,
+    pub provisioners: Option<Vec<String>>,
+    pub volumes: Option<Vec<String>>,
+    pub labels: Option<Vec<String>>,
+    pub depends_on: Option<Vec<String>>,
+    pub stop_after: Option<bool>,
+    #[serde(default)]
+    pub retention: Retention,
+    pub count: Option<u32>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -46,7 +104,31 @@ pub struct Settings {
     pub jails_mountpoint: PathBuf,
     #[serde(default)]
     pub jail_conf_defaults: IndexMap<String, JailConfValue>,
+    // glob patterns of files to merge in, relative to the config file
+    #[serde(default)]
+    pub include: Vec<String>,
+    // user defined variables for config values
+    #[serde(default)]
+    pub vars: IndexMap<String, String>,
+    // secrets for config values and where to read them from
+    #[serde(default)]
+    pub secret: IndexMap<String, String>,
+    pub secrets_file: Option<PathBuf>,
+    #[serde(default = "default_secrets_pass")]
+    pub secrets_pass: String,
+    #[serde(skip)]
+    pub secrets: Secrets,
+    #[serde(default)]
+    pub jail_template: IndexMap<String, JailTemplate>,
+    // the [jail.*] tables, resolved into jail when loading
+    #[serde(default, rename = "jail")]
+    jail_tables: IndexMap<String, JailTemplate>,
+    #[serde(skip)]
     pub jail: IndexMap<String, JailSettings>,
+    // the resolved tables of the jails with a count
+    #[serde(skip)]
+    pub replicated: IndexMap<String, JailTemplate>,
+    #[serde(default)]
     pub source: IndexMap<String, Source>,
     #[serde(default)]
     pub provisioner: IndexMap<String, Provisioner>,
@@ -54,88 +136,826 @@ pub struct Settings {
     pub volume: IndexMap<String, Volume>,
     #[serde(default)] // false
     pub noop: bool,
+    #[serde(default = "default_lock_dir")]
+    pub lock_dir: PathBuf,
+    // lock each jail rather than the whole host
+    #[serde(default)]
+    pub lock_per_jail: bool,
+    // hosts to manage over ssh, by name
+    #[serde(default)]
+    pub hosts: IndexMap<String, Host>,
+    // seconds a command may run before it's killed, unless a provisioner
+    // sets its own timeout
+    pub command_timeout: Option<u64>,
+    // JSON lines log of the commands run and files changed
+    #[serde(default = "default_audit_log")]
+    pub audit_log: PathBuf,
+    // which snapshots prune keeps, jails can override it
+    #[serde(default)]
+    pub retention: Retention,
+}
+
+// A host managed over ssh
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Host {
+    // [user@]host or an ssh:// URI
+    pub address: String,
+    // extra options for ssh, e.g. [ "-i", "/path/to/key" ]
+    #[serde(default)]
+    pub ssh_options: Vec<String>,
+}
+
+// The parts of the config that included files can define
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Include {
+    #[serde(default)]
+    vars: IndexMap<String, String>,
+    #[serde(default)]
+    secret: IndexMap<String, String>,
+    #[serde(default)]
+    jail_template: IndexMap<String, JailTemplate>,
+    #[serde(default)]
+    jail: IndexMap<String, JailTemplate>,
+    #[serde(default)]
+    source: IndexMap<String, Source>,
+    #[serde(default)]
+    provisioner: IndexMap<String, Provisioner>,
+    #[serde(default)]
+    volume: IndexMap<String, Volume>,
 }
 
-fn default_true() -> bool {
-    true
+fn default_lock_dir() -> PathBuf {
+    PathBuf::from("/var/run/rj")
+}
+
+fn default_audit_log() -> PathBuf {
+    PathBuf::from("/var/log/rj/audit.log")
+}
+
+fn default_secrets_pass() -> String {
+    "env:RJ_SECRETS_PASS".to_string()
+}
+
+impl JailTemplate {
+    fn extends(&self) -> Vec<&String> {
+        match &self.extends {
+            Some(Extends::One(name)) => vec![name],
+            Some(Extends::Many(names)) => names.iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Apply other on top of self
+    fn merge(&self, other: &JailTemplate) -> JailTemplate {
+        let list = |base: &Option<Vec<String>>, over: &Option<Vec<String>>| match (base, over) {
+            (Some(base), Some(over)) if other.list_merge != Some(ListMerge::Replace) => {
+                let mut merged = base.to_owned();
+                for item in over.iter() {
+                    if !merged.contains(item) {
+                        merged.push(item.to_owned());
+                    }
+                }
+                Some(merged)
+            },
+            _ => over.to_owned().or_else(|| base.to_owned()),
+        };
+
+        let mut conf = self.conf.to_owned();
+        for (key, value) in other.conf.iter() {
+            conf.insert(key.to_owned(), value.to_owned());
+        }
+
+        let mut zfs = self.zfs.to_owned();
+        for (key, value) in other.zfs.iter() {
+            zfs.insert(key.to_owned(), value.to_owned());
+        }
+
+        JailTemplate {
+            extends: None,
+            list_merge: None,
+            source: other.source.to_owned().or_else(|| self.source.to_owned()),
+            start: other.start.or(self.start),
+            enable: other.enable.or(self.enable),
+            conf,
+            zfs,
+            provisioners: list(&self.provisioners, &other.provisioners),
+            volumes: list(&self.volumes, &other.volumes),
+            labels: list(&self.labels, &other.labels),
+            depends_on: list(&self.depends_on, &other.depends_on),
+            stop_after: other.stop_after.or(self.stop_after),
+            retention: self.retention.merge(&other.retention),
+            count: other.count.or(self.count),
+        }
+    }
+
+    fn to_replica(&self, definition: &str, index: u32) -> Result<JailSettings> {
+        let mut settings = self.to_settings(&format!("{}-{}", definition, index))?;
+        settings.replica = Some(Replica {
+            of: definition.to_owned(),
+            index,
+        });
+        Ok(settings)
+    }
+
+    fn to_settings(&self, jail_name: &str) -> Result<JailSettings> {
+        let source = match &self.source {
+            Some(source) => source.to_owned(),
+            None => bail!(Error::Config(format!("{}: no source set", jail_name))),
+        };
+        Ok(JailSettings {
+            source,
+            start: self.start.unwrap_or(true),
+            enable: self.enable.unwrap_or(true),
+            conf: self.conf.to_owned(),
+            zfs: self.zfs.to_owned(),
+            provisioners: self.provisioners.to_owned().unwrap_or_default(),
+            volumes: self.volumes.to_owned().unwrap_or_default(),
+            labels: self.labels.to_owned().unwrap_or_default(),
+            depends_on: self.depends_on.to_owned().unwrap_or_default(),
+            stop_after: self.stop_after.unwrap_or(false),
+            retention: self.retention.to_owned(),
+            replica: None,
+        })
+    }
+}
+
+// add the items from an included file, names must be unique across files
+fn merge<'a, T>(
+    kind: &str,
+    items: &mut IndexMap<String, T>,
+    from: IndexMap<String, T>,
+    file: &'a Path,
+    origins: &mut IndexMap<String, &'a Path>,
+) -> Result<()> {
+    for (name, item) in from {
+        let key = format!("{} '{}'", kind, name);
+        if let Some(other) = origins.get(&key) {
+            bail!(Error::Config(format!(
+                "{} is defined in both {} and {}",
+                key,
+                other.display(),
+                file.display()
+            )));
+        }
+        origins.insert(key, file);
+        items.insert(name, item);
+    }
+    Ok(())
+}
+
+// Jail names end up in jail.conf, rc.conf and file names.  Numeric names
+// would be taken for jail ids.
+fn valid_jail_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.chars().all(|c| c.is_ascii_digit())
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// A jail's settings, provisioners and volumes with variables expanded
+struct Expanded {
+    settings: JailSettings,
+    conf_defaults: IndexMap<String, JailConfValue>,
+    provisioners: Vec<Provisioner>,
+    volumes: IndexMap<String, Volume>,
+    // undefined variables and the keys they were found in
+    errors: Vec<String>,
+}
+
+impl JailConfValue {
+    fn expand(&mut self, key: &str, vars: &Vars, errors: &mut Vec<String>) {
+        match self {
+            JailConfValue::String(v) => vars.expand_string(key, v, errors),
+            JailConfValue::Vec(v) => {
+                for item in v.iter_mut() {
+                    vars.expand_string(key, item, errors);
+                }
+            },
+            JailConfValue::Path(v) => vars.expand_path(key, v, errors),
+            JailConfValue::Bool(_) | JailConfValue::Int(_) => (),
+        }
+    }
+
+    // the value as strings, used for checking values across jails
+    fn to_strings(&self) -> Vec<String> {
+        match self {
+            JailConfValue::String(v) => vec![v.to_owned()],
+            JailConfValue::Bool(v) => vec![v.to_string()],
+            JailConfValue::Vec(v) => v.to_owned(),
+            JailConfValue::Int(v) => vec![v.to_string()],
+            JailConfValue::Path(v) => vec![v.display().to_string()],
+        }
+    }
 }
 
 #[allow(dead_code)]
 impl Settings {
     pub fn new(config_file: &str, noop: bool) -> Result<Self> {
+        let settings = Self::load(config_file, noop)?;
+
+        for source in settings.source.values() {
+            // Validate source
+            source.validate().kind(Error::Config)?;
+        }
+
+        if let Some(e) = settings.validate_provisioners().first() {
+            bail!(Error::Config(e.to_owned()));
+        }
+
+        Ok(settings)
+    }
+
+    // Parse the config file without validating it
+    pub fn load(config_file: &str, noop: bool) -> Result<Self> {
+        Self::parse(config_file, noop).kind(Error::Config)
+    }
+
+    fn parse(config_file: &str, noop: bool) -> Result<Self> {
         let mut settings: Settings = toml::from_str(&fs::read_to_string(config_file)?)?;
 
         settings.noop = noop;
+        settings.merge_includes(Path::new(config_file))?;
+        settings.resolve_templates()?;
+        settings.secrets = Secrets::new(
+            &settings.secret,
+            &settings.secrets_file,
+            &settings.secrets_pass,
+        );
 
         for (s_name, source) in settings.source.iter_mut() {
             // Set source name
             source.name(s_name);
-            // Validate source
-            source.validate()?;
         }
 
         for (p_name, provisioner) in settings.provisioner.iter_mut() {
             // Set provisioner name
             provisioner.name(p_name);
-            // Validate provisioner
-            provisioner.validate()?;
         }
 
         Ok(settings)
     }
 
-    pub fn to_jails(&self) -> Result<IndexMap<String, Jail>> {
+    // Merge in the files matching the include patterns, then the *.toml files
+    // in the rj.d directory next to the config file.  Each pattern's matches
+    // are merged in name order.
+    fn merge_includes(&mut self, config_file: &Path) -> Result<()> {
+        let dir = config_file.parent().unwrap_or_else(|| Path::new(""));
+        let mut patterns: Vec<PathBuf> = self.include.iter().map(|p| dir.join(p)).collect();
+        if dir.join("rj.d").is_dir() {
+            patterns.push(dir.join("rj.d/*.toml"));
+        }
+
+        let mut files: Vec<PathBuf> = Vec::new();
+        for pattern in patterns.iter() {
+            let mut matched = glob(&pattern.to_string_lossy())?.collect::<Result<Vec<_>, _>>()?;
+            matched.sort();
+            for file in matched {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+
+        // where each name was defined, for reporting duplicates
+        let mut origins: IndexMap<String, &Path> = IndexMap::new();
+        let names = self
+            .jail_tables
+            .keys()
+            .map(|n| ("jail", n))
+            .chain(self.jail_template.keys().map(|n| ("jail template", n)))
+            .chain(self.source.keys().map(|n| ("source", n)))
+            .chain(self.provisioner.keys().map(|n| ("provisioner", n)))
+            .chain(self.volume.keys().map(|n| ("volume", n)))
+            .chain(self.vars.keys().map(|n| ("var", n)))
+            .chain(self.secret.keys().map(|n| ("secret", n)));
+        for (kind, name) in names {
+            origins.insert(format!("{} '{}'", kind, name), config_file);
+        }
+
+        for file in files.iter() {
+            let include: Include = toml::from_str(&fs::read_to_string(file)?)
+                .map_err(|e| anyhow!("{}: {}", file.display(), e))?;
+            merge(
+                "jail",
+                &mut self.jail_tables,
+                include.jail,
+                file,
+                &mut origins,
+            )?;
+            merge(
+                "jail template",
+                &mut self.jail_template,
+                include.jail_template,
+                file,
+                &mut origins,
+            )?;
+            merge(
+                "source",
+                &mut self.source,
+                include.source,
+                file,
+                &mut origins,
+            )?;
+            merge(
+                "provisioner",
+                &mut self.provisioner,
+                include.provisioner,
+                file,
+                &mut origins,
+            )?;
+            merge(
+                "volume",
+                &mut self.volume,
+                include.volume,
+                file,
+                &mut origins,
+            )?;
+            merge("var", &mut self.vars, include.vars, file, &mut origins)?;
+            merge(
+                "secret",
+                &mut self.secret,
+                include.secret,
+                file,
+                &mut origins,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // Apply the templates each jail extends to make its settings
+    fn resolve_templates(&mut self) -> Result<()> {
         let mut jails = IndexMap::new();
+        let mut replicated = IndexMap::new();
+        for (jail_name, table) in self.jail_tables.iter() {
+            let resolved = self
+                .resolve(table, &mut Vec::new())
+                .map_err(|e| anyhow!("{}: {}", jail_name, e))?;
+            match resolved.count {
+                Some(count) => {
+                    for index in 1..=count {
+                        let name = format!("{}-{}", jail_name, index);
+                        if self.jail_tables.contains_key(&name) {
+                            bail!(Error::Config(format!(
+                                "{}: replica {} is also defined as a jail",
+                                jail_name, name
+                            )));
+                        }
+                        jails.insert(name.to_owned(), resolved.to_replica(jail_name, index)?);
+                    }
+                    replicated.insert(jail_name.to_owned(), resolved);
+                },
+                None => {
+                    jails.insert(jail_name.to_owned(), resolved.to_settings(jail_name)?);
+                },
+            }
+        }
+        self.jail = jails;
+        self.replicated = replicated;
+        Ok(())
+    }
+
+    // Merge a table onto the templates it extends, in order, path holds the
+    // templates being resolved
+    fn resolve<'a>(
+        &'a self,
+        table: &JailTemplate,
+        path: &mut Vec<&'a String>,
+    ) -> Result<JailTemplate> {
+        let mut resolved = JailTemplate::default();
+        for name in table.extends() {
+            let (name, template) = match self.jail_template.get_full(name) {
+                Some((_, name, template)) => (name, template),
+                None => bail!(Error::Config(format!("unknown template: {}", name))),
+            };
+            if path.contains(&name) {
+                let mut cycle: Vec<&str> = path.iter().map(|n| n.as_str()).collect();
+                cycle.push(name);
+                bail!(Error::Config(format!(
+                    "template cycle: {}",
+                    cycle.join(" -> ")
+                )));
+            }
+            path.push(name);
+            let parent = self.resolve(template, path)?;
+            path.pop();
+            resolved = resolved.merge(&parent);
+        }
+        Ok(resolved.merge(table))
+    }
+
+    // Run every check that doesn't need the host and return all the errors
+    // found rather than stopping at the first one.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        for (s_name, source) in self.source.iter() {
+            if let Err(e) = source.validate() {
+                errors.push(e.to_string());
+            }
+            // clone sources must be one of the jails managed here
+            if let Source::ZfsClone(src) = source {
+                if !self
+                    .jail
+                    .keys()
+                    .any(|j| src.path.starts_with(self.jails_dataset.join(j)))
+                {
+                    errors.push(format!(
+                        "clone source {}, path: {} is not a jail defined in the config",
+                        s_name,
+                        src.path.display()
+                    ));
+                }
+            }
+        }
+
+        errors.extend(self.validate_provisioners());
+        if let Err(e) = self.retention.validate() {
+            errors.push(e.to_string());
+        }
+        errors.extend(self.secrets.validate());
+
+        // values of these jail.conf keys must be unique across jails
+        let mut hostnames: IndexMap<String, &String> = IndexMap::new();
+        let mut addrs: IndexMap<String, &String> = IndexMap::new();
+        let mut deps_known = true;
+
+        for (jail_name, jail_settings) in self.jail.iter() {
+            let expanded = self.expand_jail(jail_name, jail_settings);
+            errors.extend(expanded.errors.iter().cloned());
+
+            if !valid_jail_name(jail_name) {
+                errors.push(format!("{}: invalid jail name", jail_name));
+            }
+
+            if !self.source.contains_key(&jail_settings.source) {
+                errors.push(format!(
+                    "{}: unknown source: {}",
+                    jail_name, jail_settings.source
+                ));
+            }
 
-        for (jail_name, jail_settings) in &mut self.jail.iter() {
-            if !&self.source.contains_key(&jail_settings.source) {
-                bail!("{}: unknown source: {}", jail_name, jail_settings.source);
+            if let Err(e) = self.dependencies(jail_name) {
+                errors.push(e.to_string());
+                deps_known = false;
             }
 
-            // gather jail provisioners
-            let mut provisioners = Vec::new();
             for p in jail_settings.provisioners.iter() {
-                // error if the provisioner is not defined
-                if !&self.provisioner.contains_key(p) {
-                    bail!("{}: unknown provisioner: {}", jail_name, p);
+                if !self.provisioner.contains_key(p) {
+                    errors.push(format!("{}: unknown provisioner: {}", jail_name, p));
+                }
+            }
+
+            if let Err(e) = jail_settings.retention.validate() {
+                errors.push(format!("{}: {}", jail_name, e));
+            }
+
+            for (property, value) in expanded.settings.zfs.iter() {
+                if let Err(e) = zfs::valid_property(property, value) {
+                    errors.push(format!("{}: {}", jail_name, e));
                 }
-                provisioners.push(&self.provisioner[p]);
             }
 
-            // gather volumes
-            let mut volumes = vec![];
+            let mut mountpoints: IndexMap<&str, &String> = IndexMap::new();
             for v in jail_settings.volumes.iter() {
-                // error if the volume is not defined
-                if !&self.volume.contains_key(v) {
-                    bail!("{}: unknown volume: {}", jail_name, v);
+                match expanded.volumes.get(v) {
+                    Some(volume) => {
+                        let mountpoint = volume.mountpoint.trim_end_matches('/');
+                        if let Some(other) = mountpoints.insert(mountpoint, v) {
+                            errors.push(format!(
+                                "{}: volumes {} and {} have the same mountpoint: {}",
+                                jail_name, other, v, volume.mountpoint
+                            ));
+                        }
+                    },
+                    None => errors.push(format!("{}: unknown volume: {}", jail_name, v)),
                 }
-                volumes.push(&self.volume[v]);
             }
 
-            // make jails
-            let jail = Jail::new(
-                jail_name,
-                &self.jails_mountpoint,
-                &self.jails_dataset,
-                // jail source
-                &self.source[&jail_settings.source],
-                &jail_settings,
-                &self.jail_conf_defaults,
-                provisioners,
-                &self.noop,
-                volumes,
-            );
+            for (key, value) in expanded.settings.conf.iter() {
+                let key = key.replacen("_", ".", 1);
+                let (seen, values) = match key.as_str() {
+                    "host.hostname" => (&mut hostnames, value.to_strings()),
+                    // drop the interface and netmask from addresses
+                    "ip4.addr" | "ip6.addr" => (
+                        &mut addrs,
+                        value
+                            .to_strings()
+                            .iter()
+                            .map(|a| {
+                                let a = a.rsplit('|').next().unwrap();
+                                a.split('/').next().unwrap().to_owned()
+                            })
+                            .collect(),
+                    ),
+                    _ => continue,
+                };
+                for v in values {
+                    match seen.get(&v) {
+                        Some(other) if *other != jail_name => errors.push(format!(
+                            "{}: {} {} is already used by jail: {}",
+                            jail_name, key, v, other
+                        )),
+                        _ => {
+                            seen.insert(v, jail_name);
+                        },
+                    }
+                }
+            }
+        }
+
+        // cycles can only be found once every dependency is known
+        if deps_known {
+            if let Err(e) = self.order() {
+                errors.push(e.to_string());
+            }
+        }
+
+        errors
+    }
+
+    // Validate provisioners with the values each jail expands them to, or as
+    // they are if no jail uses them
+    fn validate_provisioners(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (p_name, provisioner) in self.provisioner.iter() {
+            let mut expanded = Vec::new();
+            for (jail_name, jail_settings) in self.jail.iter() {
+                if !jail_settings.provisioners.contains(p_name) {
+                    continue;
+                }
+                let mountpoint = self.jails_mountpoint.join(jail_name);
+                let index = jail_settings.replica.as_ref().map(|r| r.index);
+                let vars = Vars::new(jail_name, &mountpoint, index, &self.vars, &self.secrets);
+                let mut p = provisioner.to_owned();
+                // undefined variables are reported with the jail
+                let mut expand_errors = Vec::new();
+                p.expand(&vars, &mut expand_errors);
+                if expand_errors.is_empty() {
+                    expanded.push(p);
+                }
+            }
+            if !self.jail.values().any(|j| j.provisioners.contains(p_name)) {
+                expanded.push(provisioner.to_owned());
+            }
+
+            for p in expanded.iter() {
+                if let Err(e) = p.validate() {
+                    let e = e.to_string();
+                    if !errors.contains(&e) {
+                        errors.push(e);
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    // Expand variables in the settings, jail.conf defaults, provisioners and
+    // volumes of a jail.  Unknown provisioners and volumes are left out.
+    fn expand_jail(&self, jail_name: &str, jail_settings: &JailSettings) -> Expanded {
+        let mountpoint = self.jails_mountpoint.join(jail_name);
+        let index = jail_settings.replica.as_ref().map(|r| r.index);
+        let vars = Vars::new(jail_name, &mountpoint, index, &self.vars, &self.secrets);
+        let mut errors = Vec::new();
+
+        let mut settings = jail_settings.to_owned();
+        for (key, value) in settings.conf.iter_mut() {
+            value.expand(&format!("conf.{}", key), &vars, &mut errors);
+        }
+        for (key, value) in settings.zfs.iter_mut() {
+            vars.expand_string(&format!("zfs.{}", key), value, &mut errors);
+        }
+
+        let mut conf_defaults = self.jail_conf_defaults.to_owned();
+        for (key, value) in conf_defaults.iter_mut() {
+            value.expand(&format!("jail_conf_defaults.{}", key), &vars, &mut errors);
+        }
+
+        let mut provisioners = Vec::new();
+        for p in settings.provisioners.iter() {
+            if let Some(provisioner) = self.provisioner.get(p) {
+                let mut provisioner = provisioner.to_owned();
+                provisioner.expand(&vars, &mut errors);
+                provisioners.push(provisioner);
+            }
+        }
+
+        let mut volumes = IndexMap::new();
+        for v in settings.volumes.iter() {
+            if let Some(volume) = self.volume.get(v) {
+                let mut volume = volume.to_owned();
+                volume.expand(v, &vars, &mut errors);
+                volumes.insert(v.to_owned(), volume);
+            }
+        }
+
+        Expanded {
+            settings,
+            conf_defaults,
+            provisioners,
+            volumes,
+            errors: errors
+                .iter()
+                .map(|e| format!("{}: {}", jail_name, e))
+                .collect(),
+        }
+    }
+
+    // Jails this jail depends on, the ones in depends_on and the jail its
+    // clone source points at
+    pub fn dependencies(&self, jail_name: &str) -> Result<Vec<&String>> {
+        let jail_settings = &self.jail[jail_name];
+        let mut deps = Vec::new();
+
+        for dep in jail_settings.depends_on.iter() {
+            if self.replicated.contains_key(dep) {
+                // all of the replicas
+                deps.extend(self.jail.iter().filter_map(|(name, s)| match &s.replica {
+                    Some(replica) if &replica.of == dep => Some(name),
+                    _ => None,
+                }));
+                continue;
+            }
+            if !self.jail.contains_key(dep) {
+                bail!(Error::Config(format!(
+                    "{}: unknown dependency: {}",
+                    jail_name, dep
+                )));
+            }
+            deps.push(dep);
+        }
+
+        if let Some(Source::ZfsClone(src)) = self.source.get(&jail_settings.source) {
+            for name in self.jail.keys() {
+                if name != jail_name
+                    && src.path.starts_with(self.jails_dataset.join(name))
+                    && !deps.contains(&name)
+                {
+                    deps.push(name);
+                }
+            }
+        }
+
+        Ok(deps)
+    }
+
+    // Jail names ordered so that each jail comes after the jails it depends
+    // on, otherwise in config order
+    pub fn order(&self) -> Result<Vec<&String>> {
+        let mut order = Vec::new();
+        for jail_name in self.jail.keys() {
+            self.visit(jail_name, &mut Vec::new(), &mut order)?;
+        }
+        Ok(order)
+    }
+
+    // depth first walk of the dependencies, path holds the jails being visited
+    fn visit<'a>(
+        &'a self,
+        jail_name: &'a String,
+        path: &mut Vec<&'a String>,
+        order: &mut Vec<&'a String>,
+    ) -> Result<()> {
+        if order.contains(&jail_name) {
+            return Ok(());
+        }
+        if let Some(pos) = path.iter().position(|n| *n == jail_name) {
+            let mut cycle: Vec<&str> = path[pos..].iter().map(|n| n.as_str()).collect();
+            cycle.push(jail_name);
+            bail!(Error::Config(format!(
+                "dependency cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
+
+        path.push(jail_name);
+        for dep in self.dependencies(jail_name)? {
+            self.visit(dep, path, order)?;
+        }
+        path.pop();
+
+        order.push(jail_name);
+        Ok(())
+    }
+
+    // Make jails in dependency order
+    pub fn to_jails(&self) -> Result<IndexMap<String, Jail>> {
+        let mut jails = IndexMap::new();
+
+        for jail_name in self.order()? {
+            let jail = self.make_jail(jail_name, &self.jail[jail_name])?;
             jails.insert(jail_name.to_owned(), jail);
         }
         Ok(jails)
     }
+
+    // Jails left over from lowering the count of the given definitions, found
+    // from the datasets below the jails dataset
+    pub fn surplus(&self, definitions: &[&String]) -> Result<Vec<Jail<'_>>> {
+        let mut jails = Vec::new();
+        if definitions.is_empty() {
+            return Ok(jails);
+        }
+
+        let existing =
+            zfs::DataSet::in_root(&self.jails_dataset, &self.jails_dataset).children()?;
+        for definition in definitions.iter() {
+            let resolved = &self.replicated[*definition];
+            let count = resolved.count.unwrap_or(0);
+            let mut indexes: Vec<u32> = existing
+                .iter()
+                .filter_map(|name| {
+                    let index: u32 = name
+                        .strip_prefix(&format!("{}-", definition))?
+                        .parse()
+                        .ok()?;
+                    // skip names like worker-01 that aren't replica names
+                    if *name == format!("{}-{}", definition, index) {
+                        Some(index)
+                    } else {
+                        None
+                    }
+                })
+                .filter(|index| *index > count)
+                .collect();
+            indexes.sort_unstable();
+
+            for index in indexes {
+                let name = format!("{}-{}", definition, index);
+                if self.jail.contains_key(&name) {
+                    continue;
+                }
+                let jail_settings = resolved.to_replica(definition, index)?;
+                jails.push(self.make_jail(&name, &jail_settings)?);
+            }
+        }
+        Ok(jails)
+    }
+
+    fn make_jail(&self, jail_name: &str, jail_settings: &JailSettings) -> Result<Jail<'_>> {
+        if !&self.source.contains_key(&jail_settings.source) {
+            bail!(Error::Config(format!(
+                "{}: unknown source: {}",
+                jail_name, jail_settings.source
+            )));
+        }
+
+        // error if a provisioner is not defined
+        for p in jail_settings.provisioners.iter() {
+            if !&self.provisioner.contains_key(p) {
+                bail!(Error::Config(format!(
+                    "{}: unknown provisioner: {}",
+                    jail_name, p
+                )));
+            }
+        }
+
+        // error if a volume is not defined
+        for v in jail_settings.volumes.iter() {
+            if !&self.volume.contains_key(v) {
+                bail!(Error::Config(format!(
+                    "{}: unknown volume: {}",
+                    jail_name, v
+                )));
+            }
+        }
+
+        let expanded = self.expand_jail(jail_name, jail_settings);
+        if let Some(e) = expanded.errors.first() {
+            bail!(Error::Config(e.to_owned()));
+        }
+        let mut settings = expanded.settings;
+        settings.retention = self.retention.merge(&settings.retention);
+
+        Ok(Jail::new(
+            jail_name,
+            &self.jails_mountpoint,
+            &self.jails_dataset,
+            // jail source
+            &self.source[&jail_settings.source],
+            settings,
+            expanded.conf_defaults,
+            expanded.provisioners,
+            &self.noop,
+            expanded.volumes.into_iter().map(|(_, v)| v).collect(),
+            &self.lock_dir,
+        ))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::provisioner::Provisioner;
+    use indexmap::indexmap;
+    use indoc::indoc;
     use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
 
     #[test]
     fn deserialize() {
@@ -241,8 +1061,8 @@ mod tests {
 
         let err = s.to_jails().unwrap_err();
         assert_eq!(
-            err.downcast::<String>().unwrap(),
-            "test1: unknown source: nope".to_string()
+            err.downcast::<Error>().unwrap(),
+            Error::Config("test1: unknown source: nope".to_string())
         )
     }
 
@@ -253,8 +1073,462 @@ mod tests {
 
         let err = s.to_jails().unwrap_err();
         assert_eq!(
-            err.downcast::<String>().unwrap(),
-            "test1: unknown provisioner: nope".to_string()
+            err.downcast::<Error>().unwrap(),
+            Error::Config("test1: unknown provisioner: nope".to_string())
         )
     }
+
+    #[test]
+    fn validate() -> Result<()> {
+        let s = Settings::load("testdata/config.toml", false)?;
+        assert_eq!(s.validate(), Vec::<String>::new());
+
+        let s = Settings::load("testdata/invalid.toml", false)?;
+        assert_eq!(
+            s.validate(),
+            vec![
+                "clone source elsewhere, path: zroot/templates/base is not a jail defined in the config",
+                "file provisioner missing, invalid source: testdata/provisioners/missing.txt",
+                "web1: unknown source: nope",
+                "web1: unknown provisioner: nope",
+                "web1: zfs property mountpoint is set by rj",
+                "web1: volumes a and b have the same mountpoint: /mnt/",
+                "web1: unknown volume: nope",
+                "web2: retention keep_last must be at least 1",
+                "web2: host.hostname web.jail is already used by jail: web1",
+                "web2: ip4.addr 10.11.11.2 is already used by jail: web1",
+                "bad.name: invalid jail name",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn jail_names() {
+        for name in &["test1", "web_2", "web-2", "a"] {
+            assert!(valid_jail_name(name), "{}", name);
+        }
+        for name in &["", "123", "bad.name", "with space", "-x", "a/b"] {
+            assert!(!valid_jail_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn order() -> Result<()> {
+        let mut s = Settings::new("testdata/config.toml", false)?;
+        // test1 is cloned from base
+        assert_eq!(s.dependencies("test1")?, vec!["base"]);
+        assert_eq!(s.dependencies("base")?, Vec::<&String>::new());
+
+        s.jail["base"].depends_on = vec!["clone_test".to_owned(), "stopped".to_owned()];
+        s.jail["clone_test"].source = "freebsd12".to_owned();
+        s.jail["stopped"].source = "freebsd12".to_owned();
+        s.jail["stopped"].depends_on = vec!["clone_test".to_owned()];
+        let order: Vec<&str> = s.order()?.iter().map(|n| n.as_str()).collect();
+        assert_eq!(&order[..4], &["clone_test", "stopped", "base", "test1"]);
+
+        let jails = s.to_jails()?;
+        assert_eq!(jails.get_index(0).unwrap().0, "clone_test");
+        Ok(())
+    }
+
+    #[test]
+    fn dependency_errors() -> Result<()> {
+        let mut s = Settings::new("testdata/config.toml", false)?;
+        s.jail["test2"].depends_on = vec!["nope".to_owned()];
+        let err = s.to_jails().unwrap_err();
+        assert_eq!(err.to_string(), "test2: unknown dependency: nope");
+        assert_eq!(s.validate(), vec!["test2: unknown dependency: nope"]);
+
+        // base -> test2 -> test1 -> base
+        s.jail["base"].depends_on = vec!["test2".to_owned()];
+        s.jail["test2"].depends_on = vec!["test1".to_owned()];
+        let err = s.to_jails().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "dependency cycle: base -> test2 -> test1 -> base"
+        );
+        assert_eq!(
+            s.validate(),
+            vec!["dependency cycle: base -> test2 -> test1 -> base"]
+        );
+        Ok(())
+    }
+
+    // write config files into a temporary directory, returns the main file
+    fn write_config(dir: &Path, files: &[(&str, &str)]) -> Result<String> {
+        for (name, content) in files.iter() {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, content)?;
+        }
+        Ok(dir.join(files[0].0).to_string_lossy().into_owned())
+    }
+
+    const MAIN: &str = r#"
+        jails_dataset = "zroot/jails"
+        jails_mountpoint = "/jails"
+        include = [ "services/*.toml" ]
+
+        [source.freebsd12]
+        type = "freebsd"
+        release = "12.0-RELEASE"
+        mirror = "ftp.uk.freebsd.org"
+        dists = [ "base" ]
+
+        [jail.base]
+        source = "freebsd12"
+    "#;
+
+    #[test]
+    fn include() -> Result<()> {
+        let dir = TempDir::new()?;
+        let config = write_config(
+            dir.path(),
+            &[
+                ("rj.toml", MAIN),
+                (
+                    "services/web.toml",
+                    "[source.base]\ntype = \"clone\"\npath = \"zroot/jails/base\"\n\
+                     [jail.web]\nsource = \"base\"\n",
+                ),
+                (
+                    "services/db.toml",
+                    "[jail.db]\nsource = \"base\"\nvolumes = [ \"data\" ]\n\
+                     [volume.data]\ndevice = \"/data\"\nmountpoint = \"/data\"\nfs_type = \"nullfs\"\n",
+                ),
+                ("rj.d/dns.toml", "[jail.dns]\nsource = \"base\"\n"),
+                ("rj.d/notes.txt", "not a config file"),
+            ],
+        )?;
+
+        let s = Settings::new(&config, false)?;
+        let jails: Vec<&String> = s.jail.keys().collect();
+        assert_eq!(jails, vec!["base", "db", "web", "dns"]);
+        if let Source::ZfsClone(src) = &s.source["base"] {
+            assert_eq!(src.name, "base");
+        }
+        assert_eq!(s.volume["data"].mountpoint, "/data");
+        assert_eq!(s.validate(), Vec::<String>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn include_duplicate() -> Result<()> {
+        let dir = TempDir::new()?;
+        let config = write_config(
+            dir.path(),
+            &[
+                ("rj.toml", MAIN),
+                ("services/a.toml", "[jail.web]\nsource = \"freebsd12\"\n"),
+                ("rj.d/b.toml", "[jail.web]\nsource = \"freebsd12\"\n"),
+            ],
+        )?;
+        let err = Settings::new(&config, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "jail 'web' is defined in both {0}/services/a.toml and {0}/rj.d/b.toml",
+                dir.path().display()
+            )
+        );
+
+        fs::write(
+            dir.path().join("rj.d/b.toml"),
+            "[jail.base]\nsource = \"freebsd12\"\n",
+        )?;
+        let err = Settings::new(&config, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "jail 'base' is defined in both {0}/rj.toml and {0}/rj.d/b.toml",
+                dir.path().display()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn templates() -> Result<()> {
+        let dir = TempDir::new()?;
+        let templates = indoc!(
+            r#"
+            [jail_template.base]
+            source = "freebsd12"
+            provisioners = [ "a" ]
+            labels = [ "base" ]
+            retention = { keep_last = 3 }
+            [jail_template.base.conf]
+            host_hostname = "base"
+            allow_mount = true
+            [jail_template.base.zfs]
+            quota = "10G"
+            compression = "lz4"
+
+            [jail_template.web]
+            extends = "base"
+            provisioners = [ "b" ]
+            [jail_template.web.conf]
+            host_hostname = "web"
+
+            [jail_template.prod]
+            enable = false
+            labels = [ "prod" ]
+
+            [jail.web1]
+            extends = [ "web", "prod" ]
+            provisioners = [ "a", "c" ]
+            retention = { keep_newer_than = "7d" }
+            [jail.web1.zfs]
+            quota = "20G"
+
+            [jail.web2]
+            extends = "web"
+            list_merge = "replace"
+            source = "other"
+            start = false
+            provisioners = [ "c" ]
+            [jail.web2.conf]
+            allow_mount = false
+            "#
+        );
+        let config = write_config(
+            dir.path(),
+            &[("rj.toml", MAIN), ("services/web.toml", templates)],
+        )?;
+        let s = Settings::load(&config, false)?;
+
+        let web1 = &s.jail["web1"];
+        assert_eq!(web1.source, "freebsd12");
+        assert_eq!(web1.provisioners, vec!["a", "b", "c"]);
+        assert_eq!(web1.labels, vec!["base", "prod"]);
+        assert_eq!(web1.enable, false);
+        assert_eq!(web1.start, true);
+        assert_eq!(
+            web1.conf["host_hostname"],
+            JailConfValue::String("web".to_string())
+        );
+        assert_eq!(web1.conf["allow_mount"], JailConfValue::Bool(true));
+        assert_eq!(
+            web1.zfs,
+            indexmap! {
+                "quota".to_string() => "20G".to_string(),
+                "compression".to_string() => "lz4".to_string(),
+            }
+        );
+
+        let web2 = &s.jail["web2"];
+        assert_eq!(web2.source, "other");
+        assert_eq!(web2.provisioners, vec!["c"]);
+        assert_eq!(web2.labels, vec!["base"]);
+        assert_eq!(web2.start, false);
+        assert_eq!(web2.enable, true);
+        assert_eq!(web2.conf["allow_mount"], JailConfValue::Bool(false));
+        assert_eq!(web2.zfs["quota"], "10G");
+
+        // retention is merged field by field
+        assert_eq!(
+            web1.retention,
+            Retention {
+                keep_last: Some(3),
+                keep_newer_than: Some("7d".to_string()),
+            }
+        );
+        assert_eq!(web2.retention.keep_newer_than, None);
+        Ok(())
+    }
+
+    #[test]
+    fn template_errors() -> Result<()> {
+        let dir = TempDir::new()?;
+        let load = |jails: &str| -> String {
+            let config = write_config(
+                dir.path(),
+                &[("rj.toml", MAIN), ("services/jails.toml", jails)],
+            )
+            .unwrap();
+            Settings::load(&config, false).unwrap_err().to_string()
+        };
+
+        assert_eq!(
+            load("[jail.web]\nextends = \"nope\"\n"),
+            "web: unknown template: nope"
+        );
+        assert_eq!(
+            load(indoc!(
+                r#"
+                [jail_template.a]
+                extends = "b"
+                [jail_template.b]
+                extends = [ "c" ]
+                [jail_template.c]
+                extends = "a"
+                [jail.web]
+                extends = "a"
+                "#
+            )),
+            "web: template cycle: a -> b -> c -> a"
+        );
+        assert_eq!(
+            load("[jail_template.t]\nstart = false\n[jail.web]\nextends = \"t\"\n"),
+            "web: no source set"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn vars() -> Result<()> {
+        let dir = TempDir::new()?;
+        let jails = indoc!(
+            r#"
+            [vars]
+            domain = "example.net"
+
+            [provisioner.hello]
+            type = "exec"
+            cmd = "echo ${jail.name} > /tmp/hello"
+
+            [volume.data]
+            device = "/data/${jail.name}"
+            mountpoint = "${jail.mountpoint}/data"
+            fs_type = "nullfs"
+
+            [jail.web1]
+            source = "freebsd12"
+            provisioners = [ "hello" ]
+            volumes = [ "data" ]
+            [jail.web1.conf]
+            host_hostname = "${jail.name}.${domain}"
+            allow_mount = true
+            exec_start = [ "/bin/sh /etc/rc", "echo $${jail.name}" ]
+            "#
+        );
+        let config = write_config(
+            dir.path(),
+            &[("rj.toml", MAIN), ("services/web.toml", jails)],
+        )?;
+        let s = Settings::load(&config, false)?;
+        assert_eq!(s.validate(), Vec::<String>::new());
+
+        let web1 = s.expand_jail("web1", &s.jail["web1"]);
+        assert_eq!(
+            web1.settings.conf["host_hostname"],
+            JailConfValue::String("web1.example.net".to_string())
+        );
+        assert_eq!(web1.settings.conf["allow_mount"], JailConfValue::Bool(true));
+        assert_eq!(
+            web1.settings.conf["exec_start"],
+            JailConfValue::Vec(vec![
+                "/bin/sh /etc/rc".to_string(),
+                "echo ${jail.name}".to_string()
+            ])
+        );
+        match &web1.provisioners[0] {
+            Provisioner::Exec(p) => assert_eq!(p.cmd, "echo web1 > /tmp/hello"),
+            p => panic!("unexpected provisioner: {:?}", p),
+        }
+        assert_eq!(web1.volumes["data"].device, "/data/web1");
+        assert_eq!(web1.volumes["data"].mountpoint, "/jails/web1/data");
+        Ok(())
+    }
+
+    #[test]
+    fn vars_undefined() -> Result<()> {
+        let dir = TempDir::new()?;
+        let jails = indoc!(
+            r#"
+            [provisioner.conf]
+            type = "file"
+            source = "/etc/resolv.conf"
+            dest = "${conf_dir}/resolv.conf"
+
+            [volume.data]
+            device = "/data"
+            mountpoint = "${jail.mountpoint/data"
+            fs_type = "nullfs"
+
+            [jail.web1]
+            source = "freebsd12"
+            provisioners = [ "conf" ]
+            volumes = [ "data" ]
+            [jail.web1.conf]
+            host_hostname = "${jail.name}.${domain}"
+            "#
+        );
+        let config = write_config(
+            dir.path(),
+            &[("rj.toml", MAIN), ("services/web.toml", jails)],
+        )?;
+        let s = Settings::load(&config, false)?;
+        assert_eq!(
+            s.validate(),
+            vec![
+                "web1: conf.host_hostname: undefined variable: ${domain}",
+                "web1: provisioner.conf.dest: undefined variable: ${conf_dir}",
+                "web1: volume.data.mountpoint: unterminated variable: ${jail.mountpoint/data",
+            ]
+        );
+        assert_eq!(
+            s.to_jails().unwrap_err().to_string(),
+            "web1: conf.host_hostname: undefined variable: ${domain}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn count() -> Result<()> {
+        let dir = TempDir::new()?;
+        let jails = indoc!(
+            r#"
+            [jail.worker]
+            source = "freebsd12"
+            count = 3
+            [jail.worker.conf]
+            ip4_addr = "10.0.1.${count.index}"
+
+            [jail.queue]
+            source = "freebsd12"
+            depends_on = [ "worker" ]
+            "#
+        );
+        let config = write_config(
+            dir.path(),
+            &[("rj.toml", MAIN), ("services/worker.toml", jails)],
+        )?;
+        let s = Settings::load(&config, false)?;
+        assert_eq!(s.validate(), Vec::<String>::new());
+
+        assert_eq!(
+            s.jail.keys().collect::<Vec<_>>(),
+            vec!["base", "worker-1", "worker-2", "worker-3", "queue"]
+        );
+        assert_eq!(
+            s.jail["worker-2"].replica,
+            Some(Replica {
+                of: "worker".to_string(),
+                index: 2
+            })
+        );
+        let worker2 = s.expand_jail("worker-2", &s.jail["worker-2"]);
+        assert_eq!(
+            worker2.settings.conf["ip4_addr"],
+            JailConfValue::String("10.0.1.2".to_string())
+        );
+        assert_eq!(
+            s.dependencies("queue")?,
+            vec!["worker-1", "worker-2", "worker-3"]
+        );
+
+        let jails =
+            "[jail.web]\nsource = \"freebsd12\"\ncount = 2\n[jail.web-2]\nsource = \"freebsd12\"\n";
+        let config = write_config(
+            dir.path(),
+            &[("rj.toml", MAIN), ("services/worker.toml", jails)],
+        )?;
+        assert_eq!(
+            Settings::load(&config, false).unwrap_err().to_string(),
+            "web: replica web-2 is also defined as a jail"
+        );
+        Ok(())
+    }
 }
@@ -1,5 +1,6 @@
+use crate::errors::Error;
 use crate::jail::Jail;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use serde::Deserialize;
 
 pub(crate) mod freebsd;
@@ -22,6 +23,16 @@ impl Source {
         }
     }
 
+    pub fn upgrade(&self, jail: &Jail, release: &str) -> Result<()> {
+        match self {
+            Source::FreeBSD(s) => s.upgrade(jail, release),
+            Source::ZfsClone(_) => bail!(Error::Config(format!(
+                "{}: clone sourced jails are upgraded by cloning them again",
+                jail.name()
+            ))),
+        }
+    }
+
     pub fn validate(&self) -> Result<()> {
         match self {
             Source::FreeBSD(s) => s.validate(),
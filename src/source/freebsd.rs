@@ -1,9 +1,12 @@
+use crate::cmd;
 use crate::jail::Jail;
-use crate::util;
 use anyhow::Result;
 use log::{debug, info};
 use serde::Deserialize;
 
+// ZFS user property holding the release a jail was installed or upgraded to
+pub const RELEASE_PROPERTY: &str = "rj:release";
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct FreeBSD {
@@ -25,7 +28,25 @@ impl FreeBSD {
         if !jail.noop() {
             &jail.zfs_ds().create()?;
         }
+        self.extract(jail, &self.release)
+    }
+
+    // Upgrade by extracting the dists of a newer release over the top of the
+    // jail root
+    pub fn upgrade(&self, jail: &Jail, release: &str) -> Result<()> {
+        info!(
+            "{}: upgrading to {} from source: {}{}",
+            &jail.name(),
+            release,
+            self.name,
+            &jail.noop_suffix()
+        );
+        self.extract(jail, release)
+    }
 
+    // Fetch and extract the dists of a release and record the release on the
+    // jail dataset
+    fn extract(&self, jail: &Jail, release: &str) -> Result<()> {
         for dist in &self.dists {
             info!(
                 "{}: fetching and extracting {} to {}{}",
@@ -37,12 +58,16 @@ impl FreeBSD {
 
             let url = format!(
                 "http://{}/pub/FreeBSD/releases/amd64/amd64/{}/{}.txz",
-                &self.mirror, &self.release, dist
+                &self.mirror, release, dist
             );
             if !jail.noop() {
-                util::fetch_extract(&url, &jail.mountpoint())?;
+                cmd::fetch_extract(&url, &jail.mountpoint())?;
             }
         }
+
+        if !jail.noop() {
+            jail.zfs_ds().set(RELEASE_PROPERTY, release)?;
+        }
         Ok(())
     }
 
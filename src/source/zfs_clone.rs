@@ -1,6 +1,7 @@
+use crate::errors::Error;
 use crate::jail::Jail;
 use crate::zfs;
-use anyhow::{bail, ensure, Result};
+use anyhow::{bail, Result};
 use log::{debug, info};
 use serde::Deserialize;
 use std::path::PathBuf;
@@ -15,18 +16,18 @@ pub struct ZfsClone {
 
 impl ZfsClone {
     pub fn install(&self, jail: &Jail) -> Result<()> {
-        let src_dataset = zfs::DataSet::new(&self.path);
+        // listed with the jails when the source is one of them
+        let src_dataset = zfs::DataSet::in_root(&self.path, jail.zfs_ds().root());
         let dest_dataset = &jail.zfs_ds();
 
-        ensure!(
-            src_dataset.exists()?,
-            format!(
+        if !src_dataset.exists()? {
+            bail!(Error::Prerequisite(format!(
                 "{}: dataset {} doesn't exist in clone source: {}",
                 &jail.name(),
                 &self.path.display(),
                 self.name,
-            ),
-        );
+            )));
+        }
 
         match src_dataset.last_snap("ready")? {
             Some(snapshot) => {
@@ -44,12 +45,12 @@ impl ZfsClone {
                 Ok(())
             }
             None => {
-                bail!(
+                bail!(Error::Prerequisite(format!(
                     "{}: 'ready' snapshot not found for source dataset: {}",
                     &jail.name(),
                     &self.path.display()
-                );
-            }
+                )));
+            },
         }
     }
 
@@ -93,18 +94,22 @@ mod tests {
 
         let err = clone_source.install(jail).unwrap_err();
         assert_eq!(
-            err.downcast::<String>().unwrap(),
-            "clone_test: dataset zroot/rjtest_clone doesn't exist in clone source: test"
-                .to_string()
+            err.downcast::<Error>().unwrap(),
+            Error::Prerequisite(
+                "clone_test: dataset zroot/rjtest_clone doesn't exist in clone source: test"
+                    .to_string()
+            )
         );
 
         source_ds.create()?;
 
         let err = clone_source.install(jail).unwrap_err();
         assert_eq!(
-            err.downcast::<String>().unwrap(),
-            "clone_test: 'ready' snapshot not found for source dataset: zroot/rjtest_clone"
-                .to_string()
+            err.downcast::<Error>().unwrap(),
+            Error::Prerequisite(
+                "clone_test: 'ready' snapshot not found for source dataset: zroot/rjtest_clone"
+                    .to_string()
+            )
         );
 
         source_ds.snap("ready")?;
@@ -1,3 +1,4 @@
+use crate::interpolate::Vars;
 use serde::Deserialize;
 
 #[derive(Clone, Debug, Deserialize)]
@@ -13,3 +14,14 @@ pub struct Volume {
     #[serde(default)]
     pub pass: i8,
 }
+
+impl Volume {
+    pub fn expand(&mut self, name: &str, vars: &Vars, errors: &mut Vec<String>) {
+        vars.expand_string(&format!("volume.{}.device", name), &mut self.device, errors);
+        vars.expand_string(
+            &format!("volume.{}.mountpoint", name),
+            &mut self.mountpoint,
+            errors,
+        );
+    }
+}
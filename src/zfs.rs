@@ -1,22 +1,295 @@
 use crate::cmd;
 use crate::cmd_capture;
-use anyhow::Result;
+use crate::errors::{Error, Kind};
+use crate::executor::Executor;
+use crate::source::freebsd::RELEASE_PROPERTY;
+use anyhow::{bail, Result};
 use chrono::{Local, NaiveDateTime};
+use indexmap::IndexMap;
 use log::{debug, info};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use regex::Regex;
+use serde::Serialize;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// the time snap_with_time puts before a snapshot's name
+pub const SNAP_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f";
+
+// the user properties rj sets on datasets, listed along with the others
+const USER_PROPERTIES: &[&str] = &[RELEASE_PROPERTY];
+
+// What zfs list found below each root of each host.  Shared by the threads of
+// a run and dropped whenever a zfs command changes something on the host.
+static LISTINGS: Mutex<Vec<Listing>> = Mutex::new(Vec::new());
+
+struct Listing {
+    // the executor of the host, each test has its own
+    host: Arc<dyn Executor>,
+    root: PathBuf,
+    datasets: Arc<Vec<Properties>>,
+}
+
+// runs a zfs command that changes datasets, which makes the listings of the
+// host stale
+macro_rules! zfs_change {
+    ( $( $arg:expr ),* $(,)? ) => {{
+        let result = cmd!("zfs", $( $arg ),*).kind(Error::Zfs);
+        changed();
+        result
+    }};
+}
+
+// A filesystem or snapshot as listed by zfs
+#[derive(Clone, Debug, PartialEq)]
+pub struct Properties {
+    // the full name, pool/dataset or pool/dataset@snapshot
+    pub name: String,
+    // bytes
+    pub used: u64,
+    pub referenced: u64,
+    pub creation: NaiveDateTime,
+    // the snapshot a clone was made from
+    pub origin: Option<String>,
+    // None for snapshots and unmounted filesystems
+    pub mountpoint: Option<PathBuf>,
+    // the user properties that are set
+    pub user: IndexMap<String, String>,
+}
+
+impl Properties {
+    // the part after the @ of a snapshot, None for filesystems
+    pub fn snapshot(&self) -> Option<&str> {
+        self.name.split_once('@').map(|(_, snap)| snap)
+    }
+}
+
+// Parse the output of zfs list -H -p -o with the properties of list
+fn parse(output: &str) -> Result<Vec<Properties>> {
+    let columns = 6 + USER_PROPERTIES.len();
+    let mut datasets = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let invalid = || Error::Zfs(format!("unexpected zfs list output: {}", line));
+        if fields.len() != columns {
+            return Err(invalid().into());
+        }
+        // - is shown for properties that aren't set
+        let value = |field: &str| match field {
+            "-" => None,
+            _ => Some(field.to_string()),
+        };
+        let creation = fields[3].parse::<i64>().map_err(|_| invalid())?;
+        datasets.push(Properties {
+            name: fields[0].to_string(),
+            used: fields[1].parse().map_err(|_| invalid())?,
+            referenced: fields[2].parse().map_err(|_| invalid())?,
+            creation: NaiveDateTime::from_timestamp(creation, 0),
+            origin: value(fields[4]),
+            mountpoint: value(fields[5])
+                .filter(|m| m.starts_with('/'))
+                .map(PathBuf::from),
+            user: USER_PROPERTIES
+                .iter()
+                .zip(fields[6..].iter())
+                .filter_map(|(name, field)| Some((name.to_string(), value(field)?)))
+                .collect(),
+        });
+    }
+    Ok(datasets)
+}
+
+// every filesystem and snapshot below a dataset, from a single zfs list
+fn list(root: &Path) -> Result<Vec<Properties>> {
+    let mut properties = vec![
+        "name",
+        "used",
+        "referenced",
+        "creation",
+        "origin",
+        "mountpoint",
+    ];
+    properties.extend_from_slice(USER_PROPERTIES);
+    let output = cmd_capture!(
+        "zfs",
+        "list",
+        "-H",
+        "-p",
+        "-o",
+        properties.join(","),
+        "-r",
+        "-t",
+        "filesystem,snapshot",
+        root
+    )
+    .kind(Error::Zfs)?;
+    parse(&output)
+}
+
+// the pool a dataset is in
+fn pool(path: &Path) -> PathBuf {
+    let path = path.to_string_lossy();
+    PathBuf::from(path.split(&['/', '@'][..]).next().unwrap_or_default())
+}
+
+// the listing of the pool of a root that failed to list, if it shows the
+// root doesn't exist
+fn missing_root(root: &Path) -> Option<Vec<Properties>> {
+    let pool = pool(root);
+    if root == pool {
+        return None;
+    }
+    let datasets = list(&pool).ok()?;
+    let name = root.to_string_lossy();
+    if datasets.iter().any(|p| p.name == name) {
+        return None;
+    }
+    Some(datasets)
+}
+
+// the listing of a root, listing it if it hasn't been since the last change
+fn listing(root: &Path) -> Result<Arc<Vec<Properties>>> {
+    let host = cmd::executor();
+    // held while listing so that threads wanting the same root wait for it
+    let mut listings = LISTINGS.lock().unwrap();
+    if let Some(listing) = listings
+        .iter()
+        .find(|l| Arc::ptr_eq(&l.host, &host) && l.root == root)
+    {
+        return Ok(listing.datasets.clone());
+    }
+    let datasets = Arc::new(match list(root) {
+        Ok(datasets) => datasets,
+        // the root doesn't exist until rj init creates it, in which case its
+        // pool is listed instead
+        Err(e) => match missing_root(root) {
+            Some(datasets) => datasets,
+            None => return Err(e),
+        },
+    });
+    listings.push(Listing {
+        host,
+        root: root.to_owned(),
+        datasets: datasets.clone(),
+    });
+    Ok(datasets)
+}
+
+// drop the listings of the host after changing it
+fn changed() {
+    let host = cmd::executor();
+    LISTINGS
+        .lock()
+        .unwrap()
+        .retain(|l| !Arc::ptr_eq(&l.host, &host));
+}
+
+// Check a property from a jail's zfs table.  The mountpoint and the user
+// properties are rj's to set.
+pub fn valid_property(property: &str, value: &str) -> Result<()> {
+    let valid_name = property.starts_with(|c: char| c.is_ascii_lowercase())
+        && property
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.:-".contains(c));
+    if !valid_name {
+        bail!("invalid zfs property: {}", property);
+    }
+    if property == "mountpoint" || USER_PROPERTIES.contains(&property) {
+        bail!("zfs property {} is set by rj", property);
+    }
+    if value.is_empty() || value.contains(&['\t', '\n'][..]) {
+        bail!("invalid value for zfs property {}: {:?}", property, value);
+    }
+    Ok(())
+}
+
+// Parse a size such as 20G, 1.5m or 512 into bytes
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.to_ascii_uppercase();
+    let value = value.strip_suffix('B').unwrap_or(&value);
+    let (number, shift) = match value.char_indices().last()? {
+        (i, c) if c.is_ascii_digit() => (&value[..=i], 0),
+        (i, c) => (&value[..i], 10 * ("KMGTPE".find(c)? + 1)),
+    };
+    let number: f64 = number.parse().ok()?;
+    if number < 0.0 {
+        return None;
+    }
+    Some((number * (1u64 << shift) as f64).round() as u64)
+}
+
+// Whether a value from a jail's zfs table matches the one zfs get -p shows,
+// which gives sizes in bytes and 0 for none
+pub fn same_value(wanted: &str, actual: &str) -> bool {
+    if wanted.eq_ignore_ascii_case(actual) {
+        return true;
+    }
+    let wanted = match wanted {
+        "none" => Some(0),
+        _ => parse_size(wanted),
+    };
+    wanted.is_some() && wanted == actual.parse().ok()
+}
+
+// A file zfs diff found changed
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FileChange {
+    // - removed, + created, M modified or R renamed
+    pub change: String,
+    pub path: PathBuf,
+    // where a renamed file is now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_path: Option<PathBuf>,
+}
+
+// Parse the output of zfs diff -H
+fn parse_diff(output: &str) -> Result<Vec<FileChange>> {
+    let mut changes = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let change = match fields[..] {
+            [change, path] => FileChange {
+                change: change.to_string(),
+                path: PathBuf::from(path),
+                new_path: None,
+            },
+            ["R", path, new_path] => FileChange {
+                change: "R".to_string(),
+                path: PathBuf::from(path),
+                new_path: Some(PathBuf::from(new_path)),
+            },
+            _ => bail!(Error::Zfs(format!("unexpected zfs diff output: {}", line))),
+        };
+        changes.push(change);
+    }
+    Ok(changes)
+}
 
 #[derive(Clone, Debug)]
 pub struct DataSet {
     path: PathBuf,
+    // listed to find out about the dataset, its pool unless it's below the
+    // jails dataset
+    root: PathBuf,
 }
 
 impl DataSet {
     pub fn new<P: AsRef<Path>>(path: P) -> DataSet {
         DataSet {
             path: path.as_ref().to_path_buf(),
+            root: pool(path.as_ref()),
+        }
+    }
+
+    // A dataset listed with root, instead of the whole pool, when it's at or
+    // below it
+    pub fn in_root<P: AsRef<Path>, R: AsRef<Path>>(path: P, root: R) -> DataSet {
+        if !path.as_ref().starts_with(root.as_ref()) {
+            return DataSet::new(path);
+        }
+        DataSet {
+            path: path.as_ref().to_path_buf(),
+            root: root.as_ref().to_path_buf(),
         }
     }
 
@@ -24,6 +297,10 @@ impl DataSet {
         &self.path
     }
 
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
     // create the zfs data set if it doesn't exist already
     pub fn create(&self) -> Result<bool> {
         if self.exists()? {
@@ -31,25 +308,53 @@ impl DataSet {
             Ok(false)
         } else {
             info!("creating zfs dataset {}", &self.path.display());
-            cmd!("zfs", "create", &self.path)?;
+            zfs_change!("create", &self.path)?;
             Ok(true)
         }
     }
 
     pub fn set(&self, property: &str, value: &str) -> Result<()> {
         let prop = format!("{}={}", property, value);
-        cmd!("zfs", "set", &prop, &self.path)
+        zfs_change!("set", &prop, &self.path)
     }
 
     pub fn get(&self, property: &str) -> Result<String> {
         // zfs get -H -o value mountpoint zroot/jails
-        let value = cmd_capture!("zfs", "get", "-H", "-o", "value", property, &self.path)?;
+        let value = cmd_capture!("zfs", "get", "-H", "-o", "value", property, &self.path)
+            .kind(Error::Zfs)?;
         Ok(value.trim().to_string())
     }
 
+    // the values of several properties from a single zfs get, as exact
+    // numbers where they're numeric
+    pub fn get_all(&self, properties: &[&str]) -> Result<IndexMap<String, String>> {
+        // zfs get -H -p -o property,value quota,compression zroot/jails/test
+        let output = cmd_capture!(
+            "zfs",
+            "get",
+            "-H",
+            "-p",
+            "-o",
+            "property,value",
+            properties.join(","),
+            &self.path
+        )
+        .kind(Error::Zfs)?;
+        let mut values = IndexMap::new();
+        for line in output.lines() {
+            match line.split_once('\t') {
+                Some((property, value)) => {
+                    values.insert(property.to_owned(), value.to_owned());
+                },
+                None => bail!(Error::Zfs(format!("unexpected zfs get output: {}", line))),
+            }
+        }
+        Ok(values)
+    }
+
     pub fn destroy(&self) -> Result<()> {
         info!("destroying zfs dataset: {}", &self.path.display());
-        cmd!("zfs", "destroy", &self.path)
+        zfs_change!("destroy", &self.path)
     }
 
     // destroy recursively
@@ -59,21 +364,21 @@ impl DataSet {
             "destroying zfs dataset recursively: {}",
             &self.path.display()
         );
-        cmd!("zfs", "destroy", "-r", &self.path)
+        zfs_change!("destroy", "-r", &self.path)
     }
 
     #[allow(dead_code)]
     pub fn snap(&self, snap_name: &str) -> Result<()> {
         let snap_path = format!("{}@{}", &self.path.display(), &snap_name);
         info!("creating snapshot: {}", &snap_path);
-        cmd!("zfs", "snapshot", &snap_path)
+        zfs_change!("snapshot", &snap_path)
     }
 
     // create a snapshot with date time in the name
     pub fn snap_with_time(&self, snap_name: &str) -> Result<()> {
-        let dt = Local::now().format("%Y-%m-%dT%H:%M:%S%.3f");
+        let dt = Local::now().format(SNAP_TIME_FORMAT);
         let snap_path = format!("{}@{}_{}", &self.path.display(), &dt, &snap_name);
-        cmd!("zfs", "snapshot", &snap_path)
+        zfs_change!("snapshot", &snap_path)
     }
 
     // create a snapshot with a random suffix
@@ -81,32 +386,100 @@ impl DataSet {
     pub fn snap_with_rand(&self, snap_name: &str) -> Result<()> {
         let rand: String = thread_rng().sample_iter(&Alphanumeric).take(8).collect();
         let snap_path = format!("{}@{}_{}", &self.path.display(), &rand, &snap_name);
-        cmd!("zfs", "snapshot", &snap_path)
+        zfs_change!("snapshot", &snap_path)
     }
 
     pub fn clone<P: AsRef<Path>>(&self, snap: &str, dest: P) -> Result<DataSet> {
         let snap_name = format!("{}@{}", &self.path.display(), snap);
         debug!("cloning {} to {}", snap_name, &dest.as_ref().display());
-        cmd!("zfs", "clone", snap_name, dest.as_ref())?;
+        zfs_change!("clone", snap_name, dest.as_ref())?;
         Ok(DataSet::new(dest))
     }
 
+    // the filesystems and snapshots below the dataset's root
+    fn root_listing(&self) -> Result<Arc<Vec<Properties>>> {
+        listing(&self.root)
+    }
+
+    // The properties of the dataset, None if it doesn't exist
+    pub fn properties(&self) -> Result<Option<Properties>> {
+        let name = self.path.to_string_lossy();
+        Ok(self
+            .root_listing()?
+            .iter()
+            .find(|p| p.name == name)
+            .cloned())
+    }
+
+    // The properties of the dataset's snapshots, oldest first
+    pub fn snapshots(&self) -> Result<Vec<Properties>> {
+        let prefix = format!("{}@", &self.path.display());
+        let mut snaps: Vec<Properties> = self
+            .root_listing()?
+            .iter()
+            .filter(|p| p.name.starts_with(&prefix))
+            .cloned()
+            .collect();
+        snaps.sort_by(|a, b| a.creation.cmp(&b.creation));
+        Ok(snaps)
+    }
+
     pub fn exists(&self) -> Result<bool> {
-        self.ds_exists(&self.path.to_str().unwrap())
+        Ok(self.properties()?.is_some())
     }
 
-    #[allow(dead_code)]
     pub fn snap_exists(&self, snap_name: &str) -> Result<bool> {
-        self.ds_exists(&format!("{}@{}", &self.path.display(), snap_name))
+        Ok(self
+            .snapshots()?
+            .iter()
+            .any(|s| s.snapshot() == Some(snap_name)))
+    }
+
+    // the datasets below the root cloned from each of this dataset's
+    // snapshots that have clones, by snapshot name
+    pub fn clones(&self) -> Result<IndexMap<String, Vec<String>>> {
+        let prefix = format!("{}@", &self.path.display());
+        let mut clones: IndexMap<String, Vec<String>> = IndexMap::new();
+        for p in self.root_listing()?.iter() {
+            if let Some(snap) = p.origin.as_ref().and_then(|o| o.strip_prefix(&prefix)) {
+                clones
+                    .entry(snap.to_owned())
+                    .or_default()
+                    .push(p.name.to_owned());
+            }
+        }
+        Ok(clones)
+    }
+
+    // the files changed since a snapshot, up to a later snapshot or now
+    pub fn diff(&self, snap_name: &str, to: Option<&str>) -> Result<Vec<FileChange>> {
+        let from = format!("{}@{}", &self.path.display(), snap_name);
+        let to = match to {
+            Some(to) => format!("{}@{}", &self.path.display(), to),
+            None => self.path.display().to_string(),
+        };
+        let output = cmd_capture!("zfs", "diff", "-H", &from, &to).kind(Error::Zfs)?;
+        parse_diff(&output)
+    }
+
+    // names of the datasets directly below this one, none if it doesn't exist
+    pub fn children(&self) -> Result<Vec<String>> {
+        let prefix = format!("{}/", &self.path.display());
+        let children = self
+            .root_listing()?
+            .iter()
+            .filter_map(|p| p.name.strip_prefix(&prefix))
+            .filter(|s| !s.contains(&['/', '@'][..]))
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>();
+        Ok(children)
     }
 
     pub fn list_snaps(&self) -> Result<Vec<String>> {
-        let output = cmd_capture!("zfs", "list", "-H", "-o", "name", "-t", "snap")?;
-        let filter = format!("{}@", &self.path.display());
-        let snaps = output
-            .lines()
-            .filter(|s| s.starts_with(&filter))
-            .map(|s| s.split('@').last().unwrap().to_string())
+        let snaps = self
+            .snapshots()?
+            .iter()
+            .filter_map(|s| s.snapshot().map(String::from))
             .collect::<Vec<String>>();
         Ok(snaps)
     }
@@ -114,65 +487,26 @@ impl DataSet {
     // returns the latest snapshot that matches a pattern.
     // sorts by zfs snapshot creation time so works with snapshots that don't have date/time in the name.
     pub fn last_snap(&self, pattern: &str) -> Result<Option<String>> {
-        let output = cmd_capture!(
-            "zfs",
-            "list",
-            "-H",
-            "-p",
-            "-o",
-            "name,creation",
-            "-t",
-            "snap"
-        )?;
-        let re = Regex::new(r"^(.*)@(.*)\t(\d*)$")?;
-        let mut snaps = Vec::new();
-        let ds_filter = format!("{}@", &self.path.display());
-
-        // parse the list of snapshots.  Return only the snapshots that match the ds path and snapshot name pattern.
-        for line in output.lines() {
-            if line.starts_with(&ds_filter) {
-                let caps = re.captures(line).unwrap();
-                let snap_name = caps.get(2).unwrap().as_str();
-
-                if snap_name.contains(pattern) {
-                    let epoch_secs = caps.get(3).unwrap().as_str().parse::<i64>().unwrap();
-                    let time = NaiveDateTime::from_timestamp(epoch_secs, 0);
-                    snaps.push((snap_name, time));
-                }
-            }
-        }
-
-        if snaps.is_empty() {
-            Ok(None)
-        } else {
-            snaps.sort_by(|a, b| a.1.cmp(&b.1));
-            // return the last snapshot name
-            Ok(Some(snaps.last().unwrap().0.to_string()))
-        }
+        Ok(self
+            .snapshots()?
+            .iter()
+            .filter_map(|s| s.snapshot())
+            .filter(|snap| snap.contains(pattern))
+            .last()
+            .map(String::from))
+    }
+
+    // roll back to a snapshot, destroying any later snapshots
+    pub fn rollback(&self, snap_name: &str) -> Result<()> {
+        info!("rolling back {}@{}", &self.path.display(), snap_name);
+        let snap_full_name = format!("{}@{}", self.path.display(), snap_name);
+        zfs_change!("rollback", "-r", &snap_full_name)
     }
 
     pub fn snap_destroy(&self, snap_name: &str) -> Result<()> {
         info!("destroying snapshot {}@{}", &self.path.display(), snap_name);
         let snap_full_name = format!("{}@{}", self.path.display(), snap_name);
-        cmd!("zfs", "destroy", &snap_full_name)
-    }
-
-    // checks if data set exists.
-    // this is used by self.exists() and self.snap_exists()
-    // FIXME - refactor
-    fn ds_exists(&self, ds_path: &str) -> Result<bool> {
-        let msg_pattern = format!("cannot open \'{}\': dataset does not exist\n", &ds_path);
-
-        match cmd_capture!("zfs", "list", ds_path) {
-            Ok(_) => Ok(true),
-            Err(e) => {
-                if e.to_string().contains(&msg_pattern) {
-                    Ok(false)
-                } else {
-                    Err(e)
-                }
-            }
-        }
+        zfs_change!("destroy", &snap_full_name)
     }
 }
 
@@ -180,9 +514,11 @@ impl DataSet {
 mod tests {
     // import names from outer scope.
     use super::*;
+    use indexmap::indexmap;
     use pretty_assertions::assert_eq;
     use rand::distributions::Alphanumeric;
     use rand::{thread_rng, Rng};
+    use regex::Regex;
     use std::panic::{self, AssertUnwindSafe};
     use std::thread;
     use std::time::Duration;
@@ -379,6 +715,18 @@ mod tests {
         })
     }
 
+    #[test]
+    fn ds_rollback() -> Result<()> {
+        run_test(|ds| {
+            ds.snap("test1")?;
+            ds.snap("test2")?;
+            ds.rollback("test1")?;
+            assert_eq!(ds.list_snaps()?, vec!["test1"]);
+            assert!(ds.rollback("noexist").is_err());
+            Ok(())
+        })
+    }
+
     #[test]
     fn ds_snap_destroy() -> Result<()> {
         run_test(|ds| {
@@ -390,4 +738,221 @@ mod tests {
             Ok(())
         })
     }
+
+    // name used referenced creation origin mountpoint rj:release
+    const LISTING: &str = "\
+zroot\t3000\t100\t1577836800\t-\t/zroot\t-
+zroot/jails\t2900\t100\t1577836800\t-\tnone\t-
+zroot/jails/base\t2000\t1000\t1577836800\t-\t/jails/base\t12.0-RELEASE
+zroot/jails/base@b_ready\t0\t1000\t1580515200\t-\t-\t12.0-RELEASE
+zroot/jails/base@a_ready\t100\t900\t1577836800\t-\t-\t12.0-RELEASE
+zroot/jails/test1\t800\t1100\t1580515300\tzroot/jails/base@b_ready\t/jails/test1\t-
+";
+
+    const LIST: &str = "zfs list -H -p -o name,used,referenced,creation,origin,mountpoint,rj:release -r -t filesystem,snapshot zroot";
+
+    #[test]
+    fn parse() -> Result<()> {
+        let datasets = super::parse(LISTING)?;
+        assert_eq!(datasets.len(), 6);
+        assert_eq!(
+            datasets[5],
+            Properties {
+                name: "zroot/jails/test1".to_string(),
+                used: 800,
+                referenced: 1100,
+                creation: NaiveDateTime::from_timestamp(1580515300, 0),
+                origin: Some("zroot/jails/base@b_ready".to_string()),
+                mountpoint: Some(PathBuf::from("/jails/test1")),
+                user: IndexMap::new(),
+            }
+        );
+        assert_eq!(datasets[1].mountpoint, None);
+        assert_eq!(datasets[2].user["rj:release"], "12.0-RELEASE");
+        assert_eq!(datasets[3].snapshot(), Some("b_ready"));
+        assert_eq!(datasets[2].snapshot(), None);
+
+        let err = super::parse("zroot\t3000\n").unwrap_err();
+        assert_eq!(
+            err.downcast::<Error>().unwrap(),
+            Error::Zfs("unexpected zfs list output: zroot\t3000".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn listing() -> Result<()> {
+        let fake = crate::executor::fake();
+        fake.on(LIST, 0, LISTING);
+
+        let base = DataSet::new("zroot/jails/base");
+        assert!(base.exists()?);
+        assert!(!DataSet::new("zroot/jails/nope").exists()?);
+        // oldest first
+        assert_eq!(base.list_snaps()?, vec!["a_ready", "b_ready"]);
+        assert_eq!(base.last_snap("ready")?, Some("b_ready".to_string()));
+        assert!(base.snap_exists("a_ready")?);
+        assert_eq!(
+            base.clones()?,
+            indexmap! { "b_ready".to_string() => vec!["zroot/jails/test1".to_string()] }
+        );
+        assert_eq!(
+            DataSet::new("zroot/jails").children()?,
+            vec!["base", "test1"]
+        );
+        // listed once for all of them
+        assert_eq!(fake.calls(), vec![LIST]);
+
+        // and again after a change
+        base.snap("c_ready")?;
+        assert!(base.exists()?);
+        assert_eq!(
+            fake.calls(),
+            vec![LIST, "zfs snapshot zroot/jails/base@c_ready", LIST]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn root_listing() -> Result<()> {
+        let list_jails = format!("{}/jails", LIST);
+        let jails_listing: String = LISTING
+            .lines()
+            .skip(1)
+            .map(|l| l.to_owned() + "\n")
+            .collect();
+        let fake = crate::executor::fake();
+        fake.on(&list_jails, 0, &jails_listing);
+
+        // only the jails dataset is listed
+        let base = DataSet::in_root("zroot/jails/base", "zroot/jails");
+        assert!(base.exists()?);
+        assert_eq!(
+            base.clones()?,
+            indexmap! { "b_ready".to_string() => vec!["zroot/jails/test1".to_string()] }
+        );
+        assert_eq!(fake.calls(), vec![list_jails.to_owned()]);
+
+        // before init the pool is listed instead
+        let fake = crate::executor::fake();
+        fake.on(&list_jails, 1, "")
+            .on(LIST, 0, "zroot\t3000\t100\t1577836800\t-\t/zroot\t-\n");
+        assert!(!DataSet::in_root("zroot/jails", "zroot/jails").exists()?);
+        assert_eq!(fake.calls(), vec![list_jails.as_str(), LIST]);
+
+        // but not when listing it failed for another reason
+        let fake = crate::executor::fake();
+        fake.on(&list_jails, 1, "").on(LIST, 0, LISTING);
+        assert!(DataSet::in_root("zroot/jails", "zroot/jails")
+            .exists()
+            .is_err());
+
+        // datasets outside the root are listed with their pool
+        let fake = crate::executor::fake();
+        fake.on(LIST, 0, LISTING);
+        assert!(!DataSet::in_root("zroot/images/base", "zroot/jails").exists()?);
+        assert_eq!(fake.calls(), vec![LIST]);
+        Ok(())
+    }
+
+    #[test]
+    fn create_commands() -> Result<()> {
+        let fake = crate::executor::fake();
+        fake.on(LIST, 0, LISTING);
+
+        assert!(DataSet::new("zroot/new").create()?);
+        assert!(!DataSet::new("zroot/jails").create()?);
+        assert_eq!(fake.calls(), vec![LIST, "zfs create zroot/new", LIST]);
+        Ok(())
+    }
+
+    #[test]
+    fn valid_property() {
+        assert!(super::valid_property("quota", "20G").is_ok());
+        assert!(super::valid_property("org.example:backup", "yes").is_ok());
+        for (property, value, err) in &[
+            ("Quota", "20G", "invalid zfs property: Quota"),
+            ("quota size", "20G", "invalid zfs property: quota size"),
+            ("mountpoint", "/x", "zfs property mountpoint is set by rj"),
+            ("rj:release", "x", "zfs property rj:release is set by rj"),
+            ("quota", "", "invalid value for zfs property quota: \"\""),
+        ] {
+            let e = super::valid_property(property, value).unwrap_err();
+            assert_eq!(e.to_string(), *err);
+        }
+    }
+
+    #[test]
+    fn same_value() {
+        assert!(super::same_value("lz4", "lz4"));
+        assert!(super::same_value("20G", "21474836480"));
+        assert!(super::same_value("20gb", "21474836480"));
+        assert!(super::same_value("1.5M", "1572864"));
+        assert!(super::same_value("128K", "131072"));
+        assert!(super::same_value("none", "0"));
+        assert!(super::same_value("OFF", "off"));
+        assert!(!super::same_value("10G", "21474836480"));
+        assert!(!super::same_value("lz4", "off"));
+        assert!(!super::same_value("none", "off"));
+    }
+
+    #[test]
+    fn get_all() -> Result<()> {
+        let get = "zfs get -H -p -o property,value quota,compression zroot/jails/base";
+        let fake = crate::executor::fake();
+        fake.on(get, 0, "quota\t0\ncompression\tlz4\n");
+
+        let values = DataSet::new("zroot/jails/base").get_all(&["quota", "compression"])?;
+        assert_eq!(
+            values,
+            indexmap! {
+                "quota".to_string() => "0".to_string(),
+                "compression".to_string() => "lz4".to_string(),
+            }
+        );
+        assert_eq!(fake.calls(), vec![get]);
+        Ok(())
+    }
+
+    #[test]
+    fn diff() -> Result<()> {
+        let diff = "zfs diff -H zroot/jails/base@a_ready zroot/jails/base";
+        let fake = crate::executor::fake();
+        fake.on(
+            diff,
+            0,
+            "M\t/jails/base/etc\n\
+             M\t/jails/base/etc/rc.conf\n\
+             +\t/jails/base/root/new\n\
+             R\t/jails/base/root/a\t/jails/base/root/b\n",
+        );
+
+        let changes = DataSet::new("zroot/jails/base").diff("a_ready", None)?;
+        assert_eq!(changes.len(), 4);
+        assert_eq!(
+            changes[1],
+            FileChange {
+                change: "M".to_string(),
+                path: PathBuf::from("/jails/base/etc/rc.conf"),
+                new_path: None,
+            }
+        );
+        assert_eq!(
+            changes[3].new_path,
+            Some(PathBuf::from("/jails/base/root/b"))
+        );
+
+        DataSet::new("zroot/jails/base").diff("a_ready", Some("b_ready"))?;
+        assert_eq!(
+            fake.calls(),
+            vec![
+                diff,
+                "zfs diff -H zroot/jails/base@a_ready zroot/jails/base@b_ready"
+            ]
+        );
+
+        let err = super::parse_diff("nonsense\n").unwrap_err();
+        assert_eq!(err.to_string(), "unexpected zfs diff output: nonsense");
+        Ok(())
+    }
 }
@@ -0,0 +1,69 @@
+mod common;
+
+use common::{rj, rj_config, JLS, SERVICE, SYSRC, ZFS};
+use pretty_assertions::assert_eq;
+use std::fs;
+use tempfile::TempDir;
+
+#[test]
+fn parallel() {
+    let out = rj(&[
+        "--noop", "--output", "json", "apply", "--jobs", "3", "base", "test1", "test2",
+        "clone_test",
+    ]);
+    assert!(out.success, "{}", out.stderr);
+
+    let report: serde_json::Value = serde_json::from_str(&out.stdout).unwrap();
+    let jails = report["jails"].as_array().unwrap();
+    assert_eq!(
+        jails.iter().map(|j| j["name"].clone()).collect::<Vec<_>>(),
+        vec!["base", "test1", "test2", "clone_test"]
+    );
+    assert!(jails.iter().all(|j| j["error"].is_null()));
+    assert_eq!(jails[0]["actions"].as_array().unwrap().len(), 6);
+
+    // the jails cloned from base wait for it
+    let base_done = out.stderr.find("base: stopping (noop)").unwrap();
+    for jail in &["test1", "test2", "clone_test"] {
+        let start = out.stderr.find(&format!("{}: applying changes", jail)).unwrap();
+        assert!(base_done < start, "{} started before base was done", jail);
+    }
+}
+
+#[test]
+fn parallel_failures() {
+    // the test config with a zfs property on base, the jail the others are
+    // cloned from, which zfs fails to get
+    let dir = TempDir::new().unwrap();
+    let config = dir.path().join("rj.toml");
+    let content = fs::read_to_string("testdata/config.toml").unwrap();
+    fs::write(
+        &config,
+        format!("{}\n[jail.base.zfs]\nquota = \"10G\"\n", content),
+    )
+    .unwrap();
+    let zfs = ZFS.replace("echo \"-\" ;;", "exit 1 ;;");
+
+    let out = rj_config(
+        config.to_str().unwrap(),
+        &[
+            ("zfs", &zfs),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        &["--noop", "--output", "json", "apply", "-j", "2", "base", "test1"],
+    );
+    assert!(!out.success);
+
+    let report: serde_json::Value = serde_json::from_str(&out.stdout).unwrap();
+    assert!(report["jails"][0]["error"]
+        .as_str()
+        .unwrap()
+        .contains("zfs get -H -p -o property,value quota zroot/jails/base"));
+    assert_eq!(
+        report["jails"][1]["error"],
+        "test1: skipped, dependency base failed"
+    );
+    assert_eq!(report["error"], "2 of 2 jails failed: base, test1");
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{rj_config, SERVICE, SYSRC};
+use pretty_assertions::assert_eq;
+use serde_json::{json, Value};
+use std::fs;
+use tempfile::TempDir;
+
+// lists only what's below the dataset asked for, like zfs list -r.  The
+// images source is outside the jails dataset and web was cloned from its
+// latest ready snapshot.
+const ZFS: &str = r#"#!/bin/sh
+case "$*" in
+    list*)
+        for root; do :; done
+        printf '%s\t%s\t%s\t%s\t%s\t%s\t%s\n' \
+            zroot 3000 100 1577836800 - /zroot - \
+            zroot/images 1000 100 1577836800 - /images - \
+            zroot/images/base 1000 1000 1577836800 - /images/base - \
+            zroot/images/base@2020-01-01T00:00:00.000_ready 100 900 1577836800 - - - \
+            zroot/images/base@2020-02-01T00:00:00.000_ready 0 1000 1580515200 - - - \
+            zroot/jails 2900 100 1577836800 - /jails - \
+            zroot/jails/web 800 1100 1580515300 zroot/images/base@2020-02-01T00:00:00.000_ready /jails/web - |
+            grep -E "^$root([/@	]|$)" ;;
+esac
+"#;
+
+// nothing is running
+const JLS: &str = r#"#!/bin/sh
+exit 1
+"#;
+
+const CONFIG: &str = r#"
+jails_dataset = "zroot/jails"
+jails_mountpoint = "/jails"
+
+[source.images]
+type = "clone"
+path = "zroot/images/base"
+
+[jail.web]
+source = "images"
+start = false
+enable = false
+
+[jail.db]
+source = "images"
+start = false
+enable = false
+"#;
+
+fn rj_clone(args: &[&str]) -> common::Output {
+    let dir = TempDir::new().unwrap();
+    let config = dir.path().join("rj.toml");
+    fs::write(&config, CONFIG).unwrap();
+
+    rj_config(
+        config.to_str().unwrap(),
+        &[
+            ("zfs", ZFS),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        args,
+    )
+}
+
+#[test]
+fn install_outside_jails_dataset() {
+    let out = rj_clone(&["--noop", "apply", "db"]);
+    assert!(out.success, "{}", out.stderr);
+    assert!(
+        out.stdout.contains(
+            "db: cloning zroot/images/base@2020-02-01T00:00:00.000_ready to zroot/jails/db (noop)"
+        ),
+        "{}",
+        out.stdout
+    );
+}
+
+#[test]
+fn upgrade_outside_jails_dataset() {
+    let out = rj_clone(&["--noop", "--output", "json", "upgrade", "web"]);
+    assert!(out.success, "{}", out.stderr);
+    let report: Value = serde_json::from_str(&out.stdout).unwrap();
+    assert_eq!(report["jails"][0]["actions"], json!([]));
+    assert!(out
+        .stderr
+        .contains("web: already cloned from zroot/images/base@2020-02-01T00:00:00.000_ready"));
+}
@@ -0,0 +1,119 @@
+// Helpers for running rj against stand-in zfs, jls and sysrc scripts so the
+// binary can be tested on hosts without jails or zfs.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::os::unix::prelude::*;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+// the jails dataset, base and test1 exist, base has two 'ready' snapshots and
+// was installed from 12.0-RELEASE, test1 was cloned from the latest
+pub const ZFS: &str = r#"#!/bin/sh
+case "$*" in
+    list*)
+        # name used referenced creation origin mountpoint rj:release
+        printf '%s\t%s\t%s\t%s\t%s\t%s\t%s\n' \
+            zroot 3000 100 1577836800 - /zroot - \
+            zroot/jails 2900 100 1577836800 - /jails - \
+            zroot/jails/base 2000 1000 1577836800 - /jails/base 12.0-RELEASE \
+            zroot/jails/base@2020-01-01T00:00:00.000_ready 100 900 1577836800 - - 12.0-RELEASE \
+            zroot/jails/base@2020-02-01T00:00:00.000_ready 0 1000 1580515200 - - 12.0-RELEASE \
+            zroot/jails/test1 800 1100 1577923100 zroot/jails/base@2020-01-01T00:00:00.000_ready /jails/test1 - \
+            zroot/jails/test1@2020-01-02T00:00:00.000_pre-provision 0 1100 1577923200 - - - ;;
+    get*)
+        echo "-" ;;
+esac
+"#;
+
+// only test1 is running
+pub const JLS: &str = r#"#!/bin/sh
+[ "$2" = "test1" ]
+"#;
+
+// test1 and test2 are enabled
+pub const SYSRC: &str = r#"#!/bin/sh
+echo "test1 test2"
+"#;
+
+// records each call in calls.log next to the script
+pub const SERVICE: &str = r#"#!/bin/sh
+echo "service $*" >> "$(dirname "$0")/calls.log"
+"#;
+
+// records each call and fails, for commands that must not be run
+pub const FORBIDDEN: &str = r#"#!/bin/sh
+echo "$(basename "$0") $*" >> "$(dirname "$0")/calls.log"
+exit 1
+"#;
+
+pub struct Output {
+    pub success: bool,
+    // None if rj was killed by a signal
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    // calls made to stand-in scripts that record them
+    pub calls: Vec<String>,
+}
+
+pub fn write_script(dir: &Path, name: &str, content: &str) {
+    let path = dir.join(name);
+    fs::write(&path, content).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+// run rj with the default stand-in scripts first in PATH
+pub fn rj(args: &[&str]) -> Output {
+    rj_with(
+        &[
+            ("zfs", ZFS),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        args,
+    )
+}
+
+// run rj with the given stand-in scripts first in PATH
+pub fn rj_with(scripts: &[(&str, &str)], args: &[&str]) -> Output {
+    rj_config("testdata/config.toml", scripts, args)
+}
+
+// run rj with the given config file and stand-in scripts first in PATH
+pub fn rj_config(config: &str, scripts: &[(&str, &str)], args: &[&str]) -> Output {
+    let bin_dir = TempDir::new().unwrap();
+    for (name, content) in scripts.iter() {
+        write_script(bin_dir.path(), name, content);
+    }
+
+    let output = rj_command(bin_dir.path(), config, args).output().unwrap();
+
+    let calls = fs::read_to_string(bin_dir.path().join("calls.log")).unwrap_or_default();
+
+    Output {
+        success: output.status.success(),
+        code: output.status.code(),
+        stdout: String::from_utf8(output.stdout).unwrap(),
+        stderr: String::from_utf8(output.stderr).unwrap(),
+        calls: calls.lines().map(String::from).collect(),
+    }
+}
+
+// rj with the scripts in bin_dir first in PATH, lock files and the audit log
+// go in bin_dir too
+pub fn rj_command(bin_dir: &Path, config: &str, args: &[&str]) -> Command {
+    let path = format!("{}:{}", bin_dir.display(), env::var("PATH").unwrap());
+    let mut command = Command::new(env!("CARGO_BIN_EXE_rj"));
+    command
+        .args(&["-c", config])
+        .args(args)
+        .env("PATH", path)
+        .env("TERM", "xterm")
+        .env("RJ_LOCK_DIR", bin_dir)
+        .env("RJ_AUDIT_LOG", bin_dir.join("audit.log"));
+    command
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use common::{rj_config, JLS, SERVICE, SYSRC};
+use pretty_assertions::assert_eq;
+
+// worker-1 to worker-3 exist, worker-03 isn't a replica name
+const ZFS: &str = r#"#!/bin/sh
+case "$*" in
+    list*)
+        for ds in zroot zroot/jails zroot/jails/worker-1 zroot/jails/worker-2 \
+            zroot/jails/worker-3 zroot/jails/worker-03; do
+            printf '%s\t0\t0\t1577836800\t-\t/%s\t-\n' "$ds" "$ds"
+        done ;;
+esac
+"#;
+
+const SCRIPTS: &[(&str, &str)] = &[
+    ("zfs", ZFS),
+    ("jls", JLS),
+    ("sysrc", SYSRC),
+    ("service", SERVICE),
+];
+
+#[test]
+fn plan_surplus() {
+    let out = rj_config("testdata/count.toml", SCRIPTS, &["plan"]);
+    assert!(out.success, "{}", out.stderr);
+
+    let lines: Vec<&str> = out.stdout.lines().collect();
+    assert_eq!(
+        lines,
+        vec![
+            "worker-1: create /etc/jail.worker-1.conf",
+            "worker-1: create 'ready' snapshot",
+            "worker-2: create /etc/jail.worker-2.conf",
+            "worker-2: create 'ready' snapshot",
+            "worker-3: surplus replica, would be destroyed",
+            "worker-3: destroy dataset zroot/jails/worker-3",
+        ]
+    );
+}
+
+#[test]
+fn plan_replica() {
+    // a replica's plan covers the surplus replicas of its definition
+    let out = rj_config("testdata/count.toml", SCRIPTS, &["plan", "worker-2"]);
+    assert!(out.success, "{}", out.stderr);
+    assert!(out.stdout.contains("worker-3: destroy dataset"));
+    assert!(!out.stdout.contains("worker-1"));
+}
+
+#[test]
+fn apply_surplus() {
+    let out = rj_config(
+        "testdata/count.toml",
+        SCRIPTS,
+        &["--noop", "--output", "json", "apply", "--all"],
+    );
+    assert!(out.success, "{}", out.stderr);
+
+    let report: serde_json::Value = serde_json::from_str(&out.stdout).unwrap();
+    let jails = report["jails"].as_array().unwrap();
+    assert_eq!(
+        jails.iter().map(|j| j["name"].clone()).collect::<Vec<_>>(),
+        vec!["worker-1", "worker-2", "worker-3"]
+    );
+    assert_eq!(jails[2]["actions"][0]["action"], "destroy_dataset");
+    assert!(out.stderr.contains("worker-3: surplus replica, destroying (noop)"));
+}
+
+#[test]
+fn apply_surplus_confirm() {
+    // stdin is closed, so the prompt isn't answered
+    let out = rj_config("testdata/count.toml", SCRIPTS, &["apply", "--all"]);
+    assert_eq!(out.code, Some(130));
+    assert!(out
+        .stdout
+        .contains("You are about to destroy surplus replicas: worker-3"));
+    assert!(out.calls.is_empty());
+
+    let out = rj_config(
+        "testdata/count.toml",
+        SCRIPTS,
+        &["--noop", "apply", "worker-1", "--auto-approve"],
+    );
+    assert!(out.success, "{}", out.stderr);
+    assert!(!out.stdout.contains("You are about to destroy"));
+}
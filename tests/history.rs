@@ -0,0 +1,80 @@
+mod common;
+
+use common::{rj_command, write_script, JLS, SERVICE, SYSRC, ZFS};
+use serde_json::Value;
+use std::process::Output;
+use tempfile::TempDir;
+
+fn run(dir: &TempDir, args: &[&str]) -> Output {
+    let out = rj_command(dir.path(), "testdata/config.toml", args)
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    out
+}
+
+fn setup() -> TempDir {
+    let dir = TempDir::new().unwrap();
+    for (name, content) in &[
+        ("zfs", ZFS),
+        ("jls", JLS),
+        ("sysrc", SYSRC),
+        ("service", SERVICE),
+    ] {
+        write_script(dir.path(), name, content);
+    }
+    dir
+}
+
+#[test]
+fn history() {
+    let dir = setup();
+    run(&dir, &["stop", "test1"]);
+    run(&dir, &["start", "base"]);
+    // read-only runs aren't logged
+    run(&dir, &["status"]);
+    run(&dir, &["--noop", "stop", "test1"]);
+
+    let out = run(&dir, &["history", "test1"]);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(
+        stdout
+            .lines()
+            .any(|l| l.contains(" test1: ran service jail stop test1 (exit 0, ")),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.contains("base"), "{}", stdout);
+
+    let out = run(&dir, &["--output", "json", "history"]);
+    let report: Value = serde_json::from_slice(&out.stdout).unwrap();
+    let history = report["history"].as_array().unwrap();
+    let runs: Vec<&str> = history
+        .iter()
+        .map(|e| e["run_id"].as_str().unwrap())
+        .collect();
+    let mut distinct = runs.clone();
+    distinct.dedup();
+    assert_eq!(distinct.len(), 2, "{:?}", runs);
+
+    let stop = history
+        .iter()
+        .find(|e| e["program"] == "service")
+        .unwrap();
+    assert_eq!(stop["event"], "command");
+    assert_eq!(stop["jail"], "test1");
+    assert_eq!(stop["args"], serde_json::json!(["jail", "stop", "test1"]));
+    assert_eq!(stop["code"], 0);
+    assert_eq!(stop["config_hash"].as_str().unwrap().len(), 64);
+    assert!(stop["user"].is_string());
+
+    let out = run(&dir, &["history", "--limit", "1"]);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let entries: Vec<&str> = stdout.lines().filter(|l| l.contains(": ran ")).collect();
+    assert_eq!(entries.len(), 1, "{}", stdout);
+    assert!(entries[0].contains("jail start base"), "{}", stdout);
+}
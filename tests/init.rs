@@ -0,0 +1,54 @@
+mod common;
+
+use common::rj_with;
+use pretty_assertions::assert_eq;
+use serde_json::{json, Value};
+
+// only the pool exists, zroot/jails can't be listed until it's created
+const ZFS: &str = r#"#!/bin/sh
+case "$*" in
+    list*zroot/jails)
+        echo "cannot open 'zroot/jails': dataset does not exist" >&2
+        exit 1 ;;
+    list*)
+        printf 'zroot\t3000\t100\t1577836800\t-\t/zroot\t-\n' ;;
+    get*)
+        echo "/zroot/jails" ;;
+    *)
+        echo "zfs $*" >> "$(dirname "$0")/calls.log" ;;
+esac
+"#;
+
+// jails aren't enabled yet
+const SYSRC: &str = r#"#!/bin/sh
+[ "$1" = "-c" ] && exit 1
+echo "sysrc $*" >> "$(dirname "$0")/calls.log"
+"#;
+
+#[test]
+fn init() {
+    let out = rj_with(
+        &[("zfs", ZFS), ("sysrc", SYSRC)],
+        &["--output", "json", "init"],
+    );
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        out.calls,
+        vec![
+            "zfs create zroot/jails",
+            "zfs set mountpoint=/jails zroot/jails",
+            "sysrc jail_enable=YES",
+        ]
+    );
+
+    let report: Value = serde_json::from_str(&out.stdout).unwrap();
+    assert_eq!(report["command"], "init");
+    assert_eq!(
+        report["init"],
+        json!([
+            {"action": "create_dataset", "dataset": "zroot/jails"},
+            {"action": "set_mountpoint", "dataset": "zroot/jails", "mountpoint": "/jails"},
+            {"action": "enable_jails"},
+        ])
+    );
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::{rj_command, write_script, JLS, SERVICE, SYSRC, ZFS};
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// records that the command started then runs it
+const JEXEC: &str = r#"#!/bin/sh
+echo "jexec $*" >> "$(dirname "$0")/calls.log"
+shift
+exec "$@"
+"#;
+
+#[test]
+fn interrupted() {
+    let dir = TempDir::new().unwrap();
+    for (name, content) in [
+        ("zfs", ZFS),
+        ("jls", JLS),
+        ("sysrc", SYSRC),
+        ("service", SERVICE),
+        ("jexec", JEXEC),
+    ] {
+        write_script(dir.path(), name, content);
+    }
+
+    let config = dir.path().join("rj.toml");
+    fs::write(
+        &config,
+        r#"
+        jails_dataset = "zroot/jails"
+        jails_mountpoint = "/jails"
+
+        [source.freebsd12]
+        type = "freebsd"
+        release = "12.0-RELEASE"
+        mirror = "ftp.uk.freebsd.org"
+        dists = [ "base" ]
+
+        [provisioner.hang]
+        type = "exec"
+        cmd = "sleep 30"
+
+        [jail.test1]
+        source = "freebsd12"
+        provisioners = [ "hang" ]
+        "#,
+    )
+    .unwrap();
+
+    let child = rj_command(dir.path(), config.to_str().unwrap(), &["provision", "test1"])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // wait for the provisioner to start
+    let calls = dir.path().join("calls.log");
+    let start = Instant::now();
+    while !fs::read_to_string(&calls)
+        .unwrap_or_default()
+        .contains("jexec test1 sleep 30")
+    {
+        assert!(start.elapsed() < Duration::from_secs(10), "never started");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+
+    assert_eq!(output.status.code(), Some(130));
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert!(
+        stderr.contains("interrupted during jexec test1 sleep 30"),
+        "{}",
+        stderr
+    );
+}
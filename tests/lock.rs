@@ -0,0 +1,78 @@
+mod common;
+
+use common::{rj_command, rj_with, write_script, JLS, SYSRC, ZFS};
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// takes long enough for another run to find the lock held
+const SLOW_SERVICE: &str = r#"#!/bin/sh
+sleep 2
+"#;
+
+#[test]
+fn held() {
+    let dir = TempDir::new().unwrap();
+    for (name, content) in &[
+        ("zfs", ZFS),
+        ("jls", JLS),
+        ("sysrc", SYSRC),
+        ("service", SLOW_SERVICE),
+    ] {
+        write_script(dir.path(), name, content);
+    }
+    let config = "testdata/config.toml";
+
+    let mut first = rj_command(dir.path(), config, &["start", "base"])
+        .spawn()
+        .unwrap();
+
+    // wait for the first run to take the lock
+    let lock_file = dir.path().join("rj.lock");
+    let start = Instant::now();
+    while fs::read_to_string(&lock_file).unwrap_or_default().is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5), "lock not taken");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let out = rj_command(dir.path(), config, &["stop", "test1"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(!out.status.success());
+    assert!(
+        stderr.contains(&format!("rj.lock is locked by pid {} (", first.id())),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("start base), use --wait to wait for it"));
+
+    // read-only commands don't need the lock
+    let out = rj_command(dir.path(), config, &["status", "base"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+
+    let out = rj_command(dir.path(), config, &["--timeout", "10", "stop", "test1"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert!(first.wait().unwrap().success());
+}
+
+#[test]
+fn noop_doesnt_lock() {
+    // lock files can't be created under a file
+    let out = rj_with(
+        &[("zfs", ZFS), ("jls", JLS), ("sysrc", SYSRC)],
+        &["--lock-dir", "Cargo.toml/locks", "--noop", "stop", "test1"],
+    );
+    assert!(out.success, "{}", out.stderr);
+
+    let out = rj_with(
+        &[("zfs", ZFS), ("jls", JLS), ("sysrc", SYSRC)],
+        &["--lock-dir", "Cargo.toml/locks", "stop", "test1"],
+    );
+    assert!(!out.success);
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use common::{rj, rj_with, SYSRC};
+use pretty_assertions::assert_eq;
+
+// no datasets exist, so rj hasn't been initialised
+const ZFS_EMPTY: &str = r#"#!/bin/sh
+printf 'zroot\t0\t0\t1577836800\t-\t/zroot\t-\n'
+"#;
+
+// Errors are reported in the JSON document as well as the exit status
+#[test]
+fn json_error() {
+    let out = rj_with(
+        &[("zfs", ZFS_EMPTY), ("sysrc", SYSRC)],
+        &["--output", "json", "apply", "test1"],
+    );
+    assert!(!out.success);
+
+    let report: serde_json::Value = serde_json::from_str(&out.stdout).unwrap();
+    assert_eq!(report["command"], "apply");
+    assert_eq!(report["noop"], false);
+    assert_eq!(report["jails"], serde_json::json!([]));
+    assert!(report["error"]
+        .as_str()
+        .unwrap()
+        .contains("jails dataset: zroot/jails doesn't exist."));
+}
+
+#[test]
+fn json_noop() {
+    let out = rj(&["--noop", "--output", "json", "status", "nope"]);
+    assert!(!out.success);
+
+    let report: serde_json::Value = serde_json::from_str(&out.stdout).unwrap();
+    assert_eq!(report["command"], "status");
+    assert_eq!(report["noop"], true);
+    assert_eq!(report["error"], "jail 'nope' is not defined");
+}
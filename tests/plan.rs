@@ -0,0 +1,53 @@
+mod common;
+
+use common::rj;
+use indoc::indoc;
+use pretty_assertions::assert_eq;
+
+#[test]
+fn plan_text() {
+    let out = rj(&["plan", "base", "test1", "test2"]);
+    assert!(out.success, "{}", out.stderr);
+
+    let ok = indoc!(
+        r#"
+        base: create /etc/jail.base.conf
+        base: start
+        base: create 'pre-provision' snapshot
+        base: run provisioner 'resolv_conf'
+        base: create 'ready' snapshot
+        base: stop
+        test1: create /etc/jail.test1.conf
+        test1: create /etc/fstab.test1
+        test1: create 'ready' snapshot
+        test2: install source 'base' into zroot/jails/test2
+        test2: set zfs properties of zroot/jails/test2: quota=10G, compression=lz4
+        test2: create /etc/jail.test2.conf
+        test2: start
+        test2: create 'pre-provision' snapshot
+        test2: run provisioner 'exec'
+        test2: run provisioner 'file'
+        test2: create 'ready' snapshot
+        "#
+    );
+    assert_eq!(out.stdout, ok);
+}
+
+#[test]
+fn plan_json() {
+    let out = rj(&["--output", "json", "plan", "stopped"]);
+    assert!(out.success, "{}", out.stderr);
+
+    let report: serde_json::Value = serde_json::from_str(&out.stdout).unwrap();
+    assert_eq!(report["command"], "plan");
+    assert_eq!(report["jails"][0]["name"], "stopped");
+    let actions = report["jails"][0]["actions"].as_array().unwrap();
+    assert_eq!(actions[0]["action"], "install");
+    assert_eq!(actions[0]["source"], "base");
+    assert_eq!(actions[0]["dataset"], "zroot/jails/stopped");
+    assert_eq!(actions[1]["action"], "write_jail_conf");
+    assert_eq!(actions[1]["diff"], serde_json::Value::Null);
+    assert_eq!(actions[2]["action"], "snapshot");
+    assert_eq!(actions[2]["name"], "ready");
+    assert_eq!(actions.len(), 3);
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use common::{rj_config, SERVICE, SYSRC};
+use pretty_assertions::assert_eq;
+use serde_json::{json, Value};
+use std::fs;
+use tempfile::TempDir;
+
+// base has been provisioned three times and test1 was cloned from its first
+// ready snapshot
+const ZFS: &str = r#"#!/bin/sh
+case "$*" in
+    list*)
+        printf '%s\t%s\t%s\t%s\t%s\t%s\t%s\n' \
+            zroot 3000 100 1577836800 - /zroot - \
+            zroot/jails 2900 100 1577836800 - /jails - \
+            zroot/jails/base 2000 1000 1577836700 - /jails/base 12.0-RELEASE \
+            zroot/jails/base@2020-01-01T00:01:00.000_ready 100 900 1577836860 - - 12.0-RELEASE \
+            zroot/jails/base@2020-01-02T00:00:00.000_pre-provision 100 900 1577923200 - - 12.0-RELEASE \
+            zroot/jails/base@2020-01-02T00:01:00.000_ready 100 900 1577923260 - - 12.0-RELEASE \
+            zroot/jails/base@manual 100 900 1577923300 - - 12.0-RELEASE \
+            zroot/jails/base@2020-01-03T00:00:00.000_pre-provision 100 900 1578009600 - - 12.0-RELEASE \
+            zroot/jails/base@2020-01-03T00:01:00.000_ready 0 1000 1578009660 - - 12.0-RELEASE \
+            zroot/jails/test1 800 1100 1577923100 zroot/jails/base@2020-01-01T00:01:00.000_ready /jails/test1 - ;;
+    destroy*)
+        echo "zfs $*" >> "$(dirname "$0")/calls.log" ;;
+esac
+"#;
+
+// base is stopped
+const JLS: &str = r#"#!/bin/sh
+[ "$2" = "test1" ]
+"#;
+
+// run rj with the test config and extra config appended to it
+fn rj_prune(extra: &str, args: &[&str]) -> common::Output {
+    let dir = TempDir::new().unwrap();
+    let config = dir.path().join("rj.toml");
+    let content = fs::read_to_string("testdata/config.toml").unwrap();
+    fs::write(&config, format!("{}\n{}", content, extra)).unwrap();
+
+    rj_config(
+        config.to_str().unwrap(),
+        &[
+            ("zfs", ZFS),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        args,
+    )
+}
+
+#[test]
+fn prune() {
+    let out = rj_prune("[retention]\nkeep_last = 1\n", &["prune", "base"]);
+    assert!(out.success, "{}", out.stderr);
+    // the first ready snapshot is kept for test1
+    assert_eq!(
+        out.calls,
+        vec![
+            "zfs destroy zroot/jails/base@2020-01-02T00:00:00.000_pre-provision",
+            "zfs destroy zroot/jails/base@2020-01-02T00:01:00.000_ready",
+        ]
+    );
+}
+
+#[test]
+fn prune_noop() {
+    let out = rj_prune(
+        "[retention]\nkeep_last = 1\n",
+        &["--output", "json", "prune", "--noop", "base"],
+    );
+    assert!(out.success, "{}", out.stderr);
+    assert!(out.calls.is_empty());
+
+    let report: Value = serde_json::from_str(&out.stdout).unwrap();
+    assert_eq!(report["noop"], true);
+    assert_eq!(
+        report["jails"][0]["actions"],
+        json!([{
+            "action": "destroy_snapshots",
+            "snapshots": [
+                "2020-01-02T00:00:00.000_pre-provision",
+                "2020-01-02T00:01:00.000_ready",
+            ],
+        }])
+    );
+}
+
+#[test]
+fn prune_jail_override() {
+    let out = rj_prune(
+        "[retention]\nkeep_last = 1\n[jail.base.retention]\nkeep_last = 2\n",
+        &["prune", "base"],
+    );
+    assert!(out.success, "{}", out.stderr);
+    assert!(out.calls.is_empty());
+}
+
+#[test]
+fn prune_after_provisioning() {
+    let out = rj_prune("[retention]\nkeep_last = 1\n", &["plan", "base"]);
+    assert!(out.success, "{}", out.stderr);
+    assert!(
+        out.stdout
+            .contains("base: create 'ready' snapshot\nbase: prune snapshots\nbase: stop\n"),
+        "{}",
+        out.stdout
+    );
+
+    // nothing to prune without a policy
+    let out = rj_prune("", &["plan", "base"]);
+    assert!(!out.stdout.contains("prune"), "{}", out.stdout);
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{rj_config, JLS, SERVICE, SYSRC, ZFS};
+use std::fs;
+use tempfile::TempDir;
+
+// records each call and runs the command line it's given here
+const SSH: &str = r#"#!/bin/sh
+echo "ssh $*" >> "$(dirname "$0")/calls.log"
+while [ "$1" = "-o" ] || [ "$1" = "-p" ]; do shift 2; done
+shift
+exec sh -c "$*"
+"#;
+
+fn config(dir: &TempDir) -> String {
+    let config = dir.path().join("rj.toml");
+    fs::write(
+        &config,
+        r#"
+        jails_dataset = "zroot/jails"
+        jails_mountpoint = "/jails"
+
+        [hosts.web1]
+        address = "root@web1.example.org"
+        ssh_options = [ "-p", "2222" ]
+
+        [source.freebsd12]
+        type = "freebsd"
+        release = "12.0-RELEASE"
+        mirror = "ftp.uk.freebsd.org"
+        dists = [ "base" ]
+
+        [jail.test1]
+        source = "freebsd12"
+        "#,
+    )
+    .unwrap();
+    config.to_string_lossy().into_owned()
+}
+
+fn scripts() -> Vec<(&'static str, &'static str)> {
+    vec![
+        ("ssh", SSH),
+        ("zfs", ZFS),
+        ("jls", JLS),
+        ("sysrc", SYSRC),
+        ("service", SERVICE),
+    ]
+}
+
+#[test]
+fn host_from_table() {
+    let dir = TempDir::new().unwrap();
+    let out = rj_config(&config(&dir), &scripts(), &["--host", "web1", "status"]);
+    assert!(out.success, "{}", out.stderr);
+    assert!(out.stdout.contains("test1"), "{}", out.stdout);
+
+    // every command and file read went over ssh
+    assert!(!out.calls.is_empty());
+    for call in out.calls.iter() {
+        assert!(
+            call.starts_with("ssh -o BatchMode=yes -p 2222 root@web1.example.org "),
+            "{}",
+            call
+        );
+    }
+    assert!(out.calls.contains(
+        &"ssh -o BatchMode=yes -p 2222 root@web1.example.org zfs list -H -p -o name,used,referenced,creation,origin,mountpoint,rj:release -r -t filesystem,snapshot zroot/jails"
+            .to_string()
+    ));
+    assert!(out.calls.contains(
+        &"ssh -o BatchMode=yes -p 2222 root@web1.example.org sh -c '[ -f \"$1\" ] || exit 100; cat \"$1\"' sh /etc/jail.test1.conf"
+            .to_string()
+    ));
+}
+
+#[test]
+fn host_address() {
+    let dir = TempDir::new().unwrap();
+    let out = rj_config(
+        &config(&dir),
+        &scripts(),
+        &["--host", "admin@web2", "--noop", "stop", "test1"],
+    );
+    assert!(out.success, "{}", out.stderr);
+    assert!(out
+        .calls
+        .contains(&"ssh -o BatchMode=yes admin@web2 jls -j test1".to_string()));
+    // noop doesn't stop the jail
+    assert!(!out.calls.iter().any(|c| c.contains("service jail stop")));
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{rj, rj_with, SERVICE, SYSRC};
+use pretty_assertions::assert_eq;
+use serde_json::{json, Value};
+
+// test1 exists and has a couple of snapshots
+const ZFS: &str = r#"#!/bin/sh
+case "$*" in
+    list*)
+        printf '%s\t%s\t%s\t%s\t%s\t%s\t%s\n' \
+            zroot 3000 100 1577836800 - /zroot - \
+            zroot/jails 2900 100 1577836800 - /jails - \
+            zroot/jails/test1 2000 1000 1577836700 - /jails/test1 - \
+            zroot/jails/test1@first_ready 100 900 1577836800 - - - \
+            zroot/jails/test1@second_ready 0 1000 1580515200 - - - \
+            zroot/jails/test1@pre-provision 100 900 1577923200 - - - ;;
+    rollback*)
+        echo "zfs $*" >> "$(dirname "$0")/calls.log" ;;
+esac
+"#;
+
+// test1 is running
+const JLS: &str = r#"#!/bin/sh
+[ "$2" = "test1" ]
+"#;
+
+fn rollback(args: &[&str]) -> (common::Output, Value) {
+    let mut rj_args = vec!["--noop", "--output", "json", "rollback"];
+    rj_args.extend_from_slice(args);
+    let out = rj_with(
+        &[
+            ("zfs", ZFS),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        &rj_args,
+    );
+    let report = serde_json::from_str(&out.stdout).unwrap();
+    (out, report)
+}
+
+#[test]
+fn rollback_latest_ready() {
+    let (out, report) = rollback(&["test1"]);
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        report["jails"][0]["actions"],
+        json!([
+            {"action": "stop"},
+            {"action": "rollback", "snapshot": "second_ready"},
+            {"action": "write_jail_conf", "path": "/etc/jail.test1.conf", "diff": null},
+            {"action": "write_fstab", "path": "/etc/fstab.test1", "diff": null},
+            {"action": "start"},
+        ])
+    );
+    // noop
+    assert!(out.calls.is_empty());
+}
+
+#[test]
+fn rollback_to() {
+    let (out, report) = rollback(&["test1", "--to", "pre-provision"]);
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        report["jails"][0]["actions"][1],
+        json!({"action": "rollback", "snapshot": "pre-provision"})
+    );
+
+    let (out, report) = rollback(&["test1", "--to", "first_ready"]);
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        report["jails"][0]["actions"][1],
+        json!({"action": "rollback", "snapshot": "first_ready"})
+    );
+}
+
+#[test]
+fn rollback_no_snapshot() {
+    let (out, report) = rollback(&["test1", "--to", "nope"]);
+    assert!(!out.success);
+    assert_eq!(report["error"], "test1: no snapshot matching 'nope'");
+}
+
+#[test]
+fn rollback_missing_jail() {
+    let out = rj(&["rollback", "test2"]);
+    assert!(!out.success);
+    assert!(out.stderr.contains("test2: doesn't exist"));
+}
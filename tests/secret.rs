@@ -0,0 +1,64 @@
+mod common;
+
+use common::{rj_config, JLS, SERVICE, SYSRC, ZFS};
+use std::fs;
+use tempfile::TempDir;
+
+const TOKEN: &str = "s3cr3t-t0ken";
+
+#[test]
+fn redacted() {
+    let dir = TempDir::new().unwrap();
+    let token_file = dir.path().join("token");
+    fs::write(&token_file, format!("{}\n", TOKEN)).unwrap();
+
+    let config = dir.path().join("rj.toml");
+    fs::write(
+        &config,
+        format!(
+            r#"
+            jails_dataset = "zroot/jails"
+            jails_mountpoint = "/jails"
+
+            [secret]
+            token = "file:{}"
+
+            [source.freebsd12]
+            type = "freebsd"
+            release = "12.0-RELEASE"
+            mirror = "ftp.uk.freebsd.org"
+            dists = [ "base" ]
+
+            [provisioner.login]
+            type = "exec"
+            cmd = "fetch --token ${{secret.token}} https://example.net"
+
+            [jail.test1]
+            source = "freebsd12"
+            provisioners = [ "login" ]
+            "#,
+            token_file.display()
+        ),
+    )
+    .unwrap();
+
+    let out = rj_config(
+        config.to_str().unwrap(),
+        &[
+            ("zfs", ZFS),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        &["--noop", "provision", "test1"],
+    );
+    assert!(out.success, "{}", out.stderr);
+
+    let output = format!("{}{}", out.stdout, out.stderr);
+    assert!(
+        output.contains("test1: running command: fetch --token ******** https://example.net (noop)"),
+        "{}",
+        output
+    );
+    assert!(!output.contains(TOKEN), "{}", output);
+}
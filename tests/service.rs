@@ -0,0 +1,182 @@
+mod common;
+
+use common::{rj, rj_with, FORBIDDEN, JLS, SERVICE, SYSRC, ZFS};
+use pretty_assertions::assert_eq;
+
+// every dataset exists and every jail is running
+const ZFS_ALL: &str = r#"#!/bin/sh
+case "$*" in
+    list*)
+        printf 'zroot\t0\t0\t1577836800\t-\t/zroot\t-\n'
+        printf 'zroot/jails\t0\t0\t1577836800\t-\t/jails\t-\n'
+        for jail in base stopped test1 test2 pkg_test exec_test exec_chroot_test \
+            file_test puppet_test puppet_simple_test clone_test; do
+            printf 'zroot/jails/%s\t0\t0\t1577836800\t-\t/jails/%s\t-\n' "$jail" "$jail"
+        done ;;
+esac
+"#;
+
+const JLS_ALL: &str = r#"#!/bin/sh
+exit 0
+"#;
+
+#[test]
+fn start() {
+    let out = rj(&["start", "base", "test1"]);
+    assert!(out.success, "{}", out.stderr);
+    // test1 is already running
+    assert_eq!(out.calls, vec!["service jail start base"]);
+    assert!(out.stdout.contains("test1: already running"));
+}
+
+#[test]
+fn start_missing() {
+    let out = rj(&["start", "test2"]);
+    assert_eq!(out.code, Some(3));
+    assert!(out.stderr.contains("test2: doesn't exist"));
+    assert!(out.calls.is_empty());
+}
+
+#[test]
+fn start_fails() {
+    let out = rj_with(
+        &[
+            ("zfs", ZFS),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", FORBIDDEN),
+        ],
+        &["start", "base"],
+    );
+    assert_eq!(out.code, Some(5));
+    assert!(
+        out.stderr.contains("service jail start base"),
+        "{}",
+        out.stderr
+    );
+}
+
+#[test]
+fn stop() {
+    let out = rj(&["stop", "base", "test1", "test2"]);
+    assert!(out.success, "{}", out.stderr);
+    // base is already stopped and test2 doesn't exist
+    assert_eq!(out.calls, vec!["service jail stop test1"]);
+    assert!(out.stdout.contains("base: already stopped"));
+}
+
+#[test]
+fn restart() {
+    let out = rj(&["restart", "base", "test1"]);
+    assert!(out.success, "{}", out.stderr);
+    // a stopped jail is just started
+    assert_eq!(
+        out.calls,
+        vec![
+            "service jail start base",
+            "service jail stop test1",
+            "service jail start test1",
+        ]
+    );
+}
+
+#[test]
+fn stop_all_in_reverse() {
+    let scripts = [
+        ("zfs", ZFS_ALL),
+        ("jls", JLS_ALL),
+        ("sysrc", SYSRC),
+        ("service", SERVICE),
+    ];
+
+    let out = rj_with(&scripts, &["start", "--all"]);
+    assert!(out.success, "{}", out.stderr);
+    assert!(out.calls.is_empty());
+
+    let out = rj_with(&scripts, &["stop", "--all"]);
+    assert!(out.success, "{}", out.stderr);
+    let stopped: Vec<&str> = out
+        .calls
+        .iter()
+        .map(|c| c.trim_start_matches("service jail stop "))
+        .collect();
+    assert_eq!(stopped.first(), Some(&"clone_test"));
+    assert_eq!(stopped.last(), Some(&"base"));
+    assert_eq!(stopped.len(), 11);
+}
+
+#[test]
+fn start_pattern() {
+    let out = rj_with(
+        &[
+            ("zfs", ZFS_ALL),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        &["start", "exec*", "pkg_tes?"],
+    );
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        out.calls,
+        vec![
+            "service jail start pkg_test",
+            "service jail start exec_test",
+            "service jail start exec_chroot_test",
+        ]
+    );
+}
+
+#[test]
+fn start_dependencies_first() {
+    let out = rj_with(
+        &[
+            ("zfs", ZFS_ALL),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        &["start", "exec_test", "base"],
+    );
+    assert!(out.success, "{}", out.stderr);
+    // exec_test is cloned from base
+    assert_eq!(
+        out.calls,
+        vec!["service jail start base", "service jail start exec_test"]
+    );
+}
+
+#[test]
+fn stop_selector() {
+    let out = rj_with(
+        &[
+            ("zfs", ZFS_ALL),
+            ("jls", JLS_ALL),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        &["stop", "--selector", "label=web"],
+    );
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        out.calls,
+        vec!["service jail stop test2", "service jail stop test1"]
+    );
+
+    let out = rj(&["stop", "-l", "label=web,label=prod", "-l", "label=test"]);
+    assert!(out.success, "{}", out.stderr);
+    // test2 doesn't exist
+    assert!(out.calls.is_empty());
+    assert!(out.stdout.contains("test2: doesn't exist, skipping"));
+}
+
+#[test]
+fn no_match() {
+    let out = rj(&["start", "nope*"]);
+    assert!(!out.success);
+    assert!(out.stderr.contains("no jails match 'nope*'"));
+
+    let out = rj(&["start", "--selector", "label=nope"]);
+    assert!(!out.success);
+    assert!(out.stderr.contains("no jails match the selector"));
+}
@@ -0,0 +1,161 @@
+mod common;
+
+use common::{rj_with, SERVICE, SYSRC};
+use indoc::indoc;
+use pretty_assertions::assert_eq;
+use serde_json::{json, Value};
+
+// base has a couple of snapshots and test1 was cloned from the first
+const ZFS: &str = r#"#!/bin/sh
+case "$*" in
+    list*)
+        printf '%s\t%s\t%s\t%s\t%s\t%s\t%s\n' \
+            zroot 3000 100 1577836800 - /zroot - \
+            zroot/jails 2900 100 1577836800 - /jails - \
+            zroot/jails/base 2000 1000 1577836700 - /jails/base 12.0-RELEASE \
+            zroot/jails/base@2020-01-01T00:01:00.000_ready 2048 900 1577836860 - - 12.0-RELEASE \
+            zroot/jails/base@2020-01-02T00:01:00.000_ready 100 900 1577923260 - - 12.0-RELEASE \
+            zroot/jails/test1 800 1100 1577923100 zroot/jails/base@2020-01-01T00:01:00.000_ready /jails/test1 - ;;
+    diff*)
+        echo "zfs $*" >> "$(dirname "$0")/calls.log"
+        printf 'M\t/jails/base/etc\n'
+        printf 'M\t/jails/base/etc/rc.conf\n'
+        printf 'R\t/jails/base/root/a\t/jails/base/root/b\n' ;;
+    snapshot*|destroy*)
+        echo "zfs $*" >> "$(dirname "$0")/calls.log" ;;
+esac
+"#;
+
+// nothing is running
+const JLS: &str = r#"#!/bin/sh
+exit 1
+"#;
+
+fn rj_snapshot(args: &[&str]) -> common::Output {
+    rj_with(
+        &[
+            ("zfs", ZFS),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        args,
+    )
+}
+
+#[test]
+fn list() {
+    let out = rj_snapshot(&["snapshot", "list", "base"]);
+    assert!(out.success, "{}", out.stderr);
+    let ok = indoc!(
+        r#"
+        JAIL  SNAPSHOT                       CREATION              USED    CLONES
+        base  2020-01-01T00:01:00.000_ready  2020-01-01T00:01:00Z  2.00KB  zroot/jails/test1
+        base  2020-01-02T00:01:00.000_ready  2020-01-02T00:01:00Z  100B    -
+        "#
+    );
+    assert_eq!(out.stdout, ok);
+}
+
+#[test]
+fn list_json() {
+    let out = rj_snapshot(&["--output", "json", "snapshot", "list", "base"]);
+    assert!(out.success, "{}", out.stderr);
+
+    let report: Value = serde_json::from_str(&out.stdout).unwrap();
+    assert_eq!(report["command"], "snapshot");
+    assert_eq!(
+        report["jails"][0]["snapshots"][0],
+        json!({
+            "name": "2020-01-01T00:01:00.000_ready",
+            "creation": "2020-01-01T00:01:00Z",
+            "used": 2048,
+            "clones": ["zroot/jails/test1"],
+        })
+    );
+}
+
+#[test]
+fn list_missing() {
+    let out = rj_snapshot(&["snapshot", "list", "test2"]);
+    assert_eq!(out.code, Some(3));
+    assert!(out.stderr.contains("test2: doesn't exist"), "{}", out.stderr);
+}
+
+#[test]
+fn create() {
+    let out = rj_snapshot(&["snapshot", "create", "base", "before-upgrade"]);
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(out.calls.len(), 1);
+    assert!(out.calls[0].starts_with("zfs snapshot zroot/jails/base@20"));
+    assert!(out.calls[0].ends_with("_before-upgrade"));
+
+    let out = rj_snapshot(&["--noop", "snapshot", "create", "base"]);
+    assert!(out.success, "{}", out.stderr);
+    assert!(out.stdout.contains("base: creating 'manual' snapshot (noop)"));
+    assert!(out.calls.is_empty());
+}
+
+#[test]
+fn destroy() {
+    let out = rj_snapshot(&[
+        "snapshot",
+        "destroy",
+        "base",
+        "2020-01-02T00:01:00.000_ready",
+        "--auto-approve",
+    ]);
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        out.calls,
+        vec!["zfs destroy zroot/jails/base@2020-01-02T00:01:00.000_ready"]
+    );
+}
+
+#[test]
+fn destroy_clone_origin() {
+    let out = rj_snapshot(&[
+        "snapshot",
+        "destroy",
+        "base",
+        "2020-01-01T00:01:00.000_ready",
+        "--auto-approve",
+    ]);
+    assert_eq!(out.code, Some(3));
+    assert!(
+        out.stderr.contains(
+            "base: snapshot 2020-01-01T00:01:00.000_ready has clones: zroot/jails/test1"
+        ),
+        "{}",
+        out.stderr
+    );
+    assert!(out.calls.is_empty());
+}
+
+#[test]
+fn diff() {
+    // the latest ready snapshot and the jail as it is
+    let out = rj_snapshot(&["snapshot", "diff", "base", "ready"]);
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        out.calls,
+        vec!["zfs diff -H zroot/jails/base@2020-01-02T00:01:00.000_ready zroot/jails/base"]
+    );
+    assert_eq!(
+        out.stdout,
+        "M\t/etc\nM\t/etc/rc.conf\nR\t/root/a -> /root/b\n"
+    );
+
+    let out = rj_snapshot(&[
+        "snapshot",
+        "diff",
+        "base",
+        "2020-01-01T00:01:00.000_ready",
+        "2020-01-02T00:01:00.000_ready",
+    ]);
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        out.calls,
+        vec!["zfs diff -H zroot/jails/base@2020-01-01T00:01:00.000_ready zroot/jails/base@2020-01-02T00:01:00.000_ready"]
+    );
+}
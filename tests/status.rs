@@ -0,0 +1,69 @@
+mod common;
+
+use common::rj;
+
+// return the columns of the table row for a jail
+fn row(output: &str, name: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(|l| l.split_whitespace().map(String::from).collect::<Vec<_>>())
+        .find(|cols| cols.first().map(String::as_str) == Some(name))
+        .unwrap_or_else(|| panic!("no row for {} in:\n{}", name, output))
+}
+
+#[test]
+fn status_table() {
+    let out = rj(&["status", "base", "test1", "test2"]);
+    assert!(out.success, "{}", out.stderr);
+    assert!(out.stdout.contains("NAME"));
+
+    assert_eq!(
+        row(&out.stdout, "base"),
+        vec![
+            "base",
+            "yes",
+            "no",
+            "no",
+            "2020-02-01T00:00:00.000_ready",
+            "missing",
+            "-"
+        ]
+    );
+    assert_eq!(
+        row(&out.stdout, "test1"),
+        vec!["test1", "yes", "yes", "yes", "-", "missing", "missing"]
+    );
+    assert_eq!(
+        row(&out.stdout, "test2"),
+        vec!["test2", "no", "no", "yes", "-", "missing", "-"]
+    );
+}
+
+#[test]
+fn status_all_jails() {
+    let out = rj(&["status"]);
+    assert!(out.success, "{}", out.stderr);
+    for name in &["base", "stopped", "test1", "test2", "clone_test"] {
+        row(&out.stdout, name);
+    }
+}
+
+#[test]
+fn status_unknown_jail() {
+    let out = rj(&["status", "nope"]);
+    assert!(!out.success);
+}
+
+#[test]
+fn status_json() {
+    let out = rj(&["--output", "json", "status", "test1"]);
+    assert!(out.success, "{}", out.stderr);
+
+    let report: serde_json::Value = serde_json::from_str(&out.stdout).unwrap();
+    let status = &report["jails"][0]["status"];
+    assert_eq!(status["name"], "test1");
+    assert_eq!(status["exists"], true);
+    assert_eq!(status["running"], true);
+    assert_eq!(status["last_ready_snap"], serde_json::Value::Null);
+    assert_eq!(status["jail_conf"], "missing");
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use common::{rj, rj_with, JLS, SERVICE, SYSRC};
+use pretty_assertions::assert_eq;
+use serde_json::{json, Value};
+
+// test2 was cloned from the latest ready snapshot of base, test1 from an
+// older one and scratch from test1
+const ZFS: &str = r#"#!/bin/sh
+case "$*" in
+    list*)
+        printf '%s\t%s\t%s\t%s\t%s\t%s\t%s\n' \
+            zroot 3000 100 1577836800 - /zroot - \
+            zroot/jails 2900 100 1577836800 - /jails - \
+            zroot/jails/base 2000 1000 1577836800 - /jails/base 12.0-RELEASE \
+            zroot/jails/base@2020-01-01T00:00:00.000_ready 100 900 1577836800 - - 12.0-RELEASE \
+            zroot/jails/base@2020-02-01T00:00:00.000_ready 0 1000 1580515200 - - 12.0-RELEASE \
+            zroot/jails/test1 800 1100 1577923100 zroot/jails/base@2020-01-01T00:00:00.000_ready /jails/test1 - \
+            zroot/jails/test1@2020-01-02T00:00:00.000_ready 0 1100 1577923200 - - - \
+            zroot/jails/scratch 800 1100 1577923300 zroot/jails/test1@2020-01-02T00:00:00.000_ready /jails/scratch - \
+            zroot/jails/test2 800 1100 1580515300 zroot/jails/base@2020-02-01T00:00:00.000_ready /jails/test2 - ;;
+esac
+"#;
+
+fn upgrade(args: &[&str]) -> (common::Output, Value) {
+    let mut rj_args = vec!["--noop", "--output", "json", "upgrade"];
+    rj_args.extend_from_slice(args);
+    let out = rj(&rj_args);
+    let report = serde_json::from_str(&out.stdout).unwrap();
+    (out, report)
+}
+
+#[test]
+fn upgrade_freebsd() {
+    let (out, report) = upgrade(&["base", "--release", "13.2-RELEASE"]);
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        report["jails"][0]["actions"],
+        json!([
+            {"action": "snapshot", "name": "pre-upgrade"},
+            {"action": "upgrade", "source": "freebsd12", "release": "13.2-RELEASE"},
+        ])
+    );
+}
+
+#[test]
+fn upgrade_freebsd_current_release() {
+    // base is already at the release of its source
+    let (out, report) = upgrade(&["base"]);
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(report["jails"][0]["actions"], json!([]));
+    assert!(out.stderr.contains("base: already at release 12.0-RELEASE"));
+}
+
+#[test]
+fn upgrade_clone() {
+    let (out, report) = upgrade(&["test1", "--auto-approve"]);
+    assert!(out.success, "{}", out.stderr);
+    assert_eq!(
+        report["jails"][0]["actions"],
+        json!([
+            {"action": "stop"},
+            {
+                "action": "destroy_snapshots",
+                "snapshots": ["2020-01-02T00:00:00.000_pre-provision"],
+            },
+            {"action": "destroy_dataset", "dataset": "zroot/jails/test1"},
+            {"action": "install", "source": "base", "dataset": "zroot/jails/test1"},
+            {"action": "write_jail_conf", "path": "/etc/jail.test1.conf", "diff": null},
+            {"action": "write_fstab", "path": "/etc/fstab.test1", "diff": null},
+            {"action": "start"},
+            {"action": "snapshot", "name": "ready"},
+        ])
+    );
+}
+
+#[test]
+fn upgrade_clone_release() {
+    let (out, report) = upgrade(&["test1", "--release", "13.2-RELEASE"]);
+    assert!(!out.success);
+    assert_eq!(
+        report["error"],
+        "test1: a release can only be set for FreeBSD sourced jails"
+    );
+}
+
+#[test]
+fn upgrade_clone_current() {
+    let out = rj_with(
+        &[
+            ("zfs", ZFS),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        &["--noop", "--output", "json", "upgrade", "test2"],
+    );
+    assert!(out.success, "{}", out.stderr);
+    let report: Value = serde_json::from_str(&out.stdout).unwrap();
+    assert_eq!(report["jails"][0]["actions"], json!([]));
+    assert!(out
+        .stderr
+        .contains("test2: already cloned from zroot/jails/base@2020-02-01T00:00:00.000_ready"));
+}
+
+#[test]
+fn upgrade_clone_dependents() {
+    let out = rj_with(
+        &[
+            ("zfs", ZFS),
+            ("jls", JLS),
+            ("sysrc", SYSRC),
+            ("service", SERVICE),
+        ],
+        &["--noop", "upgrade", "test1", "--auto-approve"],
+    );
+    assert_eq!(out.code, Some(3));
+    assert!(
+        out.stderr
+            .contains("test1: can't be cloned again, datasets depend on it: zroot/jails/scratch"),
+        "{}",
+        out.stderr
+    );
+}
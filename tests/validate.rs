@@ -0,0 +1,61 @@
+mod common;
+
+use common::{rj_config, FORBIDDEN};
+use pretty_assertions::assert_eq;
+
+// validate must not run anything on the host
+const SCRIPTS: &[(&str, &str)] = &[
+    ("zfs", FORBIDDEN),
+    ("jls", FORBIDDEN),
+    ("sysrc", FORBIDDEN),
+    ("service", FORBIDDEN),
+    ("jail", FORBIDDEN),
+];
+
+#[test]
+fn valid() {
+    let out = rj_config("testdata/config.toml", SCRIPTS, &["validate"]);
+    assert!(out.success, "{}", out.stderr);
+    assert!(out.stdout.contains("testdata/config.toml: ok"));
+    assert_eq!(out.calls, Vec::<String>::new());
+}
+
+#[test]
+fn invalid() {
+    let out = rj_config("testdata/invalid.toml", SCRIPTS, &["validate"]);
+    assert_eq!(out.code, Some(2));
+    assert_eq!(out.calls, Vec::<String>::new());
+
+    for err in &[
+        "clone source elsewhere, path: zroot/templates/base is not a jail defined in the config",
+        "file provisioner missing, invalid source: testdata/provisioners/missing.txt",
+        "web1: unknown source: nope",
+        "web1: unknown provisioner: nope",
+        "web1: zfs property mountpoint is set by rj",
+        "web1: volumes a and b have the same mountpoint: /mnt/",
+        "web1: unknown volume: nope",
+        "web2: retention keep_last must be at least 1",
+        "web2: host.hostname web.jail is already used by jail: web1",
+        "web2: ip4.addr 10.11.11.2 is already used by jail: web1",
+        "bad.name: invalid jail name",
+        "testdata/invalid.toml: 11 errors found",
+    ] {
+        assert!(out.stderr.contains(err), "missing '{}' in:\n{}", err, out.stderr);
+    }
+}
+
+#[test]
+fn invalid_json() {
+    let out = rj_config(
+        "testdata/invalid.toml",
+        SCRIPTS,
+        &["--output", "json", "validate"],
+    );
+    assert!(!out.success);
+
+    let report: serde_json::Value = serde_json::from_str(&out.stdout).unwrap();
+    assert_eq!(report["command"], "validate");
+    assert_eq!(report["errors"].as_array().unwrap().len(), 11);
+    assert_eq!(report["errors"][2], "web1: unknown source: nope");
+    assert_eq!(report["error"], "testdata/invalid.toml: 11 errors found");
+}